@@ -24,13 +24,13 @@
 
 // Round up integer divice (a / b).
 #define ROUNDUP(n, div) (((n) + (div) - 1) / (div))
-// Add freemap, root and swap to file number.
-#define TOTAL_FILE_NUM(OBJ_FILE_NUM) ((OBJ_FILE_NUM) + 2 + 1)
+// Add superblock, freemap, root and swap to file number.
+#define TOTAL_FILE_NUM(OBJ_FILE_NUM) ((OBJ_FILE_NUM) + FIRST_FILE_SECTOR + 1)
 
 /* -------------------------------- CONSTANTS ------------------------------- */
 
 #define SECTOR_SIZE       512
-#define FILE_NAME_LEN_MAX 28
+#define FILE_NAME_LEN_MAX 255
 #define MAX_FILES         200
 // Inode magic number.
 #define MAGIC             0x494e4f44
@@ -41,40 +41,100 @@
 #define FREEMAP_BITS      SECTOR_NUM
 // Used after.
 #define FREEMAP_BYTES     ROUNDUP(FREEMAP_BITS, 8)
-// Sectors of freemap.
-#define FREEMAP_SECTORS   ROUNDUP(FREEMAP_BYTES,  SECTOR_SIZE)
+// Metadata sectors end with a CRC-32 of the bytes before it.
+#define CHECKSUM_OFF      (SECTOR_SIZE - 4)
+// Sectors of freemap, each with CHECKSUM_OFF bytes of it.
+#define FREEMAP_SECTORS   ROUNDUP(FREEMAP_BYTES, CHECKSUM_OFF)
 // 4MiB swap.
 #define SWAP_SPACE        (4 << 20)
-// Add another FREE_NUMBER inodes could be used to create new files.
-#define FREE_NUMBER       10
-
-const uint32_t FREE_MAP_SECTOR = 0;
-const uint32_t ROOT_DIR_SECTOR = 1;
+// Add another FREE_NUMBER empty root dir sectors to create new files in.
+#define FREE_NUMBER       2
+// Metadata journal, a header sector followed by logged sectors.
+#define JOURNAL_MAGIC     0x4a524e4c
+#define JOURNAL_CAPACITY  ((SECTOR_SIZE - 8) / sizeof(uint32_t))
+#define JOURNAL_SECTORS   (JOURNAL_CAPACITY + 1)
+// Journal lives at the end of the disk.
+#define JOURNAL_START     (SECTOR_NUM - JOURNAL_SECTORS)
+
+// Superblock magic number and on-disk format version.
+#define SUPER_MAGIC       0x5441434f
+#define FS_VERSION        5
+
+#define SUPER_BLOCK_SECTOR 0
+#define FREE_MAP_SECTOR    1
+#define ROOT_DIR_SECTOR    2
+// Inode of the first file.
+#define FIRST_FILE_SECTOR  3
+
+// Content sectors pointed to by an inode, then by an index sector.
+#define DIRECT_CNT        112
+#define PTRS_PER_SECTOR   (SECTOR_SIZE / sizeof(uint32_t))
 
 const char SWAP_FNAME[] = ".glbswap";
 const char DISK_FILENAME[] = "disk.img";
 const char TEST_DIR[] = "user";
 
 /* --------------------------------- STRUCT --------------------------------- */
+// File types, as in user/lib/fstat.h.
+#define T_DIR  1
+#define T_FILE 2
+
 struct inner_inode {
-  uint32_t start;
   uint32_t len;
   uint32_t magic;
+  uint16_t type;
+  uint16_t mode;
+  uint32_t nlink;
+  // Times are in ms since boot, zero for files made here.
+  uint64_t atime;
+  uint64_t mtime;
+  uint64_t ctime;
+  // Sectors taken, including index sectors.
+  uint32_t blocks;
+  // Content sectors, 0 for holes.
+  uint32_t direct[DIRECT_CNT];
+  // Index sector pointing to the content sectors after the direct ones.
+  uint32_t indirect;
+  // Index sector pointing to index sectors, for the rest of the content.
+  uint32_t double_indirect;
 };
 
 struct ondisk_inode {
   struct inner_inode inner;
-  uint8_t unused[SECTOR_SIZE - sizeof(struct inner_inode)];
+  uint8_t unused[CHECKSUM_OFF - sizeof(struct inner_inode)];
+  uint32_t checksum;
 };
 
+// Header of a variable-length dir entry, followed by the name.
+// Entries are 4-byte aligned and never cross a sector, nor reach its checksum.
 struct dentry {
-  char name[FILE_NAME_LEN_MAX];
   uint32_t inum;
+  uint16_t rec_len;
+  uint8_t name_len;
+  uint8_t reserved;
+};
+
+struct super_block {
+  uint32_t magic;
+  uint32_t version;
+  uint32_t sectors;
+  uint32_t free_map;
+  uint32_t root_dir;
+  uint32_t journal_start;
+  uint32_t journal_len;
+  uint32_t clean;
+  uint8_t unused[SECTOR_SIZE - 8 * sizeof(uint32_t)];
+};
+
+struct journal_header {
+  uint32_t magic;
+  uint32_t cnt;
+  uint32_t homes[JOURNAL_CAPACITY];
 };
 
 /* ---------------------------------- IMPL ---------------------------------- */
 
-static char filenames[MAX_FILES][FILE_NAME_LEN_MAX];
+static char filenames[MAX_FILES][FILE_NAME_LEN_MAX + 1];
 static FILE* files[MAX_FILES];
 static uint32_t FILE_NUMBER = 0;
 
@@ -137,6 +197,7 @@ char **read_filenames(const char* dir) {
       if (name == NULL) {
         perror("last slash!");
       }
+      assert(strlen(name + 1) <= FILE_NAME_LEN_MAX);
       strcpy(filenames[FILE_NUMBER++], name+1);
     }
   }
@@ -144,6 +205,24 @@ char **read_filenames(const char* dir) {
   return (char**)filenames;
 }
 
+// CRC-32 (IEEE) of `len` bytes at `data`.
+uint32_t crc32(const uint8_t *data, size_t len) {
+  uint32_t crc = ~0u;
+  for (size_t i = 0; i < len; i++) {
+    crc ^= data[i];
+    for (int bit = 0; bit < 8; bit++) {
+      crc = (crc & 1) ? (crc >> 1) ^ 0xedb88320 : crc >> 1;
+    }
+  }
+  return ~crc;
+}
+
+// Store the checksum of metadata sector `sector` at its end.
+void seal(void *sector) {
+  uint32_t crc = crc32(sector, CHECKSUM_OFF);
+  memcpy((uint8_t *)sector + CHECKSUM_OFF, &crc, sizeof(crc));
+}
+
 void free_map_set(uint8_t *free_map, int idx) {
   free_map[idx / 8] |= 1 << (idx % 8);
 }
@@ -155,6 +234,97 @@ size_t get_file_size(FILE* fp) {
     return ret;
 }
 
+// Write index sector `index` at `*current`, and return where it was written.
+uint32_t write_index(FILE *disk, const uint32_t *index, uint32_t *current, uint32_t *blocks) {
+  fseek(disk, *current * SECTOR_SIZE, SEEK_SET);
+  fwrite(index, sizeof(uint32_t), PTRS_PER_SECTOR, disk);
+  (*blocks)++;
+  return (*current)++;
+}
+
+// Point `inode` to `cnt` contiguous content sectors from `start`.
+// Index sectors needed are taken from `*current` on.
+void map_extent(FILE *disk, struct inner_inode *inode, uint32_t start, uint32_t cnt, uint32_t *current) {
+  uint32_t i = 0;
+  inode->blocks = cnt;
+  for (; i < cnt && i < DIRECT_CNT; i++) {
+    inode->direct[i] = start + i;
+  }
+  if (i == cnt) return;
+
+  uint32_t index[PTRS_PER_SECTOR];
+  memset(index, 0, sizeof(index));
+  for (uint32_t j = 0; i < cnt && j < PTRS_PER_SECTOR; i++, j++) {
+    index[j] = start + i;
+  }
+  inode->indirect = write_index(disk, index, current, &inode->blocks);
+  if (i == cnt) return;
+
+  uint32_t outer[PTRS_PER_SECTOR];
+  memset(outer, 0, sizeof(outer));
+  for (uint32_t k = 0; i < cnt && k < PTRS_PER_SECTOR; k++) {
+    memset(index, 0, sizeof(index));
+    for (uint32_t j = 0; i < cnt && j < PTRS_PER_SECTOR; i++, j++) {
+      index[j] = start + i;
+    }
+    outer[k] = write_index(disk, index, current, &inode->blocks);
+  }
+  assert(i == cnt);
+  inode->double_indirect = write_index(disk, outer, current, &inode->blocks);
+}
+
+// Append an entry to dir content `buf` at `*off`, moving to the next sector
+// if it doesn't fit. Returns the dir length so far. Only measures if `buf` is NULL.
+uint32_t add_dentry(uint8_t *buf, uint32_t *off, uint32_t *last, const char *name, uint32_t inum) {
+  uint32_t name_len = strlen(name);
+  uint32_t rec_len = ROUNDUP(sizeof(struct dentry) + name_len, 4) * 4;
+  uint32_t remain = CHECKSUM_OFF - *off % SECTOR_SIZE;
+  if (rec_len > remain) {
+    // Let the last entry of the sector stretch to the checksum.
+    if (buf) ((struct dentry *)(buf + *last))->rec_len += remain;
+    *off += remain + sizeof(uint32_t);
+  }
+  if (buf) {
+    struct dentry *d = (struct dentry *)(buf + *off);
+    d->inum = inum;
+    d->rec_len = rec_len;
+    d->name_len = name_len;
+    d->reserved = 0;
+    memcpy(buf + *off + sizeof(struct dentry), name, name_len);
+  }
+  *last = *off;
+  *off += rec_len;
+  return *off;
+}
+
+// Terminate dir content at `off`, adding FREE_NUMBER empty sectors.
+uint32_t finish_dir(uint8_t *buf, uint32_t off, uint32_t last) {
+  if (off % SECTOR_SIZE) {
+    uint32_t remain = CHECKSUM_OFF - off % SECTOR_SIZE;
+    if (buf) ((struct dentry *)(buf + last))->rec_len += remain;
+    off += remain + sizeof(uint32_t);
+  }
+  for (uint32_t i = 0; i < FREE_NUMBER; i++, off += SECTOR_SIZE) {
+    if (buf) ((struct dentry *)(buf + off))->rec_len = CHECKSUM_OFF;
+  }
+  if (buf) {
+    for (uint32_t i = 0; i < off; i += SECTOR_SIZE) seal(buf + i);
+  }
+  return off;
+}
+
+// Make content of root DIR, including swap file. Returns its length.
+uint32_t make_root_dir(uint8_t *buf) {
+  uint32_t off = 0, last = 0;
+  for (uint32_t i = 0; i < FILE_NUMBER; i++) {
+    add_dentry(buf, &off, &last, filenames[i], i + FIRST_FILE_SECTOR);
+    if (buf) DEBUG_PRINTF("Add %s to root dir, inum = %u\n", filenames[i], i + FIRST_FILE_SECTOR);
+  }
+  add_dentry(buf, &off, &last, SWAP_FNAME, FILE_NUMBER + FIRST_FILE_SECTOR);
+  if (buf) DEBUG_PRINTF("Add %s to root dir, inum = %u\n", SWAP_FNAME, FILE_NUMBER + FIRST_FILE_SECTOR);
+  return finish_dir(buf, off, last);
+}
+
 void make_disk_img(FILE *disk, FILE **files) {
   ftruncate(fileno(disk), DISK_SIZE);
   DEBUG_PRINTF(
@@ -164,48 +334,42 @@ void make_disk_img(FILE *disk, FILE **files) {
     "Inode range = [%u, %u)\n",
     DISK_SIZE / 1024,
     FREE_MAP_SECTOR, ROOT_DIR_SECTOR,
-    2, TOTAL_FILE_NUM(FILE_NUMBER));
+    FIRST_FILE_SECTOR, TOTAL_FILE_NUM(FILE_NUMBER));
 
   // Make freemap, the first file.
   // However, write it to disk latter.
   uint32_t free_map_content_start = TOTAL_FILE_NUM(FILE_NUMBER);
-  struct inner_inode inner_free_map = {.len = FREEMAP_BYTES,
-                              .start = free_map_content_start,
-                              .magic = MAGIC};
-  struct ondisk_inode free_map_inode = {.inner = inner_free_map, .unused = {0}};
+  struct ondisk_inode free_map_inode;
+  bzero(&free_map_inode, sizeof(free_map_inode));
+  free_map_inode.inner.len = FREEMAP_SECTORS * SECTOR_SIZE;
+  free_map_inode.inner.magic = MAGIC;
+  free_map_inode.inner.type = T_FILE;
+  free_map_inode.inner.mode = 0644;
+  free_map_inode.inner.nlink = 1;
   uint8_t free_map[FREEMAP_BYTES] = {0};
   DEBUG_PRINTF("Freemap: [%u, %u), len = %u\n",
     free_map_content_start,
-    free_map_content_start + ROUNDUP(inner_free_map.len, SECTOR_SIZE),
-    inner_free_map.len);
+    free_map_content_start + FREEMAP_SECTORS,
+    free_map_inode.inner.len);
 
   // Make root DIR. The second file. Include swap file in root.
-  uint32_t root_content_start = free_map_content_start + ROUNDUP(inner_free_map.len, SECTOR_SIZE);
-  uint32_t root_map_size = FILE_NUMBER + 1 + FREE_NUMBER;
-  uint32_t root_content_len = root_map_size * sizeof(struct dentry);
-  struct inner_inode inner_root_dir = {.len = root_content_len,
-                              .start = root_content_start,
-                              .magic = MAGIC};
-  struct ondisk_inode root_dir_inode = {.inner = inner_root_dir, .unused = {0}};
+  uint32_t root_content_start = free_map_content_start + FREEMAP_SECTORS;
+  uint32_t root_content_len = make_root_dir(NULL);
+  struct ondisk_inode root_dir_inode;
+  bzero(&root_dir_inode, sizeof(root_dir_inode));
+  root_dir_inode.inner.len = root_content_len;
+  root_dir_inode.inner.magic = MAGIC;
+  root_dir_inode.inner.type = T_DIR;
+  root_dir_inode.inner.mode = 0755;
+  root_dir_inode.inner.nlink = 1;
   DEBUG_PRINTF("Root dir: [%u, %u), len = %u\n",
     root_content_start,
-    root_content_start + ROUNDUP(inner_root_dir.len, SECTOR_SIZE),
-    inner_root_dir.len);
-
-  // Write root DIR inode.
-  fseek(disk, ROOT_DIR_SECTOR * SECTOR_SIZE, SEEK_SET);
-  fwrite(&root_dir_inode, sizeof(root_dir_inode), 1, disk);
+    root_content_start + ROUNDUP(root_content_len, SECTOR_SIZE),
+    root_content_len);
 
   // Make content of root DIR, including swap file.
-  struct dentry *root_dir_content = (struct dentry *)calloc(root_map_size, sizeof(struct dentry));
-  for (uint32_t i = 0; i < FILE_NUMBER; i++) {
-    strncpy(root_dir_content[i].name, filenames[i], FILE_NAME_LEN_MAX);
-    root_dir_content[i].inum = i + 2;
-    DEBUG_PRINTF("Add %s to root dir, inum = %u\n", filenames[i], i + 2);
-  }
-  strncpy(root_dir_content[FILE_NUMBER].name, SWAP_FNAME, FILE_NAME_LEN_MAX);
-  root_dir_content[FILE_NUMBER].inum = FILE_NUMBER + 2;
-  DEBUG_PRINTF("Add %s to root dir, inum = %u\n", SWAP_FNAME, FILE_NUMBER + 2);
+  uint8_t *root_dir_content = (uint8_t *)calloc(root_content_len, 1);
+  make_root_dir(root_dir_content);
 
   // Write content of the root DIR.
   fseek(disk, root_content_start * SECTOR_SIZE, SEEK_SET);
@@ -215,12 +379,24 @@ void make_disk_img(FILE *disk, FILE **files) {
   // Calculate current sector number.
   uint32_t current = root_content_start + ROUNDUP(root_content_len, SECTOR_SIZE);
 
+  // The kernel expects the content of both in direct pointers.
+  assert(FREEMAP_SECTORS <= DIRECT_CNT);
+  assert(ROUNDUP(root_content_len, SECTOR_SIZE) <= DIRECT_CNT);
+  map_extent(disk, &free_map_inode.inner, free_map_content_start, FREEMAP_SECTORS, &current);
+  map_extent(disk, &root_dir_inode.inner, root_content_start,
+             ROUNDUP(root_content_len, SECTOR_SIZE), &current);
+
+  // Write root DIR inode.
+  seal(&root_dir_inode);
+  fseek(disk, ROOT_DIR_SECTOR * SECTOR_SIZE, SEEK_SET);
+  fwrite(&root_dir_inode, sizeof(root_dir_inode), 1, disk);
+
   // Copy file one by one.
   struct ondisk_inode file_inode;
-  bzero(file_inode.unused, sizeof(file_inode.unused));
 
   for (uint32_t i = 0; i < FILE_NUMBER; i++) {
     // Read the content of the file, then write it to disk.
+    uint32_t start = current;
     fseek(disk, current * SECTOR_SIZE, SEEK_SET);
     FILE *f = files[i];
     size_t size = get_file_size(f);
@@ -228,49 +404,81 @@ void make_disk_img(FILE *disk, FILE **files) {
     assert(fread(buf, 1, size, f) == size);
     fwrite(buf, 1, size, disk);
     free(buf);
+    current += ROUNDUP(size, SECTOR_SIZE);
 
-    // Write the inode.
+    // Write the inode, with its index sectors after the content.
+    bzero(&file_inode, sizeof(file_inode));
     file_inode.inner.len = size;
-    file_inode.inner.start = current;
     file_inode.inner.magic = MAGIC;
-    fseek(disk, (i + 2) * SECTOR_SIZE, SEEK_SET);
+    file_inode.inner.type = T_FILE;
+    file_inode.inner.mode = 0644;
+    file_inode.inner.nlink = 1;
+    map_extent(disk, &file_inode.inner, start, ROUNDUP(size, SECTOR_SIZE), &current);
+    seal(&file_inode);
+    fseek(disk, (i + FIRST_FILE_SECTOR) * SECTOR_SIZE, SEEK_SET);
     fwrite(&file_inode, sizeof(file_inode), 1, disk);
 
-    current += ROUNDUP(size, SECTOR_SIZE);
     DEBUG_PRINTF("FILE %s: [%u, %u), inum = %u, size = %u\n",
       filenames[i],
-      file_inode.inner.start, current,
-      i + 2, file_inode.inner.len);
+      start, current,
+      i + FIRST_FILE_SECTOR, file_inode.inner.len);
   }
-  // Make zeroed swap file.
-  void* buf = calloc(SECTOR_SIZE, sizeof(uint8_t));
-  fseek(disk, current * SECTOR_SIZE, SEEK_SET);
-  fwrite(buf, ROUNDUP(SWAP_SPACE, SECTOR_SIZE), SECTOR_SIZE, disk);
-  // Make swap inode.
-  struct inner_inode inner_swap = {.len = SWAP_SPACE,
-                              .start = current,
-                              .magic = MAGIC};
-  struct ondisk_inode swap_inode = {.inner = inner_swap, .unused = {0}};
-  fseek(disk, (FILE_NUMBER + 2) * SECTOR_SIZE, SEEK_SET);
+  // Make swap inode. The swap file is all a hole, taking no sector until written.
+  struct ondisk_inode swap_inode;
+  bzero(&swap_inode, sizeof(swap_inode));
+  swap_inode.inner.len = SWAP_SPACE;
+  swap_inode.inner.magic = MAGIC;
+  swap_inode.inner.type = T_FILE;
+  swap_inode.inner.mode = 0644;
+  swap_inode.inner.nlink = 1;
+  seal(&swap_inode);
+  fseek(disk, (FILE_NUMBER + FIRST_FILE_SECTOR) * SECTOR_SIZE, SEEK_SET);
   fwrite(&swap_inode, sizeof(swap_inode), 1, disk);
-  DEBUG_PRINTF("FILE %s: [%u, %u), inum = %u, size = %uKiB\n",
+  DEBUG_PRINTF("FILE %s: sparse, inum = %u, size = %uKiB\n",
     SWAP_FNAME,
-    current,
-    current + ROUNDUP(SWAP_SPACE, SECTOR_SIZE),
-    FILE_NUMBER + 2,
+    FILE_NUMBER + FIRST_FILE_SECTOR,
     SWAP_SPACE / 1024);
-  current += ROUNDUP(SWAP_SPACE, SECTOR_SIZE);
-  free(buf);
+
+  // Write an empty journal.
+  struct journal_header journal = {.magic = JOURNAL_MAGIC, .cnt = 0, .homes = {0}};
+  fseek(disk, JOURNAL_START * SECTOR_SIZE, SEEK_SET);
+  fwrite(&journal, sizeof(journal), 1, disk);
+  DEBUG_PRINTF("Journal: [%lu, %u)\n", JOURNAL_START, SECTOR_NUM);
 
   // Write free map.
   for (int i = 0; i < current; i++) {
     free_map_set(free_map, i);
   }
-  rewind(disk);
+  for (int i = JOURNAL_START; i < SECTOR_NUM; i++) {
+    free_map_set(free_map, i);
+  }
+  seal(&free_map_inode);
+  fseek(disk, FREE_MAP_SECTOR * SECTOR_SIZE, SEEK_SET);
   fwrite(&free_map_inode, sizeof(free_map_inode), 1, disk);
   fseek(disk, free_map_content_start * SECTOR_SIZE, SEEK_SET);
-  fwrite(free_map, sizeof(free_map), 1, disk);
+  for (uint32_t i = 0; i < FREEMAP_SECTORS; i++) {
+    uint8_t sector[SECTOR_SIZE] = {0};
+    uint32_t off = i * CHECKSUM_OFF;
+    uint32_t len = FREEMAP_BYTES - off < CHECKSUM_OFF ? FREEMAP_BYTES - off : CHECKSUM_OFF;
+    memcpy(sector, free_map + off, len);
+    seal(sector);
+    fwrite(sector, SECTOR_SIZE, 1, disk);
+  }
   DEBUG_PRINTF("Freemap written\n");
+
+  // Write superblock last.
+  struct super_block sb = {.magic = SUPER_MAGIC,
+                           .version = FS_VERSION,
+                           .sectors = SECTOR_NUM,
+                           .free_map = FREE_MAP_SECTOR,
+                           .root_dir = ROOT_DIR_SECTOR,
+                           .journal_start = JOURNAL_START,
+                           .journal_len = JOURNAL_SECTORS,
+                           .clean = 1,
+                           .unused = {0}};
+  fseek(disk, SUPER_BLOCK_SECTOR * SECTOR_SIZE, SEEK_SET);
+  fwrite(&sb, sizeof(sb), 1, disk);
+  DEBUG_PRINTF("Superblock written\n");
 }
 
 int main(int argc, char* argv[]) {
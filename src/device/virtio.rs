@@ -5,10 +5,15 @@
 //!
 
 use alloc::boxed::Box;
-use core::{arch, ptr};
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering::SeqCst};
+use core::{arch, cmp, ptr};
 
 use crate::mem::{MMIO_BASE, VM_OFFSET};
-use crate::sync::{Lazy, Mutex, Semaphore};
+use crate::sync::{Condvar, Intr, Lazy, Mutex, Semaphore};
+use crate::{OsError, Result};
 
 /* -------------------------------------------------------------------------- */
 /*                                  INTERFACE                                 */
@@ -17,6 +22,12 @@ use crate::sync::{Lazy, Mutex, Semaphore};
 /// Sector size.
 pub const SECTOR_SIZE: usize = 512;
 
+/// Maximum number of segments in a request, see [`Virtio::read_sectors()`].
+/// Transfers of more are split into several requests.
+///
+/// With its header and status, such a request takes a quarter of the queue.
+pub const SEGMENTS_MAX: usize = 30;
+
 /* -------------------------------------------------------------------------- */
 /*                                    MMIO                                    */
 /* -------------------------------------------------------------------------- */
@@ -27,7 +38,9 @@ const MAGIC_VALUE: *const u32 = (MMIO_BASE + 0x0) as _; // RO
 const VERSION: *const u32 = (MMIO_BASE + 0x4) as _; // RO
 const DEVICE_ID: *const u32 = (MMIO_BASE + 0x8) as _; // RO
 const DEVICE_FEATURES: *const u32 = (MMIO_BASE + 0x10) as _; // RO
+const DEVICE_FEATURES_SEL: *mut u32 = (MMIO_BASE + 0x14) as _; // WO
 const DRIVER_FEATURES: *mut u32 = (MMIO_BASE + 0x20) as _; // WO
+const DRIVER_FEATURES_SEL: *mut u32 = (MMIO_BASE + 0x24) as _; // WO
 const QUEUE_SEL: *mut u32 = (MMIO_BASE + 0x30) as _; // WO
 const QUEUE_NUM_MAX: *const u32 = (MMIO_BASE + 0x34) as _; // RO
 const QUEUE_NUM: *mut u32 = (MMIO_BASE + 0x38) as _; // WO
@@ -42,6 +55,7 @@ const QUEUE_DRIVER_LOW: *mut u32 = (MMIO_BASE + 0x90) as _; // WO
 const QUEUE_DRIVER_HIGH: *mut u32 = (MMIO_BASE + 0x94) as _; // WO
 const QUEUE_DEVICE_LOW: *mut u32 = (MMIO_BASE + 0xa0) as _; // WO
 const QUEUE_DEVICE_HIGH: *mut u32 = (MMIO_BASE + 0xa4) as _; // WO
+const CONFIG_GENERATION: *const u32 = (MMIO_BASE + 0xfc) as _; // RO
 const CONFIG: *mut u8 = (MMIO_BASE + 0x100) as _; // RW
 
 // A subset of status fields.
@@ -52,6 +66,29 @@ bitflags::bitflags! {
         const DRIVER = 2;
         const DRIVER_OK = 4;
         const FEATURES_OK = 8;
+        const FAILED = 128;
+    }
+}
+
+// Interrupt status fields.
+// See section 4.2.2 in the spec for more information.
+bitflags::bitflags! {
+    struct Interrupt: u32 {
+        const USED_BUFFER = 1;
+        const CONFIG_CHANGE = 2;
+    }
+}
+
+// The features we support, out of those a block device may offer.
+// See sections 5.2.3 and 6 in the spec for more information.
+bitflags::bitflags! {
+    struct Features: u64 {
+        const SIZE_MAX = 1 << 1; // Segments are at most `size_max` bytes.
+        const SEG_MAX = 1 << 2; // Requests have at most `seg_max` segments.
+        const RO = 1 << 5; // The disk is read-only.
+        const BLK_SIZE = 1 << 6; // The disk has `blk_size` byte blocks.
+        const FLUSH = 1 << 9; // The disk caches writes until flushed.
+        const VERSION_1 = 1 << 32; // The device follows the spec, not legacy ones.
     }
 }
 
@@ -60,27 +97,58 @@ bitflags::bitflags! {
 /* -------------------------------------------------------------------------- */
 
 // A singleton struct representing the virtio device.
-// See section 2.7 in the spec for more information.
 pub struct Virtio {
+    features: Features, // Features negotiated with the device.
+    block_size: usize,  // Block size of the disk. Aligned requests are faster.
+    seg_max: usize,     // Most segments in a request.
+    size_max: usize,    // Most bytes in a segment.
+    live: bool,         // Whether the device is initialized, and takes requests.
+}
+
+// According to the spec, this must be a power of 2.
+// A request takes a descriptor for its header, one for each segment of its
+// buffer, and one for its status, so several requests fit at once.
+const QUEUE_SIZE: u16 = 128;
+
+// The virtqueue. Requests are placed in descriptors taken from the free list,
+// and given back by the interrupt handler once the device is done with them.
+// See section 2.7 in the spec for more information.
+struct Queue {
     desc_table: *mut [Desc; QUEUE_SIZE as _], // Descriptor table.
     avail: *mut Avail,                        // Available ring.
     used: *mut Used,                          // Used ring.
-    capacity: u64,                            // Disk capacity, in 512-byte sectors.
+    free: Vec<u16>,                           // Descriptors not in use.
+    last_used: u16,                           // Used ring index handled up to.
+    inflight: Vec<Option<Arc<Completion>>>,   // Requests by their first descriptor.
 }
 
 // # Safety
 //
-// Pointers in `Virtio` are only used in this type, and `Virtio` is a singleton type.
-// Therefore, These pointers are only used by one thread at a time.
-unsafe impl Send for Virtio {}
-
-// According to the spec, this must be a power of 2.
-// We only use 3 descriptors at a time, so any size larger than 3 is OK.
-const QUEUE_SIZE: u16 = 4;
+// Pointers in `Queue` are only used in this type, and it's only reached
+// through `QUEUE`. Therefore, these pointers are only used by one thread at a time.
+unsafe impl Send for Queue {}
+
+// The queue is shared with the interrupt handler, so it's locked with
+// interrupts off.
+static QUEUE: Lazy<Mutex<Queue, Intr>> = Lazy::new(|| Mutex::new(Queue::new()));
+
+// Waited on by requests for free descriptors.
+// Notified by the interrupt handler after freeing some.
+static DESC_FREED: Lazy<Condvar> = Lazy::new(Condvar::new);
+
+// Disk capacity, in 512-byte sectors.
+// Updated by the interrupt handler when the disk is resized.
+static CAPACITY: AtomicU64 = AtomicU64::new(0);
+
+// A request in flight.
+struct Completion {
+    done: Semaphore, // Up'ed by the interrupt handler.
+    len: AtomicU32,  // Bytes written by the device, set before `done` is up'ed.
+}
 
 // Desctriptor.
 #[repr(C)]
-#[derive(Default)]
+#[derive(Default, Clone, Copy)]
 struct Desc {
     addr: u64,
     len: u32,
@@ -99,7 +167,6 @@ bitflags::bitflags! {
 
 // Available ring.
 #[repr(C)]
-#[derive(Default)]
 struct Avail {
     flags: u16,
     idx: u16,
@@ -108,7 +175,6 @@ struct Avail {
 
 // Used ring.
 #[repr(C)]
-#[derive(Default)]
 struct Used {
     flags: u16,
     idx: u16,
@@ -117,30 +183,57 @@ struct Used {
 
 // Used ring element.
 #[repr(C)]
-#[derive(Default)]
+#[derive(Default, Clone, Copy)]
 struct UsedElem {
     id: u32,
     len: u32,
 }
 
+impl Queue {
+    // Allocate and zero the queue. All descriptors are free.
+    fn new() -> Self {
+        Queue {
+            desc_table: Box::into_raw(Box::new([Desc::default(); QUEUE_SIZE as _])),
+            avail: Box::into_raw(Box::new(Avail {
+                flags: 0,
+                idx: 0,
+                ring: [0; QUEUE_SIZE as _],
+            })),
+            used: Box::into_raw(Box::new(Used {
+                flags: 0,
+                idx: 0,
+                ring: [UsedElem::default(); QUEUE_SIZE as _],
+            })),
+            free: (0..QUEUE_SIZE).rev().collect(),
+            last_used: 0,
+            inflight: (0..QUEUE_SIZE).map(|_| None).collect(),
+        }
+    }
+}
+
 /* -------------------------------------------------------------------------- */
 /*                               INITIALIZATION                               */
 /* -------------------------------------------------------------------------- */
 
 impl Virtio {
-    fn init(&mut self) {
+    // Initialize the device, failing with `Unsupported` if it isn't one we
+    // can drive, or with `IoError` if it doesn't behave.
+    fn init(&mut self) -> Result<()> {
         unsafe {
             // Start device initialization.
             // See section 4.2.3.1 in the spec for more information.
             let magic = MAGIC_VALUE.read_volatile();
-            assert_eq!(magic, 0x74726976);
             let version = VERSION.read_volatile();
-            assert_eq!(version, 0x2);
+            if magic != 0x74726976 || version != 0x2 {
+                return Err(OsError::Unsupported);
+            }
 
             // We only support Virtio Block Device.
             // See section 5.2 in the spec for more information.
             let device_id = DEVICE_ID.read_volatile();
-            assert_eq!(device_id, 0x2);
+            if device_id != 0x2 {
+                return Err(OsError::Unsupported);
+            }
 
             // Reset the device.
             let mut status = Status { bits: 0 };
@@ -154,9 +247,21 @@ impl Virtio {
             status |= Status::DRIVER;
             STATUS.write_volatile(status.bits());
 
-            // Negotiate features. We don't support any feature.
-            _ = DEVICE_FEATURES.read_volatile();
-            DRIVER_FEATURES.write_volatile(0);
+            // Negotiate features, taking those we support out of the offered.
+            // A device not following the spec is of no use.
+            DEVICE_FEATURES_SEL.write_volatile(0);
+            let low = DEVICE_FEATURES.read_volatile();
+            DEVICE_FEATURES_SEL.write_volatile(1);
+            let high = DEVICE_FEATURES.read_volatile();
+            let features = Features::from_bits_truncate((high as u64) << 32 | low as u64);
+            if !features.contains(Features::VERSION_1) {
+                return Err(OsError::Unsupported);
+            }
+            DRIVER_FEATURES_SEL.write_volatile(0);
+            DRIVER_FEATURES.write_volatile(features.bits() as u32);
+            DRIVER_FEATURES_SEL.write_volatile(1);
+            DRIVER_FEATURES.write_volatile((features.bits() >> 32) as u32);
+            self.features = features;
 
             // Finish feature negotiation.
             status |= Status::FEATURES_OK;
@@ -166,42 +271,68 @@ impl Virtio {
             status = Status {
                 bits: STATUS.read_volatile(),
             };
-            assert!(status.contains(Status::FEATURES_OK));
+            if !status.contains(Status::FEATURES_OK) {
+                return Err(OsError::Unsupported);
+            }
 
             // Get capacity of the disk.
-            let capacity = (CONFIG as *mut u64).read_volatile();
-            self.capacity = capacity;
+            let capacity = read_capacity();
+            CAPACITY.store(capacity, SeqCst);
+
+            // Get the limits of the negotiated features.
+            // See section 5.2.4 in the spec for more information.
+            if features.contains(Features::SIZE_MAX) {
+                self.size_max = (CONFIG.add(8) as *mut u32).read_volatile() as _;
+            }
+            if features.contains(Features::SEG_MAX) {
+                let seg_max = (CONFIG.add(12) as *mut u32).read_volatile() as usize;
+                self.seg_max = cmp::min(seg_max, SEGMENTS_MAX);
+            }
+            if features.contains(Features::BLK_SIZE) {
+                // Only blocks of whole sectors are of use to align to.
+                let block_size = (CONFIG.add(20) as *mut u32).read_volatile() as usize;
+                if block_size > 0 && block_size % SECTOR_SIZE == 0 {
+                    self.block_size = block_size;
+                }
+            }
+            // A request must be able to hold a sector.
+            if self.seg_max.saturating_mul(self.size_max) < SECTOR_SIZE {
+                return Err(OsError::Unsupported);
+            }
 
             #[cfg(feature = "debug")]
-            kprintln!("Disk capacity: {} * {}B", capacity, SECTOR_SIZE);
+            kprintln!(
+                "Disk capacity: {} * {}B, features: {:?}",
+                capacity,
+                SECTOR_SIZE,
+                features
+            );
 
             // Select queue 0. We only use queue 0.
             QUEUE_SEL.write_volatile(0);
 
             // Ensure the queue is not already in use.
             let ready = QUEUE_READY.read_volatile();
-            assert_eq!(ready, 0);
+            if ready != 0 {
+                return Err(OsError::IoError);
+            }
 
             // Negotiate queue size.
             let max_size = QUEUE_NUM_MAX.read_volatile();
-            assert!(QUEUE_SIZE <= max_size as _);
+            if QUEUE_SIZE > max_size as _ {
+                return Err(OsError::Unsupported);
+            }
             QUEUE_NUM.write_volatile(QUEUE_SIZE as _);
 
-            // Allocate and zero the queues.
-            self.desc_table = Box::into_raw(Box::default());
-            self.avail = Box::into_raw(Box::default());
-            self.used = Box::into_raw(Box::default());
-            self.desc_table.write(Default::default());
-            self.avail.write(Default::default());
-            self.used.write(Default::default());
-
             // Tell physical addresses of the queues to the device.
-            QUEUE_DESC_LOW.write_volatile((self.desc_table as usize - VM_OFFSET) as u32);
-            QUEUE_DESC_HIGH.write_volatile((self.desc_table as usize - VM_OFFSET >> 32) as u32);
-            QUEUE_DRIVER_LOW.write_volatile((self.avail as usize - VM_OFFSET) as u32);
-            QUEUE_DRIVER_HIGH.write_volatile((self.avail as usize - VM_OFFSET >> 32) as u32);
-            QUEUE_DEVICE_LOW.write_volatile((self.used as usize - VM_OFFSET) as u32);
-            QUEUE_DEVICE_HIGH.write_volatile((self.used as usize - VM_OFFSET >> 32) as u32);
+            let queue = QUEUE.lock();
+            QUEUE_DESC_LOW.write_volatile((queue.desc_table as usize - VM_OFFSET) as u32);
+            QUEUE_DESC_HIGH.write_volatile(((queue.desc_table as usize - VM_OFFSET) >> 32) as u32);
+            QUEUE_DRIVER_LOW.write_volatile((queue.avail as usize - VM_OFFSET) as u32);
+            QUEUE_DRIVER_HIGH.write_volatile(((queue.avail as usize - VM_OFFSET) >> 32) as u32);
+            QUEUE_DEVICE_LOW.write_volatile((queue.used as usize - VM_OFFSET) as u32);
+            QUEUE_DEVICE_HIGH.write_volatile(((queue.used as usize - VM_OFFSET) >> 32) as u32);
+            drop(queue);
 
             // The queue is ready after this.
             QUEUE_READY.write_volatile(0x1);
@@ -210,17 +341,24 @@ impl Virtio {
             status |= Status::DRIVER_OK;
             STATUS.write_volatile(status.bits());
         }
+        self.live = true;
+        Ok(())
     }
 
     pub fn get() -> &'static Mutex<Self> {
         static INSTANCE: Lazy<Mutex<Virtio>> = Lazy::new(|| {
             let virtio = Mutex::new(Virtio {
-                desc_table: ptr::null_mut(),
-                avail: ptr::null_mut(),
-                used: ptr::null_mut(),
-                capacity: 0,
+                features: Features::empty(),
+                block_size: SECTOR_SIZE,
+                seg_max: SEGMENTS_MAX,
+                size_max: usize::MAX,
+                live: false,
             });
-            virtio.lock().init();
+            // Requests to a device that failed to initialize fail instead.
+            if let Err(e) = virtio.lock().init() {
+                unsafe { STATUS.write_volatile(Status::FAILED.bits()) };
+                kprintln!("Virtio disk is not usable: {:?}", e);
+            }
             virtio
         });
 
@@ -228,29 +366,107 @@ impl Virtio {
     }
 
     pub fn capacity(&self) -> u64 {
-        self.capacity
+        CAPACITY.load(SeqCst)
+    }
+
+    /// Whether the disk refuses writes.
+    pub fn read_only(&self) -> bool {
+        self.features.contains(Features::RO)
     }
 
     /// Read a sector from virtio block device.
+    ///
+    /// Requests of several threads are in flight at once, each thread waiting
+    /// for its own to complete.
+    ///
+    /// # Errors
+    /// - [`OsError::IoError`]: the device failed or couldn't be initialized,
+    ///   or `sector` is past the end of the disk.
+    /// - [`OsError::Unsupported`]: the device doesn't take the request.
+    ///
     /// # Example
     ///
     /// ```
     /// let mut buf = [0; SECTOR_SIZE];
-    /// read_sector(0, &mut buf);   // Read from sector 0.
+    /// read_sector(0, &mut buf)?;   // Read from sector 0.
     /// ```
-    pub fn read_sector(sector: u64, buf: &mut [u8; SECTOR_SIZE]) {
-        Virtio::get().lock().read_sector_impl(sector, buf);
+    pub fn read_sector(sector: u64, buf: &mut [u8; SECTOR_SIZE]) -> Result<()> {
+        let segment = (buf.as_mut_ptr() as usize, SECTOR_SIZE);
+        transfer(BlkReqType::In, sector, core::iter::once(segment))
     }
 
     /// Write a sector to virtio block device.
+    ///
+    /// # Errors
+    /// [`OsError::ReadOnlyFs`] if the disk is read-only, or else those of
+    /// [`Self::read_sector()`].
+    ///
     /// # Example
     ///
     /// ```
     /// let buf = [0; SECTOR_SIZE];
-    /// write_sector(0, &mut buf);  // Write to sector 0.
+    /// write_sector(0, &mut buf)?;  // Write to sector 0.
     /// ```
-    pub fn write_sector(sector: u64, buf: &[u8; SECTOR_SIZE]) {
-        Virtio::get().lock().write_sector_impl(sector, buf);
+    pub fn write_sector(sector: u64, buf: &[u8; SECTOR_SIZE]) -> Result<()> {
+        Self::check_writable()?;
+        let segment = (buf.as_ptr() as usize, SECTOR_SIZE);
+        transfer(BlkReqType::Out, sector, core::iter::once(segment))
+    }
+
+    /// Read contiguous sectors from `sector` on, into `segments` one after
+    /// another, in as few requests as the device takes.
+    ///
+    /// Segments are kernel buffers, of any length and alignment, but of a
+    /// whole number of sectors in total. Sectors before the failing request,
+    /// if any, may have been read.
+    ///
+    /// # Errors
+    /// [`OsError::InvalidArgument`] if the segments aren't a whole number of
+    /// sectors, or else those of [`Self::read_sector()`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let (mut a, mut b) = ([0; 100], [0; 924]);
+    /// read_sectors(0, &mut [&mut a, &mut b]);  // Read sectors 0 and 1.
+    /// ```
+    pub fn read_sectors(sector: u64, segments: &mut [&mut [u8]]) -> Result<()> {
+        let segments = segments
+            .iter_mut()
+            .map(|seg| (seg.as_mut_ptr() as usize, seg.len()));
+        transfer(BlkReqType::In, sector, segments)
+    }
+
+    /// Write contiguous sectors from `sector` on, from `segments` one after
+    /// another. See [`Self::read_sectors()`].
+    ///
+    /// # Errors
+    /// See [`Self::write_sector()`].
+    pub fn write_sectors(sector: u64, segments: &[&[u8]]) -> Result<()> {
+        Self::check_writable()?;
+        let segments = segments
+            .iter()
+            .map(|seg| (seg.as_ptr() as usize, seg.len()));
+        transfer(BlkReqType::Out, sector, segments)
+    }
+
+    /// Wait until the writes done so far are on the disk, rather than in the
+    /// device's cache. A device without a cache has nothing to flush.
+    ///
+    /// # Errors
+    /// See [`Self::read_sector()`].
+    pub fn flush() -> Result<()> {
+        if Self::get().lock().features.contains(Features::FLUSH) {
+            request(BlkReqType::Flush, 0, core::iter::empty())?;
+        }
+        Ok(())
+    }
+
+    fn check_writable() -> Result<()> {
+        if Self::get().lock().read_only() {
+            return Err(OsError::ReadOnlyFs);
+        }
+        Ok(())
     }
 }
 
@@ -258,10 +474,6 @@ impl Virtio {
 /*                                READ / WRITE                                */
 /* -------------------------------------------------------------------------- */
 
-// Down'ed by a thread to wait for notification from the disk.
-// Up'ed by interrupt handler.
-static USED_RING_NOTIFICATION: Lazy<Semaphore> = Lazy::new(|| Semaphore::new(0));
-
 // Part of the block request structure.
 // See section 5.2.6 in the spec for more information.
 #[repr(C)]
@@ -273,87 +485,226 @@ struct BlkReqHeader {
 
 // A subset of block request types.
 #[repr(u32)]
+#[derive(Clone, Copy)]
 enum BlkReqType {
     In = 0,
     Out = 1,
+    Flush = 4,
 }
 
-impl Virtio {
-    fn read_sector_impl(&mut self, sector: u64, buf: &mut [u8; SECTOR_SIZE]) {
-        // Construct block request header and tailer.
-        let header = BlkReqHeader {
-            req_type: BlkReqType::In,
-            reserved: 0,
-            sector,
+// Request status written by the device.
+const BLK_S_OK: u8 = 0;
+const BLK_S_IOERR: u8 = 1;
+const BLK_S_UNSUPP: u8 = 2;
+
+// Transfer the sectors from `sector` on, to or from `segments`, given as
+// kernel addresses and lengths, in requests within the device's limits.
+// Stops at the first failing request.
+fn transfer<I>(req_type: BlkReqType, mut sector: u64, segments: I) -> Result<()>
+where
+    I: Iterator<Item = (usize, usize)>,
+{
+    let (block_size, seg_max, size_max) = {
+        let virtio = Virtio::get().lock();
+        (virtio.block_size as u64, virtio.seg_max, virtio.size_max)
+    };
+
+    // Split segments longer than the device takes.
+    let mut pieces: VecDeque<(usize, usize)> = segments
+        .flat_map(|(addr, len)| {
+            (0..len)
+                .step_by(size_max)
+                .map(move |off| (addr + off, cmp::min(size_max, len - off)))
+        })
+        .collect();
+    let total: usize = pieces.iter().map(|(_, len)| len).sum();
+    if total % SECTOR_SIZE != 0 {
+        return Err(OsError::InvalidArgument);
+    }
+
+    while !pieces.is_empty() {
+        let cnt = cmp::min(seg_max, pieces.len());
+        let mut batch: Vec<_> = pieces.drain(..cnt).collect();
+
+        // End the request on a block boundary if it reaches one, or else on
+        // a sector boundary, and leave the rest to the next.
+        let start = sector * SECTOR_SIZE as u64;
+        let end = start + batch.iter().map(|(_, len)| *len as u64).sum::<u64>();
+        let mut excess = if end / block_size * block_size > start {
+            (end % block_size) as usize
+        } else {
+            (end % SECTOR_SIZE as u64) as usize
         };
-        let mut status: u8 = 0xff;
+        while excess > 0 {
+            let (addr, len) = batch.pop().unwrap();
+            if len <= excess {
+                pieces.push_front((addr, len));
+                excess -= len;
+            } else {
+                batch.push((addr, len - excess));
+                pieces.push_front((addr + len - excess, excess));
+                excess = 0;
+            }
+        }
+        // Segments too short to make up a sector in one request.
+        if batch.is_empty() {
+            bounce(req_type, sector, size_max, &mut pieces)?;
+            sector += 1;
+            continue;
+        }
 
-        unsafe {
-            // Initialize the descriptors. See section 2.7.5 in the spec for more information.
-            (*self.desc_table)[0].addr = (ptr::addr_of!(header) as usize - VM_OFFSET) as _;
-            (*self.desc_table)[0].len = core::mem::size_of::<BlkReqHeader>() as _;
-            (*self.desc_table)[0].flag = DescFlag::NEXT;
-            (*self.desc_table)[0].next = 1;
-            (*self.desc_table)[1].addr = (buf as *mut _ as usize - VM_OFFSET) as _;
-            (*self.desc_table)[1].len = SECTOR_SIZE as _;
-            (*self.desc_table)[1].flag = DescFlag::NEXT | DescFlag::WRITE;
-            (*self.desc_table)[1].next = 2;
-            (*self.desc_table)[2].addr = (ptr::addr_of_mut!(status) as usize - VM_OFFSET) as _;
-            (*self.desc_table)[2].len = 1;
-            (*self.desc_table)[2].flag = DescFlag::WRITE;
-            (*self.desc_table)[2].next = 0; // Actually unnecessary.
-
-            // Supply buffer to the device, and wait for notification.
-            self.supply_buffer(0);
-            USED_RING_NOTIFICATION.get().down();
-
-            // Check if the operation was successful.
-            assert_eq!(status, 0);
-            assert_eq!(
-                (*self.used).ring[((*self.used).idx.wrapping_sub(1) % QUEUE_SIZE) as usize].len,
-                (SECTOR_SIZE + 1) as _
-            );
+        let len: usize = batch.iter().map(|(_, len)| len).sum();
+        request(req_type, sector, batch.into_iter())?;
+        sector += (len / SECTOR_SIZE) as u64;
+    }
+    Ok(())
+}
 
-            // Tell the device we've done with the interrupt.
-            INTERRUPT_ACK.write_volatile(1);
+// Transfer the sector at `sector` through a sector buffer, to or from the
+// first `SECTOR_SIZE` bytes of `pieces`, which are taken off.
+fn bounce(
+    req_type: BlkReqType,
+    sector: u64,
+    size_max: usize,
+    pieces: &mut VecDeque<(usize, usize)>,
+) -> Result<()> {
+    let mut parts = Vec::new();
+    let mut filled = 0;
+    while filled < SECTOR_SIZE {
+        let (addr, len) = pieces.pop_front().unwrap();
+        let take = cmp::min(len, SECTOR_SIZE - filled);
+        if take < len {
+            pieces.push_front((addr + take, len - take));
         }
+        parts.push((addr, filled, take));
+        filled += take;
     }
 
-    // See comments in read_sector() for more information.
-    fn write_sector_impl(&mut self, sector: u64, buf: &[u8; SECTOR_SIZE]) {
-        let header = BlkReqHeader {
-            req_type: BlkReqType::Out,
-            reserved: 0,
-            sector,
-        };
-        let mut status: u8 = 0xff;
-        unsafe {
-            (*self.desc_table)[0].addr = (ptr::addr_of!(header) as usize - VM_OFFSET) as _;
-            (*self.desc_table)[0].len = core::mem::size_of::<BlkReqHeader>() as _;
-            (*self.desc_table)[0].flag = DescFlag::NEXT;
-            (*self.desc_table)[0].next = 1;
-            (*self.desc_table)[1].addr = (buf as *const _ as usize - VM_OFFSET) as _;
-            (*self.desc_table)[1].len = SECTOR_SIZE as _;
-            (*self.desc_table)[1].flag = DescFlag::NEXT;
-            (*self.desc_table)[1].next = 2;
-            (*self.desc_table)[2].addr = (ptr::addr_of_mut!(status) as usize - VM_OFFSET) as _;
-            (*self.desc_table)[2].len = 1;
-            (*self.desc_table)[2].flag = DescFlag::WRITE;
-            (*self.desc_table)[2].next = 0;
-
-            self.supply_buffer(0);
-            USED_RING_NOTIFICATION.get().down();
-
-            assert_eq!(status, 0);
-            assert_eq!(
-                (*self.used).ring[((*self.used).idx.wrapping_sub(1) % QUEUE_SIZE) as usize].len,
-                1
-            );
+    let mut buf = [0u8; SECTOR_SIZE];
+    let base = buf.as_mut_ptr() as usize;
+    let is_read = matches!(req_type, BlkReqType::In);
+    if !is_read {
+        for &(addr, off, len) in &parts {
+            unsafe { ptr::copy_nonoverlapping(addr as *const u8, (base + off) as *mut u8, len) };
+        }
+    }
+    let segments = (0..SECTOR_SIZE)
+        .step_by(size_max)
+        .map(|off| (base + off, cmp::min(size_max, SECTOR_SIZE - off)));
+    request(req_type, sector, segments)?;
+    if is_read {
+        for &(addr, off, len) in &parts {
+            unsafe { ptr::copy_nonoverlapping((base + off) as *const u8, addr as *mut u8, len) };
+        }
+    }
+    Ok(())
+}
+
+// Issue a request on the sectors from `sector` on, with `segments` as its
+// buffer, and wait for it to be done.
+fn request<I>(req_type: BlkReqType, sector: u64, segments: I) -> Result<()>
+where
+    I: Iterator<Item = (usize, usize)>,
+{
+    // The device must be initialized first.
+    if !Virtio::get().lock().live {
+        return Err(OsError::IoError);
+    }
 
-            INTERRUPT_ACK.write_volatile(1);
+    let segments: Vec<_> = segments.filter(|(_, len)| *len > 0).collect();
+    let total: usize = segments.iter().map(|(_, len)| len).sum();
+    if segments.len() > SEGMENTS_MAX || total % SECTOR_SIZE != 0 {
+        return Err(OsError::InvalidArgument);
+    }
+
+    // Construct block request header and tailer.
+    let is_read = matches!(req_type, BlkReqType::In);
+    let header = BlkReqHeader {
+        req_type,
+        reserved: 0,
+        sector,
+    };
+    let mut status: u8 = 0xff;
+    let completion = Arc::new(Completion {
+        done: Semaphore::new(0),
+        len: AtomicU32::new(0),
+    });
+
+    // Take the descriptors, waiting for other requests to free them if
+    // there aren't enough.
+    let mut queue = QUEUE.lock();
+    let need = segments.len() + 2;
+    while queue.free.len() < need {
+        DESC_FREED.wait(&mut queue);
+    }
+    let len = queue.free.len();
+    let ids = queue.free.split_off(len - need);
+
+    // Initialize the descriptors, the header first, then a segment each, and
+    // the status last. See section 2.7.5 in the spec for more information.
+    let flag = if is_read {
+        DescFlag::NEXT | DescFlag::WRITE
+    } else {
+        DescFlag::NEXT
+    };
+    let buffers = core::iter::once((
+        ptr::addr_of!(header) as usize,
+        core::mem::size_of::<BlkReqHeader>(),
+        DescFlag::NEXT,
+    ))
+    .chain(segments.iter().map(|(addr, len)| (*addr, *len, flag)))
+    .chain(core::iter::once((
+        ptr::addr_of_mut!(status) as usize,
+        1,
+        DescFlag::WRITE,
+    )));
+    unsafe {
+        let desc_table = &mut *queue.desc_table;
+        for (i, (addr, len, flag)) in buffers.enumerate() {
+            let desc = &mut desc_table[ids[i] as usize];
+            desc.addr = (addr - VM_OFFSET) as _;
+            desc.len = len as _;
+            desc.flag = flag;
+            desc.next = ids.get(i + 1).copied().unwrap_or(0);
         }
+
+        // Supply buffer to the device, and wait for notification.
+        queue.inflight[ids[0] as usize] = Some(completion.clone());
+        queue.supply_buffer(ids[0]);
+    }
+    drop(queue);
+    completion.done.down();
+
+    // Check if the operation was successful. The device reports the bytes it
+    // wrote, which are the ones read and the status.
+    // See section 5.2.6 in the spec for more information.
+    match unsafe { ptr::read_volatile(&status) } {
+        BLK_S_OK => {}
+        BLK_S_IOERR => return Err(OsError::IoError),
+        BLK_S_UNSUPP => return Err(OsError::Unsupported),
+        // Not a status the spec defines.
+        _ => return Err(OsError::IoError),
+    }
+    let len = completion.len.load(SeqCst) as usize;
+    if len != if is_read { total + 1 } else { 1 } {
+        return Err(OsError::IoError);
     }
+    Ok(())
+}
 
+// Read the capacity from the config space, again if the device changed it
+// meanwhile. See section 4.2.2.1 in the spec for more information.
+unsafe fn read_capacity() -> u64 {
+    loop {
+        let generation = CONFIG_GENERATION.read_volatile();
+        let capacity = (CONFIG as *mut u64).read_volatile();
+        if CONFIG_GENERATION.read_volatile() == generation {
+            return capacity;
+        }
+    }
+}
+
+impl Queue {
     // Supply a buffer to the device.
     // See section 2.7.13 in the spec for more information.
     unsafe fn supply_buffer(&mut self, id: u16) {
@@ -372,15 +723,65 @@ impl Virtio {
         // Notify the device.
         QUEUE_NOTIFY.write_volatile(0);
     }
+
+    // Wake up the requests the device has completed, and free their descriptors.
+    // See section 2.7.14 in the spec for more information.
+    fn complete(&mut self) {
+        unsafe {
+            let used_idx = ptr::addr_of!((*self.used).idx).read_volatile();
+            // Ensure the used elements are read after the index.
+            arch::asm!("fence r,r");
+
+            while self.last_used != used_idx {
+                let elem = (*self.used).ring[(self.last_used % QUEUE_SIZE) as usize];
+                self.last_used = self.last_used.wrapping_add(1);
+
+                // A request that isn't in flight has no descriptors of ours
+                // to free, and nobody waiting for it.
+                let slot = self.inflight.get_mut(elem.id as usize);
+                let completion = match slot.and_then(Option::take) {
+                    Some(completion) => completion,
+                    None => continue,
+                };
+                completion.len.store(elem.len, SeqCst);
+
+                let mut id = elem.id as u16;
+                loop {
+                    self.free.push(id);
+                    let desc = (*self.desc_table)[id as usize];
+                    if !desc.flag.contains(DescFlag::NEXT) {
+                        break;
+                    }
+                    id = desc.next;
+                }
+                completion.done.up();
+            }
+        }
+    }
 }
 
 /// Handle the interrupt.
 pub fn handle_interrupt() {
     // Check interrupt status.
     // See section 4.2.3.4 in the spec for more information.
-    let status = unsafe { INTERRUPT_STATUS.read_volatile() };
-    assert_eq!(status, 1);
+    let status = Interrupt::from_bits_truncate(unsafe { INTERRUPT_STATUS.read_volatile() });
 
-    // Wake up the waiting thread.
-    USED_RING_NOTIFICATION.get().up();
+    // Tell the device we've seen the interrupt, before looking at what it
+    // completed, so later completions raise another one.
+    unsafe { INTERRUPT_ACK.write_volatile(status.bits()) };
+
+    // The disk was resized. Sectors past its end now fail.
+    if status.contains(Interrupt::CONFIG_CHANGE) {
+        let capacity = unsafe { read_capacity() };
+        CAPACITY.store(capacity, SeqCst);
+
+        #[cfg(feature = "debug")]
+        kprintln!("Disk capacity changed: {} * {}B", capacity, SECTOR_SIZE);
+    }
+
+    // Wake up the waiting threads.
+    if status.contains(Interrupt::USED_BUFFER) {
+        QUEUE.lock().complete();
+        DESC_FREED.notify_all();
+    }
 }
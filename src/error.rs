@@ -18,4 +18,24 @@ pub enum OsError {
     ArgumentTooLong = -11,
     InvalidFileMode = -12,
     FileNotOpened = -13,
+    JournalFull = -14,
+    BadSuperBlock = -15,
+    FsVersionMismatch = -16,
+    FileNameTooLong = -17,
+    BadDirEntry = -18,
+    SymlinkLoop = -19,
+    IsDir = -20,
+    NotDir = -21,
+    DirNotEmpty = -22,
+    InvalidArgument = -23,
+    CrossDevice = -24,
+    WouldBlock = -25,
+    FileTooLarge = -26,
+    DoubleFree = -27,
+    ChecksumMismatch = -28,
+    ReadOnlyFs = -29,
+    SwapFull = -30,
+    IoError = -31,
+    Unsupported = -32,
+    TransactionAborted = -33,
 }
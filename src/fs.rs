@@ -1,14 +1,24 @@
 //! File System Interface
 //!
 
+pub mod devfs;
 pub mod disk;
+pub mod ext2;
+pub mod fat;
 pub mod inmem;
+pub mod lock;
+pub mod procfs;
+pub mod vfs;
 
+use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec;
+use core::ops::Range;
 
+use self::lock::{FileLocks, LockKind};
 use crate::io::{Read, Seek, Write};
 use crate::sync::Mutex;
-use crate::Result;
+use crate::{OsError, Result};
 
 /* -------------------------------------------------------------------------- */
 /*                                 File System                                */
@@ -29,12 +39,80 @@ pub trait FileSys: Sync + Send + Sized {
     type Device;
 
     fn mount(device: Self::Device) -> Result<Self>;
-    fn unmount(&self);
+    fn unmount(&self) -> Result<()>;
 
     fn open(&self, id: Self::Path) -> Result<File>;
     fn close(&self, file: File);
     fn create(&self, id: Self::Path) -> Result<File>;
     fn remove(&self, id: Self::Path) -> Result<()>;
+    fn rename(&self, from: Self::Path, to: Self::Path) -> Result<()>;
+    fn readdir(&self, id: Self::Path) -> Result<ReadDir>;
+    fn statfs(&self) -> Result<StatFs>;
+
+    /// Write what is held in memory or in the device's cache to the disk.
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Create an empty directory at `id`.
+    ///
+    /// # Errors
+    /// [`OsError::Unsupported`] if the file system has no directories to add,
+    /// which is the default.
+    fn mkdir(&self, _id: Self::Path) -> Result<()> {
+        Err(OsError::Unsupported)
+    }
+
+    /// Where looking up `id`, the last name included if `follow`, leads out of
+    /// the file system: the absolute path to look up instead, once a symlink
+    /// to one is met. `None` if it doesn't, or the lookup fails.
+    fn escape(&self, _id: Self::Path, _follow: bool) -> Option<String> {
+        None
+    }
+}
+
+/// An entry of a directory, see [`FileSys::readdir()`].
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub inum: usize,
+    pub kind: FileType,
+}
+
+/// Iterator over the entries of a directory, as they were when it was read.
+pub struct ReadDir(vec::IntoIter<DirEntry>);
+
+impl ReadDir {
+    pub fn new(entries: vec::Vec<DirEntry>) -> Self {
+        Self(entries.into_iter())
+    }
+}
+
+impl Iterator for ReadDir {
+    type Item = DirEntry;
+
+    fn next(&mut self) -> Option<DirEntry> {
+        self.0.next()
+    }
+}
+
+/// Capacity and usage of a file system, see [`FileSys::statfs()`].
+///
+/// Space is in 512-byte units, like [`Stat::blocks`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StatFs {
+    /// Space of the file system, including its own metadata.
+    pub blocks: usize,
+    /// Space that can still be allocated.
+    pub free_blocks: usize,
+    /// The largest contiguous free space.
+    pub largest_free: usize,
+    /// Number of files and directories.
+    pub inodes: usize,
+    /// Number of files and directories that can still be created.
+    pub free_inodes: usize,
+    /// Space set aside for swapping.
+    pub swap_blocks: usize,
 }
 
 /* -------------------------------------------------------------------------- */
@@ -51,41 +129,227 @@ pub trait FileSys: Sync + Send + Sized {
 pub trait Vnode: Sync + Send {
     fn read_at(&self, buf: &mut [u8], off: usize) -> Result<usize>;
     fn write_at(&self, buf: &[u8], off: usize) -> Result<usize>;
+
+    /// Write `buf` at the end of the file, returning the offset it was written
+    /// at and the bytes written.
+    ///
+    /// Files shared by several writers must do this atomically. The default
+    /// doesn't, which only suits devices ignoring offsets.
+    fn append(&self, buf: &[u8]) -> Result<(usize, usize)> {
+        let off = self.len();
+        Ok((off, self.write_at(buf, off)?))
+    }
+
     fn deny_write(&self);
     fn allow_write(&self);
 
     fn inum(&self) -> usize;
     fn len(&self) -> usize;
+    fn stat(&self) -> Stat;
     fn resize(&self, size: usize) -> Result<()>;
-    fn close(&self);
+
+    /// Write back what's held in memory, and free a removed file.
+    fn close(&self) -> Result<()>;
+
+    /// Free the space of `len` bytes from `off`, which then read as zeros.
+    /// The length of the file doesn't change.
+    ///
+    /// # Errors
+    /// [`OsError::InvalidArgument`] if the file can't have holes, which is
+    /// the default.
+    fn punch_hole(&self, _off: usize, _len: usize) -> Result<()> {
+        Err(OsError::InvalidArgument)
+    }
+
+    /// Advisory locks on the file, if it supports them.
+    fn locks(&self) -> Option<&FileLocks> {
+        None
+    }
+}
+
+/// Type of a file. Values agree with `user/lib/fstat.h`.
+#[repr(u16)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Dir = 1,
+    File = 2,
+    Device = 3,
+    Symlink = 4,
+}
+
+impl FileType {
+    pub fn from_raw(raw: u16) -> Option<Self> {
+        match raw {
+            1 => Some(Self::Dir),
+            2 => Some(Self::File),
+            3 => Some(Self::Device),
+            4 => Some(Self::Symlink),
+            _ => None,
+        }
+    }
+
+    /// Permission bits a new file of this type gets.
+    pub fn default_mode(self) -> u16 {
+        match self {
+            Self::Dir => 0o755,
+            Self::File | Self::Device => 0o644,
+            Self::Symlink => 0o777,
+        }
+    }
+}
+
+/// Metadata of a file, see [`Vnode::stat()`].
+///
+/// Times are in milliseconds since boot.
+#[derive(Debug, Clone, Copy)]
+pub struct Stat {
+    pub inum: usize,
+    pub kind: FileType,
+    /// Permission bits, `rwxrwxrwx`.
+    pub mode: u16,
+    /// Number of names referring to the file.
+    pub nlink: u32,
+    pub size: usize,
+    /// Space taken in 512-byte units, which is less than `size` implies
+    /// for a file with holes.
+    pub blocks: usize,
+    /// Last access time.
+    pub atime: u64,
+    /// Last modification time.
+    pub mtime: u64,
+    /// Creation time.
+    pub ctime: u64,
 }
 
 /* -------------------------------------------------------------------------- */
 /*                                    File                                    */
 /* -------------------------------------------------------------------------- */
 
+bitflags::bitflags! {
+    /// Flags of opening a file. Values agree with `user/lib/fcntl.h`.
+    ///
+    /// The lowest two bits are the access mode, one of `RDONLY`, `WRONLY`
+    /// and `RDWR`.
+    pub struct OpenFlags: u32 {
+        const RDONLY = 0x000;
+        const WRONLY = 0x001;
+        const RDWR = 0x002;
+        /// Every write goes to the end of the file.
+        const APPEND = 0x008;
+        /// Create the file if it doesn't exist.
+        const CREAT = 0x200;
+        /// Truncate the file to 0 if opened for writing.
+        const TRUNC = 0x400;
+        /// With `CREAT`, fail if the file exists.
+        const EXCL = 0x800;
+    }
+}
+
+impl OpenFlags {
+    const ACCESS_MODE: u32 = 0x003;
+
+    pub fn readable(self) -> bool {
+        self.bits() & Self::ACCESS_MODE != Self::WRONLY.bits()
+    }
+
+    pub fn writable(self) -> bool {
+        self.bits() & Self::ACCESS_MODE != Self::RDONLY.bits()
+    }
+}
+
 /// A file descriptor, binding with a [`Vnode`], that has
 /// independent position and permissions. It provides basic
 /// file I/O interface.
+///
+/// A new file can both read and write. See [`File::with_flags()`].
 #[derive(Clone)]
 pub struct File {
     vnode: Arc<dyn Vnode>,
     pos: usize,
     deny_write: bool,
+    flags: OpenFlags,
+    owner: Arc<LockOwner>,
+}
+
+/// A [`File`] and its clones, as the owner of advisory locks. The locks are
+/// released when the last of them is dropped.
+struct LockOwner(Arc<dyn Vnode>);
+
+impl LockOwner {
+    fn id(&self) -> usize {
+        self as *const Self as usize
+    }
+}
+
+impl Drop for LockOwner {
+    fn drop(&mut self) {
+        if let Some(locks) = self.0.locks() {
+            locks.release(self.id());
+        }
+    }
 }
 
 impl File {
+    /// # Errors
+    /// [`OsError::InvalidFileMode`] if the file isn't opened for writing.
     pub fn set_len(&mut self, size: usize) -> Result<()> {
+        if !self.flags.writable() {
+            return Err(OsError::InvalidFileMode);
+        }
         self.vnode.resize(size)
     }
 
+    /// Deallocate `range` of the file, which then reads as zeros. The length
+    /// of the file stays.
+    ///
+    /// # Errors
+    /// - [`OsError::InvalidFileMode`]: the file isn't opened for writing.
+    /// - [`OsError::InvalidArgument`]: the file can't have holes.
+    pub fn punch_hole(&self, range: Range<usize>) -> Result<()> {
+        if !self.flags.writable() {
+            return Err(OsError::InvalidFileMode);
+        }
+        self.vnode
+            .punch_hole(range.start, range.end.saturating_sub(range.start))
+    }
+
     pub fn inum(&self) -> usize {
         self.vnode.inum()
     }
+
+    pub fn stat(&self) -> Stat {
+        self.vnode.stat()
+    }
+
+    /// Lock `range` of the file, `0..usize::MAX` for the whole of it. With
+    /// `wait`, block until conflicting locks are released.
+    ///
+    /// # Errors
+    /// - [`OsError::WouldBlock`]: without `wait`, someone else holds a conflicting lock.
+    /// - [`OsError::InvalidArgument`]: `range` is empty, or the file can't be locked.
+    pub fn lock(&self, kind: LockKind, range: Range<usize>, wait: bool) -> Result<()> {
+        if range.is_empty() {
+            return Err(OsError::InvalidArgument);
+        }
+        let locks = self.vnode.locks().ok_or(OsError::InvalidArgument)?;
+        locks.lock(self.owner.id(), kind, range, wait)
+    }
+
+    /// Unlock `range` of the file.
+    pub fn unlock(&self, range: Range<usize>) -> Result<()> {
+        let locks = self.vnode.locks().ok_or(OsError::InvalidArgument)?;
+        locks.unlock(self.owner.id(), range);
+        Ok(())
+    }
 }
 
 impl Read for File {
+    /// # Errors
+    /// [`OsError::InvalidFileMode`] if the file isn't opened for reading.
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if !self.flags.readable() {
+            return Err(OsError::InvalidFileMode);
+        }
         let cnt = self.vnode.read_at(buf, self.pos)?;
         self.pos += cnt;
         Ok(cnt)
@@ -93,7 +357,17 @@ impl Read for File {
 }
 
 impl Write for File {
+    /// # Errors
+    /// [`OsError::InvalidFileMode`] if the file isn't opened for writing.
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if !self.flags.writable() {
+            return Err(OsError::InvalidFileMode);
+        }
+        if self.flags.contains(OpenFlags::APPEND) {
+            let (off, cnt) = self.vnode.append(buf)?;
+            self.pos = off + cnt;
+            return Ok(cnt);
+        }
         let cnt = self.vnode.write_at(buf, self.pos)?;
         self.pos += cnt;
         Ok(cnt)
@@ -117,12 +391,25 @@ impl Seek for File {
 impl File {
     pub fn new(vnode: Arc<dyn Vnode>) -> Self {
         Self {
+            owner: Arc::new(LockOwner(vnode.clone())),
             vnode,
             pos: 0,
             deny_write: false,
+            flags: OpenFlags::RDWR,
         }
     }
 
+    /// Restrict the file to `flags`, of which only the access mode and
+    /// [`OpenFlags::APPEND`] matter once opened.
+    pub fn with_flags(mut self, flags: OpenFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub fn flags(&self) -> OpenFlags {
+        self.flags
+    }
+
     pub fn deny_write(&mut self) {
         self.deny_write = true;
         self.vnode.deny_write();
@@ -0,0 +1,321 @@
+//! Device file system.
+//!
+//! A flat directory of character and block devices, mounted at `/dev`:
+//!
+//! - `console`: reads from and writes to the SBI console.
+//! - `null`: discards writes, reads nothing.
+//! - `zero`: discards writes, reads zeros.
+//! - `random`: reads pseudo-random bytes, seeded from the timer.
+//! - `vda`: the virtio disk, as a file of its sectors.
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cmp::min;
+
+use crate::device::virtio::{Virtio, SECTOR_SIZE};
+use crate::sbi::{console, console_getchar, console_putchar};
+use crate::thread;
+use crate::{OsError, Result};
+
+use super::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                   FileSys                                  */
+/* -------------------------------------------------------------------------- */
+
+/// Inumber of the root directory, devices are numbered after it.
+const ROOT_INUM: usize = 1;
+
+pub struct DevFs {
+    devices: Vec<(&'static str, Arc<dyn Vnode>)>,
+}
+
+impl DevFs {
+    fn get(&self, path: &str) -> Result<&Arc<dyn Vnode>> {
+        let name = path.trim_start_matches('/');
+        if name.is_empty() {
+            return Err(OsError::IsDir);
+        }
+        self.devices
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, dev)| dev)
+            .ok_or(OsError::NoSuchFile)
+    }
+}
+
+impl FileSys for DevFs {
+    type Device = ();
+    type Path = String;
+
+    fn mount(_device: Self::Device) -> Result<Self> {
+        let devices: Vec<(&'static str, Arc<dyn Vnode>)> = alloc::vec![
+            ("console", Arc::new(Console)),
+            ("null", Arc::new(Null)),
+            ("zero", Arc::new(Zero)),
+            ("random", Arc::new(Random(Mutex::new(0)))),
+            ("vda", Arc::new(Disk)),
+        ];
+        Ok(Self { devices })
+    }
+
+    fn unmount(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn open(&self, id: Self::Path) -> Result<File> {
+        Ok(File::new(self.get(&id)?.clone()))
+    }
+
+    fn close(&self, _file: File) {}
+
+    /// Devices can't be created, creating one opens it.
+    fn create(&self, id: Self::Path) -> Result<File> {
+        self.open(id)
+    }
+
+    fn remove(&self, id: Self::Path) -> Result<()> {
+        self.get(&id)?;
+        Err(OsError::InvalidArgument)
+    }
+
+    fn rename(&self, from: Self::Path, _to: Self::Path) -> Result<()> {
+        self.get(&from)?;
+        Err(OsError::InvalidArgument)
+    }
+
+    fn readdir(&self, id: Self::Path) -> Result<ReadDir> {
+        match self.get(&id) {
+            Err(OsError::IsDir) => {}
+            Ok(_) => return Err(OsError::NotDir),
+            Err(e) => return Err(e),
+        }
+        let entries = self
+            .devices
+            .iter()
+            .map(|(name, dev)| DirEntry {
+                name: String::from(*name),
+                inum: dev.inum(),
+                kind: FileType::Device,
+            })
+            .collect();
+        Ok(ReadDir::new(entries))
+    }
+
+    /// Devices take no space, and there are no more of them to create.
+    fn statfs(&self) -> Result<StatFs> {
+        Ok(StatFs {
+            inodes: self.devices.len() + 1,
+            ..StatFs::default()
+        })
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                   Devices                                  */
+/* -------------------------------------------------------------------------- */
+
+/// Stat of a device, which doesn't keep times.
+fn device_stat(inum: usize, size: usize) -> Stat {
+    Stat {
+        inum,
+        kind: FileType::Device,
+        mode: FileType::Device.default_mode(),
+        nlink: 1,
+        size,
+        blocks: 0,
+        atime: 0,
+        mtime: 0,
+        ctime: 0,
+    }
+}
+
+/// Implement the [`Vnode`] methods a device has nothing to do with.
+macro_rules! device_common {
+    ($inum: expr) => {
+        fn inum(&self) -> usize {
+            $inum
+        }
+
+        fn stat(&self) -> Stat {
+            device_stat($inum, self.len())
+        }
+
+        fn resize(&self, _size: usize) -> Result<()> {
+            Err(OsError::InvalidArgument)
+        }
+
+        fn close(&self) -> Result<()> {
+            Ok(())
+        }
+        fn deny_write(&self) {}
+        fn allow_write(&self) {}
+    };
+}
+
+/// The SBI console.
+///
+/// Reads block until a byte arrives, then return what has arrived up to the
+/// end of the line.
+struct Console;
+
+impl Vnode for Console {
+    device_common!(ROOT_INUM + 1);
+
+    fn len(&self) -> usize {
+        0
+    }
+
+    fn read_at(&self, buf: &mut [u8], _off: usize) -> Result<usize> {
+        let mut cnt = 0;
+        while cnt < buf.len() {
+            let ch = console_getchar();
+            if ch == usize::MAX {
+                if cnt > 0 {
+                    break;
+                }
+                thread::schedule();
+                continue;
+            }
+            buf[cnt] = ch as u8;
+            cnt += 1;
+            if ch as u8 == b'\n' {
+                break;
+            }
+        }
+        Ok(cnt)
+    }
+
+    fn write_at(&self, buf: &[u8], _off: usize) -> Result<usize> {
+        let _lock = console::stdout().lock();
+        for &byte in buf {
+            console_putchar(byte as usize);
+        }
+        Ok(buf.len())
+    }
+}
+
+struct Null;
+
+impl Vnode for Null {
+    device_common!(ROOT_INUM + 2);
+
+    fn len(&self) -> usize {
+        0
+    }
+
+    fn read_at(&self, _buf: &mut [u8], _off: usize) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn write_at(&self, buf: &[u8], _off: usize) -> Result<usize> {
+        Ok(buf.len())
+    }
+}
+
+struct Zero;
+
+impl Vnode for Zero {
+    device_common!(ROOT_INUM + 3);
+
+    fn len(&self) -> usize {
+        0
+    }
+
+    fn read_at(&self, buf: &mut [u8], _off: usize) -> Result<usize> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write_at(&self, buf: &[u8], _off: usize) -> Result<usize> {
+        Ok(buf.len())
+    }
+}
+
+/// A xorshift64* generator, seeded from the clock on first read.
+///
+/// Not suitable for cryptography.
+struct Random(Mutex<u64>);
+
+impl Vnode for Random {
+    device_common!(ROOT_INUM + 4);
+
+    fn len(&self) -> usize {
+        0
+    }
+
+    fn read_at(&self, buf: &mut [u8], _off: usize) -> Result<usize> {
+        let mut state = self.0.lock();
+        if *state == 0 {
+            *state = crate::sbi::timer::clock() as u64 | 1;
+        }
+        for chunk in buf.chunks_mut(8) {
+            *state ^= *state >> 12;
+            *state ^= *state << 25;
+            *state ^= *state >> 27;
+            let bytes = state.wrapping_mul(0x2545_f491_4f6c_dd1d).to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+        Ok(buf.len())
+    }
+
+    /// Writes are discarded, they don't add entropy.
+    fn write_at(&self, buf: &[u8], _off: usize) -> Result<usize> {
+        Ok(buf.len())
+    }
+}
+
+/// The virtio disk.
+///
+/// Writing to it would bypass the disk file system and its journal, so it's
+/// read-only while the file system is mounted.
+struct Disk;
+
+impl Vnode for Disk {
+    device_common!(ROOT_INUM + 5);
+
+    fn len(&self) -> usize {
+        Virtio::get().lock().capacity() as usize * SECTOR_SIZE
+    }
+
+    fn read_at(&self, buf: &mut [u8], off: usize) -> Result<usize> {
+        let len = self.len();
+        if off >= len {
+            return Ok(0);
+        }
+        let end = min(len, off.saturating_add(buf.len()));
+        let mut sector = [0; SECTOR_SIZE];
+        let mut pos = off;
+        while pos < end {
+            let (idx, start) = (pos / SECTOR_SIZE, pos % SECTOR_SIZE);
+            let cnt = min(SECTOR_SIZE - start, end - pos);
+            Virtio::read_sector(idx as u64, &mut sector)?;
+            buf[pos - off..pos - off + cnt].copy_from_slice(&sector[start..start + cnt]);
+            pos += cnt;
+        }
+        Ok(end - off)
+    }
+
+    fn write_at(&self, buf: &[u8], off: usize) -> Result<usize> {
+        if disk::is_mounted() {
+            return Err(OsError::ReadOnlyFs);
+        }
+        let len = self.len();
+        if off >= len {
+            return Err(OsError::UnexpectedEOF);
+        }
+        let end = min(len, off.saturating_add(buf.len()));
+        let mut sector = [0; SECTOR_SIZE];
+        let mut pos = off;
+        while pos < end {
+            let (idx, start) = (pos / SECTOR_SIZE, pos % SECTOR_SIZE);
+            let cnt = min(SECTOR_SIZE - start, end - pos);
+            if cnt < SECTOR_SIZE {
+                Virtio::read_sector(idx as u64, &mut sector)?;
+            }
+            sector[start..start + cnt].copy_from_slice(&buf[pos - off..pos - off + cnt]);
+            Virtio::write_sector(idx as u64, &sector)?;
+            pos += cnt;
+        }
+        Ok(end - off)
+    }
+}
@@ -1,26 +1,34 @@
 //! On disk file system.
 //!
+mod checksum;
 mod dir;
 mod free_map;
 mod inode;
+mod journal;
 mod path;
+mod super_block;
 mod swap;
 
 // Expose path for it is frequently used.
 pub use self::path::Path;
 // Expose swap utils.
-pub use self::swap::Swap;
+pub use self::swap::{Swap, SwapSlot, SwapStats};
 
-use alloc::collections::BTreeMap;
+use alloc::collections::{BTreeMap, BTreeSet, VecDeque};
+use alloc::string::String;
 use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering::SeqCst};
 
-use self::dir::RootDir;
+use self::dir::{Dir, DirNode};
 use self::free_map::FreeMap;
 use self::inode::Inode;
+use self::super_block::SuperBlock;
 
-use super::{File, FileSys, Vnode};
+use super::{DirEntry, File, FileSys, FileType, ReadDir, StatFs, Vnode};
 use crate::device::virtio::{Virtio, SECTOR_SIZE};
-use crate::sync::{Lazy, Mutex};
+use crate::sync::{Lazy, Mutex, MutexGuard, Primitive};
 use crate::{OsError, Result};
 
 /// Inode number.
@@ -29,15 +37,16 @@ use crate::{OsError, Result};
 pub type Inum = u32;
 
 /// Inumber of sector free bitmap.
-pub(self) const FREE_MAP_SECTOR: Inum = 0;
+pub(self) const FREE_MAP_SECTOR: Inum = 1;
 
 /// Inumber of root dir.
-pub(self) const ROOT_DIR_SECTOR: Inum = 1;
+pub(self) const ROOT_DIR_SECTOR: Inum = 2;
 
-/// Root dir length in sector.
-///
-/// Currently we hard code this.
-const ROOT_DIR_SECTOR_LEN: u32 = 8;
+/// Initial root dir length in sector. It grows as files are created.
+const ROOT_DIR_SECTOR_LEN: u32 = 1;
+
+/// Maximum number of symlinks followed in one lookup.
+const SYMLINK_DEPTH_MAX: usize = 8;
 
 /// Global disk filesys.
 ///
@@ -45,16 +54,16 @@ const ROOT_DIR_SECTOR_LEN: u32 = 8;
 ///
 /// - **get disk sector free bitmap:**
 /// ```ignore
-/// let freemap = DISKFS.free_map.lock();
+/// let mut freemap = DISKFS.free_map.lock();
 /// // Do sth.
-/// let new_sector = freemap.alloc(1);
+/// let new_sector = freemap.alloc_near(hint, 1)?;
 /// ```
 ///
 /// - **get root dir:**
 /// ```ignore
-/// let rootdir = DISKFS.root_dir.lock();
+/// let mut rootdir = DISKFS.root_dir.lock();
 /// // Do sth.
-/// let if_exist = rootdir.exists("/myfile".into());
+/// let if_exist = rootdir.exists("myfile");
 /// ```
 ///
 /// - **file operations (create, open, remove):**
@@ -69,8 +78,54 @@ const ROOT_DIR_SECTOR_LEN: u32 = 8;
 /// // remove
 /// DISKFS.remove("/new_file".into())?;
 /// ```
-pub static DISKFS: Lazy<DiskFs> =
-    Lazy::new(|| DiskFs::mount(Virtio::get()).expect("Disk fs mounting failed"));
+///
+/// - **links:**
+/// ```ignore
+/// // another name of the same file
+/// DISKFS.link("/new_file".into(), "/hard".into())?;
+/// // a file naming another one
+/// DISKFS.symlink("/new_file", "/soft".into())?;
+/// ```
+///
+/// - **directories:**
+/// ```ignore
+/// DISKFS.mkdir("/dir".into())?;
+/// DISKFS.rename("/new_file".into(), "/dir/file".into())?;
+/// ```
+///
+/// A read-only disk is mounted read-only: nothing is written to it, and
+/// changing the file system fails with [`OsError::ReadOnlyFs`].
+///
+/// Mounting fails on a disk without a valid superblock. Such a disk has to be
+/// formatted explicitly with [`DiskFs::format()`] first, which the
+/// `fs-disk-format` feature does before mounting. With the `fs-disk-strict`
+/// feature, it fails on a corrupted disk as well, see [`MountOptions::strict`].
+pub static DISKFS: Lazy<DiskFs> = Lazy::new(|| {
+    #[cfg(feature = "fs-disk-format")]
+    DiskFs::format(Virtio::get()).expect("Disk fs formatting failed");
+    let options = MountOptions {
+        strict: cfg!(feature = "fs-disk-strict"),
+    };
+    DiskFs::mount_with(Virtio::get(), options).expect("Disk fs mounting failed")
+});
+
+/// Whether a [`DiskFs`] is mounted on the disk.
+static MOUNTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether a [`DiskFs`] is mounted on the disk, which then must not be
+/// written by anyone else.
+pub fn is_mounted() -> bool {
+    MOUNTED.load(SeqCst)
+}
+
+/// How a [`DiskFs`] is mounted.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MountOptions {
+    /// Check every inode and directory block on mount, and refuse to mount
+    /// if one is corrupted. Otherwise, corruption is only reported with
+    /// [`OsError::ChecksumMismatch`] once the corrupted metadata is used.
+    pub strict: bool,
+}
 
 /// Disk file system.
 ///
@@ -79,9 +134,14 @@ pub static DISKFS: Lazy<DiskFs> =
 pub struct DiskFs {
     #[allow(unused)]
     device: &'static Mutex<Virtio>,
+    /// Whether the disk is read-only, so the file system is mounted
+    /// read-only as well.
+    read_only: bool,
+    super_block: Mutex<SuperBlock>,
     pub(self) free_map: Mutex<FreeMap>,
-    pub root_dir: Mutex<RootDir>,
+    pub root_dir: Arc<DirNode>,
     inode_table: Mutex<BTreeMap<Inum, Weak<Inode>>>,
+    dir_table: Mutex<BTreeMap<Inum, Weak<DirNode>>>,
 }
 
 impl FileSys for DiskFs {
@@ -89,115 +149,624 @@ impl FileSys for DiskFs {
     type Path = Path;
 
     fn mount(device: Self::Device) -> Result<Self> {
-        let capacity = device.lock().capacity();
-        let inode_table = Mutex::new(BTreeMap::new());
-        let free_map = Mutex::new({
-            let size = capacity as u32;
-            if let Ok(loaded) = FreeMap::load(size) {
-                loaded
+        Self::mount_with(device, MountOptions::default())
+    }
+
+    /// Unmount, marking the file system clean on the disk once the rest is
+    /// written. If that fails, it stays dirty, for the next mount to check.
+    fn unmount(&self) -> Result<()> {
+        MOUNTED.store(false, SeqCst);
+        if self.read_only {
+            return Ok(());
+        }
+        self.free_map.lock().flush()?;
+        let mut super_block = self.super_block.lock();
+        super_block.set_clean(true);
+        if let Err(e) = super_block.flush() {
+            super_block.set_clean(false);
+            return Err(e);
+        }
+        Virtio::flush()
+    }
+
+    fn create(&self, id: Self::Path) -> Result<super::File> {
+        let tx = journal::begin();
+        let vnode = {
+            let (dir, name) = self.parent(&id, &mut Vec::new())?;
+            let exists = dir.lock().exists(&name);
+            if exists {
+                let vnode = self.lookup(&id, true)?;
+                if vnode.kind() == FileType::Dir {
+                    return Err(OsError::IsDir);
+                }
+                // Trunc existing file to 0 on create.
+                vnode.resize(0)?;
+                vnode
             } else {
-                FreeMap::new_format(size)?
+                self.create_inode(&dir, &name, FileType::File, &[])?
             }
-        });
-        let root_dir = Mutex::new({
-            let vnode = if let Ok(loaded) = Inode::open(ROOT_DIR_SECTOR) {
-                loaded
-            } else {
-                let start = free_map.lock().alloc(ROOT_DIR_SECTOR_LEN)?;
-
-                #[cfg(feature = "debug")]
-                kprintln!(
-                    "Rootdir format at sector {}, len={}",
-                    start,
-                    ROOT_DIR_SECTOR_LEN
-                );
-
-                Inode::create(
-                    ROOT_DIR_SECTOR,
-                    start,
-                    ROOT_DIR_SECTOR_LEN as usize * SECTOR_SIZE,
-                )?
+        };
+        tx.commit()?;
+
+        Ok(File::new(vnode))
+    }
+
+    fn open(&self, id: Self::Path) -> Result<super::File> {
+        Ok(File::new(self.lookup(&id, true)?))
+    }
+
+    fn close(&self, _file: super::File) {}
+
+    /// Remove a file, a symlink, or an empty directory.
+    fn remove(&self, id: Self::Path) -> Result<()> {
+        let tx = journal::begin();
+        {
+            let (dir, name) = self.parent(&id, &mut Vec::new())?;
+            let inode = self.get_inode(dir.lock().lookup(&name)?)?;
+            let child = match inode.kind() {
+                FileType::Dir => Some(self.get_dir(inode.clone())?),
+                _ => None,
+            };
+
+            {
+                let (mut parent, child) = Self::lock_pair(&dir, child.as_ref());
+                if let Some(mut child) = child {
+                    if !child.is_empty()? {
+                        return Err(OsError::DirNotEmpty);
+                    }
+                }
+                parent.remove(&name)?;
+            }
+            // Sectors are freed when the last name is gone and the last holder
+            // of the inode is dropped, which must happen after the dirs are unlocked.
+            inode.unlink()?;
+        }
+        tx.commit()
+    }
+
+    /// Move `from` to `to`, replacing the file at `to` if any.
+    ///
+    /// The move is done in one transaction, with both parent directories
+    /// locked, so no one ever sees both names or neither of them.
+    fn rename(&self, from: Self::Path, to: Self::Path) -> Result<()> {
+        let tx = journal::begin();
+        {
+            let (from_dir, from_name) = self.parent(&from, &mut Vec::new())?;
+            // Directories passed through to reach the new parent.
+            let mut trail = Vec::new();
+            let (to_dir, to_name) = self.parent(&to, &mut trail)?;
+
+            let inum = from_dir.lock().lookup(&from_name)?;
+            let inode = self.get_inode(inum)?;
+            // A directory can't be moved into itself.
+            if inode.kind() == FileType::Dir && (to_dir.inum() == inum || trail.contains(&inum)) {
+                return Err(OsError::InvalidArgument);
+            }
+
+            let existing = to_dir.lock().lookup(&to_name);
+            let replaced = match existing {
+                Ok(old) if old == inum => return tx.commit(),
+                Ok(old) => Some(self.get_inode(old)?),
+                Err(_) => None,
             };
+            if let Some(old) = &replaced {
+                match (inode.kind() == FileType::Dir, old.kind() == FileType::Dir) {
+                    (false, true) => return Err(OsError::IsDir),
+                    (true, false) => return Err(OsError::NotDir),
+                    (true, true) if !self.get_dir(old.clone())?.lock().is_empty()? => {
+                        return Err(OsError::DirNotEmpty)
+                    }
+                    _ => {}
+                }
+            }
+
+            // Entries only change in transactions. We are in one, so what is
+            // checked above still holds.
+            //
+            // A replaced entry is pointed to the moved inode in place, and a new
+            // one, which may not fit, is made before the old name is removed.
+            // The target name is kept if any step fails.
+            {
+                let (mut from_entries, mut to_entries) = Self::lock_pair(&from_dir, Some(&to_dir));
+                let to_entries = to_entries.as_deref_mut().unwrap_or(&mut *from_entries);
+                match replaced {
+                    Some(_) => to_entries.replace(&to_name, inum).map(|_| ())?,
+                    None => to_entries.insert(&to_name, inum)?,
+                }
+                from_entries.remove(&from_name)?;
+            }
+
+            if let Some(old) = replaced {
+                old.unlink()?;
+            }
+        }
+        tx.commit()
+    }
+
+    /// Create an empty directory at `path`.
+    ///
+    /// # Errors
+    /// [`OsError::CreateExistInode`] if `path` exists.
+    fn mkdir(&self, path: Self::Path) -> Result<()> {
+        let tx = journal::begin();
+        {
+            let (dir, name) = self.parent(&path, &mut Vec::new())?;
+            if dir.lock().exists(&name) {
+                return Err(OsError::CreateExistInode);
+            }
+            self.create_inode(&dir, &name, FileType::Dir, &dir::empty_block())?;
+        }
+        tx.commit()
+    }
+
+    fn readdir(&self, id: Self::Path) -> Result<ReadDir> {
+        let dir = self.get_dir(self.lookup(&id, true)?)?;
+        let entries = dir.lock().entries()?;
+        let entries = entries
+            .into_iter()
+            .map(|(name, inum)| {
+                Ok(DirEntry {
+                    name,
+                    inum: inum as usize,
+                    kind: self.get_inode(inum)?.kind(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(ReadDir::new(entries))
+    }
 
+    /// Every free sector can hold an inode, so there are as many free inodes
+    /// as free sectors. Inodes in use are counted by walking the tree from
+    /// the root dir, the free map included.
+    fn statfs(&self) -> Result<StatFs> {
+        let (blocks, free_blocks, largest_free) = {
+            let free_map = self.free_map.lock();
+            (
+                self.super_block.lock().sectors(),
+                free_map.free_cnt(),
+                free_map.largest_free(),
+            )
+        };
+        let swap_blocks = match self.lookup(swap::SWAP_PATH, true) {
+            Ok(inode) => inode.len() / SECTOR_SIZE,
+            Err(OsError::NoSuchFile) => 0,
+            Err(e) => return Err(e),
+        };
+        Ok(StatFs {
+            blocks: blocks as usize,
+            free_blocks: free_blocks as usize,
+            largest_free: largest_free as usize,
+            inodes: self.count_inodes()?,
+            free_inodes: free_blocks as usize,
+            swap_blocks,
+        })
+    }
+
+    /// Transactions are on the disk once committed, but may still be in the
+    /// device's cache.
+    fn sync(&self) -> Result<()> {
+        Virtio::flush()
+    }
+
+    fn escape(&self, id: Self::Path, follow: bool) -> Option<String> {
+        match self.walk_within(&id, follow, &mut Vec::new(), &mut 0) {
+            Ok(Walked::Out(path)) => Some(path),
+            _ => None,
+        }
+    }
+}
+
+impl DiskFs {
+    /// Mount the file system on `device` with `options`.
+    ///
+    /// # Errors
+    /// Besides the errors of a superblock that doesn't fit, a corrupted free
+    /// map or root dir inode is [`OsError::ChecksumMismatch`], and so is any
+    /// corrupted inode or directory block if [`MountOptions::strict`] is set.
+    pub fn mount_with(device: &'static Mutex<Virtio>, options: MountOptions) -> Result<Self> {
+        let read_only = device.lock().read_only();
+        let capacity = device.lock().capacity();
+        let mut super_block = SuperBlock::load(capacity)?;
+
+        // Redo the last committed transaction before reading any metadata.
+        journal::load(super_block.journal_start(), read_only)?;
+        if !super_block.is_clean() {
+            kprintln!("Disk fs was not unmounted cleanly, journal replayed.");
+        }
+        if options.strict {
+            Self::check()?;
+        }
+
+        let inode_table = Mutex::new(BTreeMap::new());
+        let dir_table = Mutex::new(BTreeMap::new());
+        let free_map = Mutex::new(FreeMap::load(super_block.sectors())?);
+        let root_dir = {
+            let vnode = Inode::open(ROOT_DIR_SECTOR)?;
             let weak = Arc::downgrade(&vnode);
             inode_table.lock().insert(ROOT_DIR_SECTOR, weak);
-            RootDir(File::new(vnode))
-        });
+            let dir = Arc::new(DirNode::new(ROOT_DIR_SECTOR, File::new(vnode)));
+            dir_table
+                .lock()
+                .insert(ROOT_DIR_SECTOR, Arc::downgrade(&dir));
+            dir
+        };
+
+        // Until unmounted, a crash leaves the file system dirty.
+        if !read_only {
+            super_block.set_clean(false);
+            super_block.flush()?;
+        }
+
+        MOUNTED.store(true, SeqCst);
         Ok(Self {
             device,
+            read_only,
+            super_block: Mutex::new(super_block),
             free_map,
             root_dir,
             inode_table,
+            dir_table,
         })
     }
 
-    fn unmount(&self) {
-        let _ = self.free_map.lock().flush();
+    /// Whether the file system is unmounted cleanly. It isn't while mounted.
+    pub fn is_clean(&self) -> bool {
+        self.super_block.lock().is_clean()
     }
 
-    fn create(&self, id: Self::Path) -> Result<super::File> {
-        let vnode = if self.root_dir.lock().exists(&id) {
-            let inum = self.root_dir.lock().path2inum(&id).unwrap();
-            let vnode =
-                if let Some(arc) = self.inode_table.lock().get(&inum).and_then(Weak::upgrade) {
-                    arc
-                } else {
-                    Inode::open(inum)?
-                };
-            // Trunc existing file to 0 on create.
-            vnode.resize(0)?;
-            vnode
-        } else {
-            let sector = self.free_map.lock().alloc(1)?;
-
-            let cnt = bytes_to_sectors(0);
-            let start = self.free_map.lock().alloc(cnt)?;
-
-            let vnode = Inode::create(sector, start, 0)?;
-            let weak = Arc::downgrade(&vnode);
-            self.inode_table.lock().insert(sector, weak);
+    /// Check the checksums of every inode and directory block reachable from
+    /// the root dir.
+    ///
+    /// Sectors are read directly, since the file system isn't mounted yet.
+    fn check() -> Result<()> {
+        let mut seen = BTreeSet::new();
+        let mut dirs = vec![ROOT_DIR_SECTOR];
+        seen.insert(ROOT_DIR_SECTOR);
+        let mut block = [0; SECTOR_SIZE];
+        while let Some(inum) = dirs.pop() {
+            for sector in Inode::open(inum)?.content_sectors()? {
+                match sector {
+                    // A hole where a block should be can't pass.
+                    0 => block = [0; SECTOR_SIZE],
+                    _ => journal::read_sector(sector, &mut block)?,
+                }
+                for child in dir::check_block(&block)? {
+                    if seen.insert(child) && Inode::open(child)?.kind() == FileType::Dir {
+                        dirs.push(child);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
 
-            self.root_dir.lock().insert(&id, sector)?;
-            vnode
-        };
+    /// Number of inodes reachable from the root dir, and the free map.
+    fn count_inodes(&self) -> Result<usize> {
+        let mut seen: BTreeSet<_> = [FREE_MAP_SECTOR, ROOT_DIR_SECTOR].iter().copied().collect();
+        let mut dirs = vec![self.root_dir.clone()];
+        while let Some(dir) = dirs.pop() {
+            let entries = dir.lock().entries()?;
+            for (_, inum) in entries {
+                if !seen.insert(inum) {
+                    continue;
+                }
+                let inode = self.get_inode(inum)?;
+                if inode.kind() == FileType::Dir {
+                    dirs.push(self.get_dir(inode)?);
+                }
+            }
+        }
+        Ok(seen.len())
+    }
 
-        Ok(File::new(vnode))
+    /// Add `new` as another name of the file at `old`.
+    ///
+    /// # Errors
+    /// - [`OsError::NoSuchFile`]: `old` doesn't exist.
+    /// - [`OsError::CreateExistInode`]: `new` exists.
+    /// - [`OsError::IsDir`]: `old` is a directory.
+    pub fn link(&self, old: Path, new: Path) -> Result<()> {
+        let tx = journal::begin();
+        {
+            let inode = self.lookup(&old, false)?;
+            if inode.kind() == FileType::Dir {
+                return Err(OsError::IsDir);
+            }
+            let (dir, name) = self.parent(&new, &mut Vec::new())?;
+            {
+                let mut dir = dir.lock();
+                if dir.exists(&name) {
+                    return Err(OsError::CreateExistInode);
+                }
+                dir.insert(&name, inode.inum() as Inum)?;
+            }
+            inode.link()?;
+        }
+        tx.commit()
     }
 
-    fn open(&self, id: Self::Path) -> Result<super::File> {
-        if !self.root_dir.lock().exists(&id) {
-            return Err(OsError::NoSuchFile);
+    /// Create a symlink at `link`, naming `target`. The target needn't exist.
+    ///
+    /// # Errors
+    /// - [`OsError::CreateExistInode`]: `link` exists.
+    /// - [`OsError::CstrFormatErr`]: `target` is empty.
+    pub fn symlink(&self, target: &str, link: Path) -> Result<()> {
+        if target.is_empty() {
+            return Err(OsError::CstrFormatErr);
         }
-        // Expect existing.
-        let inum = self.root_dir.lock().path2inum(&id).unwrap();
-        if let Some(arc) = self.inode_table.lock().get(&inum).and_then(Weak::upgrade) {
-            return Ok(File::new(arc));
+        let tx = journal::begin();
+        {
+            let (dir, name) = self.parent(&link, &mut Vec::new())?;
+            if dir.lock().exists(&name) {
+                return Err(OsError::CreateExistInode);
+            }
+            self.create_inode(&dir, &name, FileType::Symlink, target.as_bytes())?;
+        }
+        tx.commit()
+    }
+
+    /// Read the target of the symlink at `link`.
+    pub fn readlink(&self, link: Path) -> Result<String> {
+        let inode = self.lookup(&link, false)?;
+        if inode.kind() != FileType::Symlink {
+            return Err(OsError::UnknownFormat);
+        }
+        Self::read_link(&inode)
+    }
+
+    /// Whether there is a file at `path`.
+    pub(self) fn exists(&self, path: &str) -> bool {
+        self.lookup(path, true).is_ok()
+    }
+
+    /// Create an inode of `kind` holding `content`, and name it `name` in `dir`.
+    ///
+    /// Must be called in a transaction.
+    fn create_inode(
+        &self,
+        dir: &DirNode,
+        name: &str,
+        kind: FileType,
+        content: &[u8],
+    ) -> Result<Arc<Inode>> {
+        // Keep the inode close to its directory.
+        let sector = self.free_map.lock().alloc_near(dir.inum(), 1)?;
+        let vnode = Inode::create(sector, kind)?;
+        // On failure the inode is freed when dropped.
+        let named = vnode
+            .write_at(content, 0)
+            .and_then(|cnt| match cnt == content.len() {
+                true => Ok(()),
+                false => Err(OsError::DiskSectorAllocFail),
+            })
+            .and_then(|_| dir.lock().insert(name, sector));
+        if let Err(e) = named {
+            vnode.remove();
+            return Err(e);
         }
 
-        let vnode = Inode::open(inum)?;
         let weak = Arc::downgrade(&vnode);
-        self.inode_table.lock().insert(inum, weak);
+        self.inode_table.lock().insert(sector, weak);
+        Ok(vnode)
+    }
 
-        Ok(File::new(vnode))
+    /// Undo in memory what the transaction just aborted did: give back the
+    /// sectors it allocated, take back those it freed, and reread the inodes it
+    /// may have changed, if it `wrote` any sector or changed the free map.
+    ///
+    /// The inodes are returned, to be dropped once the transaction is ended.
+    fn rollback(&self, wrote: bool) -> Vec<Arc<Inode>> {
+        if !self.free_map.lock().rollback() && !wrote {
+            return Vec::new();
+        }
+        let inodes: Vec<_> = self
+            .inode_table
+            .lock()
+            .values()
+            .filter_map(Weak::upgrade)
+            .collect();
+        for inode in &inodes {
+            if let Err(e) = inode.reload() {
+                kprintln!("Failed to reload inode {}: {:?}", inode.inum(), e);
+            }
+        }
+        inodes
     }
 
-    fn close(&self, _file: super::File) {}
+    /// Get the in-memory inode of `inum`, opening it if no one holds it.
+    fn get_inode(&self, inum: Inum) -> Result<Arc<Inode>> {
+        let mut table = self.inode_table.lock();
+        if let Some(arc) = table.get(&inum).and_then(Weak::upgrade) {
+            return Ok(arc);
+        }
 
-    fn remove(&self, id: Self::Path) -> Result<()> {
-        let inum = self.root_dir.lock().path2inum(&id)?;
-        let mut rootdir = DISKFS.root_dir.lock();
-        rootdir.remove(inum)?;
-        if let Some(arc) = self.inode_table.lock().get(&inum).and_then(Weak::upgrade) {
-            arc.remove();
-            return Ok(());
+        let vnode = Inode::open(inum)?;
+        table.insert(inum, Arc::downgrade(&vnode));
+        Ok(vnode)
+    }
+
+    /// Get the in-memory directory of `inode`.
+    ///
+    /// # Errors
+    /// [`OsError::NotDir`] if `inode` isn't a directory.
+    fn get_dir(&self, inode: Arc<Inode>) -> Result<Arc<DirNode>> {
+        if inode.kind() != FileType::Dir {
+            return Err(OsError::NotDir);
         }
-        // Not opened
-        let inode = Inode::open(inum)?;
-        inode.remove();
+        let inum = inode.inum() as Inum;
+        let mut table = self.dir_table.lock();
+        if let Some(arc) = table.get(&inum).and_then(Weak::upgrade) {
+            return Ok(arc);
+        }
+
+        let dir = Arc::new(DirNode::new(inum, File::new(inode)));
+        table.insert(inum, Arc::downgrade(&dir));
+        Ok(dir)
+    }
+
+    /// Lock `a`, and `b` if it's another directory, in order of inumber.
+    fn lock_pair<'a>(
+        a: &'a DirNode,
+        b: Option<&'a Arc<DirNode>>,
+    ) -> (
+        MutexGuard<'a, Dir, Primitive>,
+        Option<MutexGuard<'a, Dir, Primitive>>,
+    ) {
+        match b {
+            Some(b) if b.inum() < a.inum() => {
+                let b = b.lock();
+                (a.lock(), Some(b))
+            }
+            Some(b) if b.inum() > a.inum() => {
+                let a = a.lock();
+                (a, Some(b.lock()))
+            }
+            _ => (a.lock(), None),
+        }
+    }
+
+    /// Look up `path` from the root dir, following symlinks on the way,
+    /// and the last one as well if `follow`.
+    fn lookup(&self, path: &str, follow: bool) -> Result<Arc<Inode>> {
+        self.walk(path, follow, &mut Vec::new())
+    }
+
+    /// [`Self::lookup()`], recording in `trail` the directories passed through.
+    ///
+    /// Absolute symlink targets are looked up in this file system, as if it
+    /// were mounted at `/`. Through [`crate::fs::vfs::VFS`], they are looked up
+    /// in the mount table instead, see [`FileSys::escape()`].
+    ///
+    /// # Errors
+    /// [`OsError::SymlinkLoop`] if more than [`SYMLINK_DEPTH_MAX`] symlinks are met.
+    fn walk(&self, path: &str, follow: bool, trail: &mut Vec<Inum>) -> Result<Arc<Inode>> {
+        let mut depth = 0;
+        let mut walked = self.walk_within(path, follow, trail, &mut depth)?;
+        loop {
+            match walked {
+                Walked::Found(inode) => return Ok(inode),
+                Walked::Out(path) => walked = self.walk_within(&path, follow, trail, &mut depth)?,
+            }
+        }
+    }
+
+    /// [`Self::walk()`] until a symlink with an absolute target is met, which
+    /// may lead out of the file system. `depth` counts the symlinks met.
+    fn walk_within(
+        &self,
+        path: &str,
+        follow: bool,
+        trail: &mut Vec<Inum>,
+        depth: &mut usize,
+    ) -> Result<Walked> {
+        let mut names: VecDeque<String> = components(path).map(String::from).collect();
+        let mut inode = self.get_inode(ROOT_DIR_SECTOR)?;
+        trail.clear();
+
+        while let Some(name) = names.pop_front() {
+            let dir = self.get_dir(inode)?;
+            trail.push(dir.inum());
+            let next = self.get_inode(dir.lock().lookup(&name)?)?;
+
+            if next.kind() == FileType::Symlink && (follow || !names.is_empty()) {
+                if *depth == SYMLINK_DEPTH_MAX {
+                    return Err(OsError::SymlinkLoop);
+                }
+                *depth += 1;
+
+                let mut target = Self::read_link(&next)?;
+                if target.starts_with('/') {
+                    for name in names {
+                        target.push('/');
+                        target.push_str(&name);
+                    }
+                    return Ok(Walked::Out(target));
+                }
+                // Relative targets are looked up from the dir of the symlink,
+                // which is passed through again.
+                for name in components(&target).rev() {
+                    names.push_front(name.into());
+                }
+                inode = self.get_inode(dir.inum())?;
+                trail.pop();
+                continue;
+            }
+            inode = next;
+        }
+        Ok(Walked::Found(inode))
+    }
+
+    /// Split `path` into its parent directory and its last name.
+    fn parent(&self, path: &str, trail: &mut Vec<Inum>) -> Result<(Arc<DirNode>, String)> {
+        let path = path.trim_end_matches('/');
+        let (dir, name) = match path.rfind('/') {
+            Some(i) => (&path[..i], &path[i + 1..]),
+            None => ("", path),
+        };
+        // The root dir has no parent.
+        if components(name).next().is_none() {
+            return Err(OsError::CstrFormatErr);
+        }
+        let dir = self.get_dir(self.walk(dir, true, trail)?)?;
+        Ok((dir, name.into()))
+    }
+
+    fn read_link(inode: &Inode) -> Result<String> {
+        let mut target = vec![0; inode.len()];
+        inode.read_at(&mut target, 0)?;
+        String::from_utf8(target).map_err(|_| OsError::CstrFormatErr)
+    }
+
+    /// Format `device` with an empty file system. Everything on it is lost.
+    ///
+    /// This must be done before [`DISKFS`] is mounted. Nothing is written
+    /// through the journal, and the free map is made in place.
+    pub fn format(device: &'static Mutex<Virtio>) -> Result<()> {
+        let capacity = device.lock().capacity();
+        let super_block = SuperBlock::new(capacity as u32);
+
+        journal::format(super_block.journal_start())?;
+        let mut free_map = FreeMap::new_format(&super_block)?;
+
+        let start = free_map.alloc(ROOT_DIR_SECTOR_LEN)?;
+
+        #[cfg(feature = "debug")]
+        kprintln!(
+            "Rootdir format at sector {}, len={}",
+            start,
+            ROOT_DIR_SECTOR_LEN
+        );
+
+        Inode::create_contiguous(
+            ROOT_DIR_SECTOR,
+            start,
+            ROOT_DIR_SECTOR_LEN as usize * SECTOR_SIZE,
+            FileType::Dir,
+            &dir::empty_block(),
+        )?;
+        free_map.flush()?;
+
+        // Write the superblock last, so a half formatted disk won't be mounted.
+        super_block.flush()?;
         Ok(())
     }
 }
 
+/// Where [`DiskFs::walk_within()`] ends.
+enum Walked {
+    /// The inode looked up.
+    Found(Arc<Inode>),
+    /// The absolute path the lookup goes on with, which a symlink led to.
+    Out(String),
+}
+
+/// Names in `path`, which is relative to the root dir
+/// whether it starts with `/` or not.
+fn components(path: &str) -> impl DoubleEndedIterator<Item = &str> {
+    path.split('/')
+        .filter(|name| !name.is_empty() && *name != ".")
+}
+
 pub(self) fn bytes_to_sectors(bytes: usize) -> u32 {
     ((bytes + SECTOR_SIZE - 1) / SECTOR_SIZE) as u32
 }
@@ -0,0 +1,62 @@
+//! Metadata checksums.
+//!
+//! Inodes, directory blocks and free map sectors keep the CRC-32 (IEEE) of
+//! their first [`CHECKSUM_OFF`] bytes in their last 4 bytes. It's stored when
+//! the sector is written and verified when it's read back, so a corrupted
+//! sector is reported instead of trusted.
+use core::convert::TryInto;
+
+use crate::device::virtio::SECTOR_SIZE;
+use crate::{OsError, Result};
+
+/// Offset of the checksum in a sector. Bytes before it are covered.
+pub(super) const CHECKSUM_OFF: usize = SECTOR_SIZE - 4;
+
+const POLY: u32 = 0xedb88320;
+
+static TABLE: [u32; 256] = table();
+
+const fn table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC-32 of `data`.
+pub(super) fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, byte| {
+        TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Store the checksum of `sector` in it.
+pub(super) fn seal(sector: &mut [u8; SECTOR_SIZE]) {
+    let crc = crc32(&sector[..CHECKSUM_OFF]);
+    sector[CHECKSUM_OFF..].copy_from_slice(&crc.to_le_bytes());
+}
+
+/// Check the checksum stored in `sector`.
+///
+/// # Errors
+/// [`OsError::ChecksumMismatch`] if it doesn't match the content.
+pub(super) fn verify(sector: &[u8; SECTOR_SIZE]) -> Result<()> {
+    let stored = u32::from_le_bytes(sector[CHECKSUM_OFF..].try_into().unwrap());
+    if stored != crc32(&sector[..CHECKSUM_OFF]) {
+        return Err(OsError::ChecksumMismatch);
+    }
+    Ok(())
+}
@@ -1,49 +1,157 @@
-//! Root dir.
+//! Directory.
 //!
-use super::{Inum, Path};
+//! A directory is an array of sector-sized blocks. Each block holds a chain of
+//! variable-length entries: a [`DirEntryHeader`] followed by the name, padded
+//! to 4 bytes, and ends with its checksum. Entries never cross a block, so the
+//! last entry of a block stretches to the checksum. Unused space is either
+//! an entry with `inum == 0`, or slack at the end of a used entry.
+//!
+//! The directory grows by a block when no block has room for a new entry,
+//! and drops its empty trailing blocks when entries are removed.
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use super::checksum::{self, CHECKSUM_OFF};
+use super::Inum;
+use crate::device::virtio::SECTOR_SIZE;
 use crate::fs::File;
 use crate::io::prelude::*;
+use crate::sync::{Mutex, MutexGuard, Primitive};
 use crate::{OsError, Result};
 
-const FILE_NAME_LEN_MAX: usize = 28;
+/// Maximum length of a file name in bytes.
+pub const FILE_NAME_LEN_MAX: usize = 255;
+
+const HEADER_LEN: usize = size_of::<DirEntryHeader>();
 
-/// 32-byte entry.
+/// Bytes of a block the entries take, the rest is the checksum.
+const ENTRIES_LEN: usize = CHECKSUM_OFF;
+
+/// Header of a directory entry, followed by `name_len` bytes of name.
 #[repr(C)]
-pub struct DirEntry {
-    name: [u8; FILE_NAME_LEN_MAX],
+#[derive(Clone, Copy)]
+struct DirEntryHeader {
+    /// Inumber of the file, 0 if the entry is unused.
     inum: Inum,
+    /// Length of the whole entry, including the name and padding.
+    rec_len: u16,
+    name_len: u8,
+    reserved: u8,
 }
 
-impl DirEntry {
-    pub fn is_valid(&self) -> bool {
-        self.name[0] != '#' as u8 && self.name[0] != 0
+impl DirEntryHeader {
+    fn is_valid(&self) -> bool {
+        self.inum != 0
     }
+}
 
-    pub fn invalidate(&mut self) {
-        self.name[0] = '#' as u8
-    }
+/// Bytes an entry with a name of `name_len` occupies at least.
+fn entry_len(name_len: usize) -> usize {
+    (HEADER_LEN + name_len + 3) & !3
 }
 
-/// Currently only support root dir. Other files should not be dir.
-pub struct RootDir(pub(super) File);
-
-impl RootDir {
-    /// Convert a path to inumber. This will iteratively search through the
-    /// root dir entries, return the first entry that with the same name of given one.
-    pub fn path2inum(&mut self, path: &Path) -> Result<Inum> {
-        self.0.rewind()?;
-        while let Ok(entry) = self.0.read_into::<DirEntry>() {
-            if !entry.is_valid() {
-                continue;
+/// A directory block.
+struct Block([u8; SECTOR_SIZE]);
+
+impl Block {
+    /// An empty block, holding one unused entry.
+    fn empty() -> Self {
+        let mut block = Block([0; SECTOR_SIZE]);
+        block.set_header(
+            0,
+            DirEntryHeader {
+                inum: 0,
+                rec_len: ENTRIES_LEN as u16,
+                name_len: 0,
+                reserved: 0,
+            },
+        );
+        block
+    }
+
+    fn header(&self, off: usize) -> DirEntryHeader {
+        unsafe { (self.0.as_ptr().add(off) as *const DirEntryHeader).read_unaligned() }
+    }
+
+    fn set_header(&mut self, off: usize, header: DirEntryHeader) {
+        unsafe { (self.0.as_mut_ptr().add(off) as *mut DirEntryHeader).write_unaligned(header) }
+    }
+
+    fn name(&self, off: usize, header: &DirEntryHeader) -> &[u8] {
+        &self.0[off + HEADER_LEN..off + HEADER_LEN + header.name_len as usize]
+    }
+
+    fn set_name(&mut self, off: usize, name: &[u8]) {
+        self.0[off + HEADER_LEN..off + HEADER_LEN + name.len()].copy_from_slice(name);
+    }
+
+    /// Whether no entry in the block is in use.
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.entries()?.iter().all(|(_, header)| !header.is_valid()))
+    }
+
+    /// Offsets and headers of all entries in the block.
+    ///
+    /// # Errors
+    /// [`OsError::BadDirEntry`] if the chain of entries is broken.
+    fn entries(&self) -> Result<Vec<(usize, DirEntryHeader)>> {
+        let mut entries = Vec::new();
+        let mut off = 0;
+        while off < ENTRIES_LEN {
+            let header = self.header(off);
+            let rec_len = header.rec_len as usize;
+            if rec_len < HEADER_LEN
+                || off + rec_len > ENTRIES_LEN
+                || entry_len(header.name_len as usize) > rec_len
+            {
+                return Err(OsError::BadDirEntry);
             }
-            let name = unsafe {
-                core::ffi::CStr::from_ptr(&entry.name as *const u8 as *const i8)
-                    .to_str()
-                    .or(Err(OsError::CstrFormatErr))?
-            };
-            // Deref `Path` to `String`.
-            if path.eq(name) {
-                return Ok(entry.inum);
+            entries.push((off, header));
+            off += rec_len;
+        }
+        Ok(entries)
+    }
+}
+
+/// A directory in memory, shared by everyone using it.
+///
+/// Its entries can only be accessed with the lock held. When more than one
+/// directory is locked at a time, they are locked in order of inumber.
+pub struct DirNode {
+    inum: Inum,
+    dir: Mutex<Dir>,
+}
+
+impl DirNode {
+    pub(super) fn new(inum: Inum, file: File) -> Self {
+        Self {
+            inum,
+            dir: Mutex::new(Dir(file)),
+        }
+    }
+
+    pub fn inum(&self) -> Inum {
+        self.inum
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, Dir, Primitive> {
+        self.dir.lock()
+    }
+}
+
+/// Entries of a directory.
+pub struct Dir(File);
+
+impl Dir {
+    /// Find the inumber of the entry with given name.
+    pub fn lookup(&mut self, name: &str) -> Result<Inum> {
+        for idx in 0..self.block_cnt()? {
+            let block = self.read_block(idx)?;
+            for (off, header) in block.entries()? {
+                if header.is_valid() && block.name(off, &header) == name.as_bytes() {
+                    return Ok(header.inum);
+                }
             }
         }
         Err(OsError::NoSuchFile)
@@ -52,50 +160,208 @@ impl RootDir {
     /// Check if there is a file with the given name.
     ///
     /// # See
-    /// [`path2inum()`].
-    pub fn exists(&mut self, path: &Path) -> bool {
-        self.path2inum(path).is_ok()
+    /// [`lookup()`].
+    pub fn exists(&mut self, name: &str) -> bool {
+        self.lookup(name).is_ok()
+    }
+
+    /// Names and inumbers of all entries.
+    pub fn entries(&mut self) -> Result<Vec<(String, Inum)>> {
+        let mut entries = Vec::new();
+        for idx in 0..self.block_cnt()? {
+            let block = self.read_block(idx)?;
+            for (off, header) in block.entries()? {
+                if header.is_valid() {
+                    let name = String::from_utf8_lossy(block.name(off, &header));
+                    entries.push((name.into_owned(), header.inum));
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Whether the directory has no entry.
+    pub fn is_empty(&mut self) -> Result<bool> {
+        for idx in 0..self.block_cnt()? {
+            if !self.read_block(idx)?.is_empty()? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
     }
 
     /// Insert an entry with given name and inumber.
-    pub fn insert(&mut self, path: &Path, inum: Inum) -> Result<()> {
-        if !path.is_ascii() {
+    ///
+    /// # Errors
+    /// - [`OsError::CstrFormatErr`]: the name is empty, `.`, `..`, or has a `/` or a NUL.
+    /// - [`OsError::FileNameTooLong`]: the name is longer than [`FILE_NAME_LEN_MAX`] bytes.
+    /// - [`OsError::DiskSectorAllocFail`]: the directory needs to grow, but the disk is full.
+    pub fn insert(&mut self, name: &str, inum: Inum) -> Result<()> {
+        if name.is_empty() || name == "." || name == ".." || name.contains(&['/', '\0'][..]) {
             return Err(OsError::CstrFormatErr);
         }
-        let pos = self.first_invalid()?;
-        let mut entry = DirEntry {
-            name: [0; FILE_NAME_LEN_MAX],
-            inum,
-        };
-        entry.name[0..core::cmp::min(path.len(), FILE_NAME_LEN_MAX - 1)]
-            .copy_from_slice(path.as_bytes());
-        self.0.seek(SeekFrom::Start(pos))?;
-        self.0.write_from(entry)?;
-        Ok(())
+        if name.len() > FILE_NAME_LEN_MAX {
+            return Err(OsError::FileNameTooLong);
+        }
+        let needed = entry_len(name.len());
+
+        let block_cnt = self.block_cnt()?;
+        for idx in 0..block_cnt {
+            let mut block = self.read_block(idx)?;
+            for (off, mut header) in block.entries()? {
+                let rec_len = header.rec_len as usize;
+                // Where the new entry goes in this one, and the length left to the old entry.
+                let (at, remained) = if !header.is_valid() {
+                    (off, 0)
+                } else {
+                    let used = entry_len(header.name_len as usize);
+                    (off + used, used)
+                };
+                if rec_len - remained < needed {
+                    continue;
+                }
+
+                if remained > 0 {
+                    header.rec_len = remained as u16;
+                    block.set_header(off, header);
+                }
+                block.set_header(
+                    at,
+                    DirEntryHeader {
+                        inum,
+                        rec_len: (rec_len - remained) as u16,
+                        name_len: name.len() as u8,
+                        reserved: 0,
+                    },
+                );
+                block.set_name(at, name.as_bytes());
+                return self.write_block(idx, &block);
+            }
+        }
+
+        // No room, append a new block.
+        let mut block = Block::empty();
+        block.set_header(
+            0,
+            DirEntryHeader {
+                inum,
+                rec_len: ENTRIES_LEN as u16,
+                name_len: name.len() as u8,
+                reserved: 0,
+            },
+        );
+        block.set_name(0, name.as_bytes());
+        // Writing past the end extends the directory in one go.
+        self.write_block(block_cnt, &block)
     }
 
-    /// Remove an entry from root dir by given inumber.
-    pub fn remove(&mut self, inum: Inum) -> Result<()> {
-        self.0.rewind()?;
-        while let Ok(mut entry) = self.0.read_into::<DirEntry>() {
-            if entry.inum == inum {
-                entry.invalidate();
-                self.0.seek(SeekFrom::Current(-32))?;
-                self.0.write_from(entry)?;
+    /// Point the entry of given name to `inum`, returning the inumber it had.
+    /// Unlike removing it and inserting another, this never needs room.
+    ///
+    /// # Errors
+    /// [`OsError::NoSuchFile`] if there isn't such an entry.
+    pub fn replace(&mut self, name: &str, inum: Inum) -> Result<Inum> {
+        for idx in 0..self.block_cnt()? {
+            let mut block = self.read_block(idx)?;
+            for (off, mut header) in block.entries()? {
+                if header.is_valid() && block.name(off, &header) == name.as_bytes() {
+                    let old = header.inum;
+                    header.inum = inum;
+                    block.set_header(off, header);
+                    self.write_block(idx, &block)?;
+                    return Ok(old);
+                }
             }
         }
-        // Ignore unexisting file.
-        Ok(())
+        Err(OsError::NoSuchFile)
     }
 
-    /// Find the first invalid place of entry. We may use it to insert a new one later.
-    fn first_invalid(&mut self) -> Result<usize> {
-        self.0.rewind()?;
-        while let Ok(entry) = self.0.read_into::<DirEntry>() {
-            if !entry.is_valid() {
-                return Ok(self.0.seek(SeekFrom::Current(-32)).unwrap());
+    /// Remove the entry of given name, returning its inumber.
+    ///
+    /// # Errors
+    /// [`OsError::NoSuchFile`] if there isn't such an entry.
+    pub fn remove(&mut self, name: &str) -> Result<Inum> {
+        for idx in 0..self.block_cnt()? {
+            let mut block = self.read_block(idx)?;
+            // The previous entry in the block absorbs the removed one.
+            let mut prev: Option<(usize, DirEntryHeader)> = None;
+            for (off, mut header) in block.entries()? {
+                if !header.is_valid() || block.name(off, &header) != name.as_bytes() {
+                    prev = Some((off, header));
+                    continue;
+                }
+                let inum = header.inum;
+                match prev {
+                    Some((prev_off, mut prev_header)) => {
+                        prev_header.rec_len += header.rec_len;
+                        block.set_header(prev_off, prev_header);
+                    }
+                    None => {
+                        header.inum = 0;
+                        block.set_header(off, header);
+                    }
+                }
+                self.write_block(idx, &block)?;
+                self.trim()?;
+                return Ok(inum);
             }
         }
-        Err(OsError::RootDirFull)
+        Err(OsError::NoSuchFile)
+    }
+
+    /// Drop empty blocks at the end of the directory, keeping at least one.
+    fn trim(&mut self) -> Result<()> {
+        let mut block_cnt = self.block_cnt()?;
+        while block_cnt > 1 && self.read_block(block_cnt - 1)?.is_empty()? {
+            block_cnt -= 1;
+        }
+        if block_cnt < self.block_cnt()? {
+            self.0.set_len(block_cnt * SECTOR_SIZE)?;
+        }
+        Ok(())
+    }
+
+    fn block_cnt(&mut self) -> Result<usize> {
+        Ok(self.0.len()? / SECTOR_SIZE)
+    }
+
+    /// # Errors
+    /// [`OsError::ChecksumMismatch`] if the block is corrupted.
+    fn read_block(&mut self, idx: usize) -> Result<Block> {
+        let mut block = Block([0; SECTOR_SIZE]);
+        self.0.seek(SeekFrom::Start(idx * SECTOR_SIZE))?;
+        self.0.read_exact(&mut block.0)?;
+        checksum::verify(&block.0)?;
+        Ok(block)
+    }
+
+    fn write_block(&mut self, idx: usize, block: &Block) -> Result<()> {
+        let mut sector = block.0;
+        checksum::seal(&mut sector);
+        self.0.seek(SeekFrom::Start(idx * SECTOR_SIZE))?;
+        self.0.write_all(&sector)
     }
 }
+
+/// Check a directory block as read from the disk, and return the inumbers
+/// in its entries.
+///
+/// # Errors
+/// [`OsError::ChecksumMismatch`] or [`OsError::BadDirEntry`] if it's corrupted.
+pub(super) fn check_block(sector: &[u8; SECTOR_SIZE]) -> Result<Vec<Inum>> {
+    checksum::verify(sector)?;
+    let block = Block(*sector);
+    Ok(block
+        .entries()?
+        .into_iter()
+        .filter(|(_, header)| header.is_valid())
+        .map(|(_, header)| header.inum)
+        .collect())
+}
+
+/// Content of a newly created directory.
+pub(super) fn empty_block() -> [u8; SECTOR_SIZE] {
+    let mut sector = Block::empty().0;
+    checksum::seal(&mut sector);
+    sector
+}
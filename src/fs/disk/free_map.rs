@@ -1,132 +1,308 @@
 //! Disk sector free bitmap.
 //!
+//! The bitmap is what is kept on the disk, and its changes are committed with
+//! the transaction making them. Each sector of it holds [`MAP_BYTES`] bytes of
+//! the bitmap, followed by their checksum. In memory, free sectors are also indexed as
+//! extents, by start and by length, which is what allocation searches.
+//!
+//! Sectors freed by a transaction aren't reused until it's committed. Until
+//! then, the disk still has metadata pointing to them, and file content,
+//! which isn't journaled, written to them would corrupt their old owner if
+//! the transaction was lost in a crash.
+//!
+//! What the open transaction allocates and frees is recorded as well, so it
+//! can be undone if the transaction is aborted.
 use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, BTreeSet};
 use alloc::vec;
+use alloc::vec::Vec;
+use core::{cmp, mem};
 
+use super::checksum::{self, CHECKSUM_OFF};
 use super::inode::Inode;
+use super::super_block::{SuperBlock, SUPER_BLOCK_SECTOR};
 use super::{Inum, FREE_MAP_SECTOR, ROOT_DIR_SECTOR};
-use crate::fs::Vnode;
+use crate::device::virtio::SECTOR_SIZE;
+use crate::fs::{FileType, Vnode};
 use crate::{OsError, Result};
 
+/// Bytes of the bitmap in each of its sectors.
+const MAP_BYTES: usize = CHECKSUM_OFF;
+
+/// Length of the free map of a disk with `size` sectors, in bytes of bitmap
+/// and in sectors holding them.
+fn map_len(size: u32) -> (usize, u32) {
+    let bytes = (size as usize + 7) / 8;
+    (bytes, ((bytes + MAP_BYTES - 1) / MAP_BYTES) as u32)
+}
+
 /// Disk sector free bitmap.
 pub(super) struct FreeMap {
     size: u32,
     bits: Box<[u8]>,
+    /// Sectors of the bitmap changed since the last flush.
+    dirty: BTreeSet<usize>,
+    /// Free extents, from start to length.
+    by_start: BTreeMap<Inum, u32>,
+    /// Free extents, as `(length, start)`.
+    by_len: BTreeSet<(u32, Inum)>,
+    /// Extents freed by the open transaction, to be reused after it's committed.
+    released: Vec<(Inum, u32)>,
+    /// Extents allocated by the open transaction.
+    allocated: Vec<(Inum, u32)>,
 }
 
 impl FreeMap {
-    /// Format the disk and return a free map.
-    pub(super) fn new_format(size: u32) -> Result<Self> {
-        let bitmap_len_in_byte = (size as usize + 7) / 8;
+    fn new(size: u32, bits: Box<[u8]>) -> Self {
         let mut free_map = FreeMap {
             size,
-            bits: vec![0; bitmap_len_in_byte].into(),
+            bits,
+            dirty: BTreeSet::new(),
+            by_start: BTreeMap::new(),
+            by_len: BTreeSet::new(),
+            released: Vec::new(),
+            allocated: Vec::new(),
         };
-        free_map.set(FREE_MAP_SECTOR);
-        free_map.set(ROOT_DIR_SECTOR);
-        let start = free_map.alloc(super::bytes_to_sectors(bitmap_len_in_byte))?;
+        free_map.index();
+        free_map
+    }
+
+    /// Format the disk described by `super_block` and return a free map.
+    pub(super) fn new_format(super_block: &SuperBlock) -> Result<Self> {
+        let size = super_block.sectors();
+        let (bitmap_len_in_byte, sectors) = map_len(size);
+        let mut bits = vec![0; bitmap_len_in_byte].into_boxed_slice();
+        let reserved = [SUPER_BLOCK_SECTOR, FREE_MAP_SECTOR, ROOT_DIR_SECTOR];
+        for sector in reserved
+            .iter()
+            .copied()
+            .chain(super_block.journal_start()..size)
+        {
+            bits[sector as usize / 8] |= 1 << (sector % 8);
+        }
+        let mut free_map = Self::new(size, bits);
+        // All of the bitmap is new.
+        free_map.dirty = (0..sectors as usize).collect();
+        let start = free_map.alloc(sectors)?;
 
         #[cfg(feature = "debug")]
-        kprintln!(
-            "Freemap format at sector {}, len={}",
-            start,
-            super::bytes_to_sectors(bitmap_len_in_byte)
-        );
+        kprintln!("Freemap format at sector {}, len={}", start, sectors);
 
-        Inode::create(FREE_MAP_SECTOR, start, bitmap_len_in_byte)?;
+        let len = sectors as usize * SECTOR_SIZE;
+        Inode::create_contiguous(
+            FREE_MAP_SECTOR,
+            start,
+            len,
+            FileType::File,
+            &[0; SECTOR_SIZE],
+        )?;
+        free_map.flush()?;
         Ok(free_map)
     }
 
-    // Load from the disk.
+    /// Load from the disk.
+    ///
+    /// # Errors
+    /// [`OsError::ChecksumMismatch`] if a sector of the bitmap is corrupted.
     pub(super) fn load(size: u32) -> Result<Self> {
         let inode = Inode::open(FREE_MAP_SECTOR)?;
-        let len = inode.len();
-        assert!(len == (size as usize + 7) / 8);
-        let mut free_map = FreeMap {
-            size,
-            bits: vec![0; len].into(),
-        };
-        inode.read_at(&mut free_map.bits, 0)?;
-        Ok(free_map)
+        let (len, sectors) = map_len(size);
+        if inode.len() != sectors as usize * SECTOR_SIZE {
+            return Err(OsError::BadSuperBlock);
+        }
+        let mut bits = vec![0; len].into_boxed_slice();
+        let mut buf = [0; SECTOR_SIZE];
+        for (i, chunk) in bits.chunks_mut(MAP_BYTES).enumerate() {
+            if inode.read_at(&mut buf, i * SECTOR_SIZE)? != SECTOR_SIZE {
+                return Err(OsError::UnexpectedEOF);
+            }
+            checksum::verify(&buf)?;
+            chunk.copy_from_slice(&buf[..chunk.len()]);
+        }
+        Ok(Self::new(size, bits))
     }
 
-    // Flush to the disk.
-    pub(super) fn flush(&self) -> Result<()> {
+    // Flush changed sectors to the disk.
+    pub(super) fn flush(&mut self) -> Result<()> {
+        if self.dirty.is_empty() {
+            return Ok(());
+        }
         let inode = Inode::open(FREE_MAP_SECTOR)?;
-        inode.write_at(&self.bits, 0)?;
+        for i in mem::take(&mut self.dirty) {
+            let off = i * MAP_BYTES;
+            let end = cmp::min(off + MAP_BYTES, self.bits.len());
+            let mut buf = [0; SECTOR_SIZE];
+            buf[..end - off].copy_from_slice(&self.bits[off..end]);
+            checksum::seal(&mut buf);
+            inode.write_at(&buf, i * SECTOR_SIZE)?;
+        }
         Ok(())
     }
 
+    /// Make the sectors freed by the transaction just committed reusable.
+    pub(super) fn settle(&mut self) {
+        for (start, len) in mem::take(&mut self.released) {
+            self.insert(start, len);
+        }
+        self.allocated.clear();
+    }
+
+    /// Undo the allocations and frees of the transaction just aborted.
+    ///
+    /// Returns whether there was anything to undo.
+    pub(super) fn rollback(&mut self) -> bool {
+        let changed = !self.released.is_empty() || !self.allocated.is_empty();
+        for (start, len) in mem::take(&mut self.released) {
+            for sector in start..start + len {
+                self.set(sector);
+            }
+        }
+        // An extent freed after being allocated is allocated again above,
+        // and freed for good here.
+        for (start, len) in mem::take(&mut self.allocated).into_iter().rev() {
+            for sector in start..start + len {
+                self.reset(sector);
+            }
+            self.insert(start, len);
+        }
+        changed
+    }
+
+    /// Number of sectors that can be allocated.
+    pub(super) fn free_cnt(&self) -> u32 {
+        self.by_start.values().sum()
+    }
+
+    /// Length of the largest free extent.
+    pub(super) fn largest_free(&self) -> u32 {
+        self.by_len.iter().next_back().map_or(0, |(len, _)| *len)
+    }
+
     fn get(&self, sector: Inum) -> bool {
         assert!(sector < self.size);
-        self.bits[sector as usize / 8] & (1 << sector % 8) != 0
+        self.bits[sector as usize / 8] & (1 << (sector % 8)) != 0
     }
 
     fn set(&mut self, sector: Inum) {
         assert!(sector < self.size);
-        self.bits[sector as usize / 8] |= 1 << sector % 8;
+        self.bits[sector as usize / 8] |= 1 << (sector % 8);
+        self.dirty.insert(sector as usize / 8 / MAP_BYTES);
     }
 
     fn reset(&mut self, sector: Inum) {
         assert!(sector < self.size);
-        self.bits[sector as usize / 8] &= !(1 << sector % 8);
+        self.bits[sector as usize / 8] &= !(1 << (sector % 8));
+        self.dirty.insert(sector as usize / 8 / MAP_BYTES);
     }
 
-    /// Allocate a contiguous array of sectors with `cnt` length.
-    pub(super) fn alloc(&mut self, cnt: u32) -> Result<Inum> {
-        if cnt == 0 {
-            return Ok(0);
-        }
-        if cnt >= self.size {
-            return Err(OsError::DiskSectorAllocFail);
-        }
-        'outer: for mut i in 0..self.size - cnt {
-            for _ in 0..cnt {
-                if self.get(i) {
-                    // `for` will skip this bit for us.
-                    // Donot need `i += 1`.
-                    continue 'outer;
+    /// Build the extent index from the bitmap.
+    fn index(&mut self) {
+        let mut start = None;
+        for sector in 0..self.size {
+            match (self.get(sector), start) {
+                (false, None) => start = Some(sector),
+                (true, Some(s)) => {
+                    self.insert(s, sector - s);
+                    start = None;
                 }
-                // Skip the tested bits. If this '0' chunk is
-                // unusable (not long enough), any of these sectors
-                // will be unusable and need not to be tested again.
-                i += 1;
+                _ => {}
             }
+        }
+        if let Some(s) = start {
+            self.insert(s, self.size - s);
+        }
+    }
 
-            for j in i - cnt..i {
-                self.set(j);
+    /// Index a free extent, merging it with the ones next to it.
+    fn insert(&mut self, mut start: Inum, mut len: u32) {
+        if let Some((&prev, &prev_len)) = self.by_start.range(..start).next_back() {
+            if prev + prev_len == start {
+                self.remove(prev, prev_len);
+                start = prev;
+                len += prev_len;
             }
-            return Ok(i - cnt);
         }
+        if let Some(&next_len) = self.by_start.get(&(start + len)) {
+            self.remove(start + len, next_len);
+            len += next_len;
+        }
+        self.by_start.insert(start, len);
+        self.by_len.insert((len, start));
+    }
+
+    fn remove(&mut self, start: Inum, len: u32) {
+        self.by_start.remove(&start);
+        self.by_len.remove(&(len, start));
+    }
+
+    /// Take `start..start + cnt` out of the free extent `ext..ext + ext_len`
+    /// holding it.
+    fn take(&mut self, (ext, ext_len): (Inum, u32), start: Inum, cnt: u32) -> Inum {
+        self.remove(ext, ext_len);
+        if start > ext {
+            self.insert(ext, start - ext);
+        }
+        if start + cnt < ext + ext_len {
+            self.insert(start + cnt, ext + ext_len - start - cnt);
+        }
+        for sector in start..start + cnt {
+            self.set(sector);
+        }
+        self.allocated.push((start, cnt));
+        start
+    }
 
-        Err(OsError::DiskSectorAllocFail)
+    /// Allocate a contiguous array of sectors with `cnt` length, from the
+    /// smallest free extent that fits.
+    pub(super) fn alloc(&mut self, cnt: u32) -> Result<Inum> {
+        if cnt == 0 {
+            return Ok(0);
+        }
+        let &(len, start) = self
+            .by_len
+            .range((cnt, 0)..)
+            .next()
+            .ok_or(OsError::DiskSectorAllocFail)?;
+        Ok(self.take((start, len), start, cnt))
     }
 
-    /// Allocate a contiguous array but specify its start sector.
-    pub(super) fn in_place_alloc(&mut self, start: Inum, cnt: u32) -> Result<()> {
+    /// Allocate a contiguous array of sectors with `cnt` length, as close
+    /// after `hint` as possible.
+    ///
+    /// It goes at `hint` if it's free, or else in the first free extent after
+    /// it that fits, and in the smallest one that fits if there isn't one.
+    pub(super) fn alloc_near(&mut self, hint: Inum, cnt: u32) -> Result<Inum> {
         if cnt == 0 {
-            return Ok(());
+            return Ok(0);
         }
-        // Check.
-        for i in start..start + cnt {
-            if self.get(i) {
-                return Err(OsError::DiskSectorAllocFail);
+        if let Some((&ext, &ext_len)) = self.by_start.range(..=hint).next_back() {
+            if hint + cnt <= ext + ext_len {
+                return Ok(self.take((ext, ext_len), hint, cnt));
             }
         }
-        // Check passed.
-        for i in start..start + cnt {
-            self.set(i);
+        let after = self.by_start.range(hint..).find(|(_, len)| **len >= cnt);
+        match after {
+            Some((&ext, &ext_len)) => Ok(self.take((ext, ext_len), ext, cnt)),
+            None => self.alloc(cnt),
         }
-        Ok(())
     }
 
-    /// Deallocate a contiguous array of sectors with ***length <= `cnt`***.
-    pub(super) fn dealloc(&mut self, sector: Inum, cnt: u32) {
+    /// Deallocate a contiguous array of sectors with `cnt` length.
+    ///
+    /// # Errors
+    /// [`OsError::DoubleFree`] if any of the sectors isn't allocated. None of
+    /// them is freed then.
+    pub(super) fn dealloc(&mut self, sector: Inum, cnt: u32) -> Result<()> {
+        let end = sector.checked_add(cnt).filter(|end| *end <= self.size);
+        if end.map_or(true, |end| (sector..end).any(|i| !self.get(i))) {
+            return Err(OsError::DoubleFree);
+        }
         for i in sector..sector + cnt {
-            if !self.get(i) {
-                break;
-            }
             self.reset(i);
         }
+        if cnt > 0 {
+            self.released.push((sector, cnt));
+        }
+        Ok(())
     }
 }
@@ -1,20 +1,54 @@
 //! Disk inode.
 //!
+//! Content sectors of a file are found through its inode. The first
+//! [`DIRECT_CNT`] of them are pointed to by the inode itself, the next ones
+//! by an indirect sector full of pointers, and the rest by a double indirect
+//! sector pointing to indirect ones.
+//!
+//! A null pointer is a hole, which reads as zeros and takes no space on the
+//! disk. Sectors are allocated when written, so files can be sparse.
 use alloc::sync::Arc;
-use core::convert::TryInto;
-use core::ops::{Deref, Drop};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::{DerefMut, Drop, Range};
 use core::{cmp, mem};
 
-use super::{bytes_to_sectors, Inum, DISKFS};
-use crate::device::virtio::{Virtio, SECTOR_SIZE};
-use crate::fs::Vnode;
+use super::checksum;
+use super::free_map::FreeMap;
+use super::journal;
+use super::{bytes_to_sectors, Inum, DISKFS, FREE_MAP_SECTOR};
+use crate::device::virtio::{Virtio, SECTOR_SIZE, SEGMENTS_MAX};
+use crate::fs::lock::FileLocks;
+use crate::fs::{FileType, Stat, Vnode};
 use crate::mem::{Translate, PG_MASK, PG_SIZE};
+use crate::sbi::timer::time_ms;
 use crate::sync::Mutex;
 use crate::{OsError, Result};
 
-const INODE_PADDING: usize = SECTOR_SIZE - core::mem::size_of::<DiskInodeInner>();
+const INODE_PADDING: usize = checksum::CHECKSUM_OFF - core::mem::size_of::<DiskInodeInner>();
 const INODE_MAGIC: u32 = 0x494e4f44;
 
+/// Number of content sectors pointed to by the inode itself.
+const DIRECT_CNT: usize = 112;
+/// Number of pointers in an index sector.
+const PTRS_PER_SECTOR: usize = SECTOR_SIZE / mem::size_of::<Inum>();
+/// Maximum number of content sectors of a file.
+const SECTORS_MAX: usize = DIRECT_CNT + PTRS_PER_SECTOR + PTRS_PER_SECTOR * PTRS_PER_SECTOR;
+/// Maximum number of sectors a write allocates, longer writes are cut short.
+///
+/// This bounds the index sectors a write changes, which must fit in
+/// a transaction along with the free map.
+const ALLOC_MAX: usize = 16 * PTRS_PER_SECTOR;
+/// Maximum number of sectors transferred in one request. A buffer this long
+/// spans at most [`SEGMENTS_MAX`] pages, so it can be split at pages.
+const RUN_MAX: usize = (SEGMENTS_MAX - 1) * PG_SIZE / SECTOR_SIZE;
+/// Access times are kept no more often than this, in ms, unless the file was
+/// modified since the last access.
+const ATIME_PERIOD: u64 = 24 * 60 * 60 * 1000;
+
+/// Content of an index sector.
+type Index = [Inum; PTRS_PER_SECTOR];
+
 /// An inode on the disk.
 ///
 /// Size of this must be `SECTOR_SIZE`.
@@ -22,6 +56,8 @@ const INODE_MAGIC: u32 = 0x494e4f44;
 struct DiskInode {
     inner: DiskInodeInner,
     padding: [u8; INODE_PADDING],
+    /// See [`checksum`].
+    checksum: u32,
 }
 
 /// Metadata of on disk inode.
@@ -30,11 +66,158 @@ struct DiskInode {
 #[repr(C)]
 #[derive(Debug)]
 struct DiskInodeInner {
-    /// Start sector.
-    start: Inum,
     /// Length in bytes.
     len: u32,
     magic: u32,
+    /// [`FileType`] of the file.
+    kind: u16,
+    /// Permission bits.
+    mode: u16,
+    /// Link count.
+    nlink: u32,
+    /// Access, modification and creation time, in ms since boot.
+    atime: u64,
+    mtime: u64,
+    ctime: u64,
+    /// Number of sectors taken, including index sectors.
+    blocks: u32,
+    /// Content sectors, `0` for holes.
+    direct: [Inum; DIRECT_CNT],
+    /// Index sector pointing to the content sectors after the direct ones.
+    indirect: Inum,
+    /// Index sector pointing to index sectors, for the rest of the content.
+    double_indirect: Inum,
+}
+
+impl DiskInode {
+    fn new(kind: u16, mode: u16, nlink: u32, now: u64) -> Self {
+        Self {
+            inner: DiskInodeInner {
+                len: 0,
+                magic: INODE_MAGIC,
+                kind,
+                mode,
+                nlink,
+                atime: now,
+                mtime: now,
+                ctime: now,
+                blocks: 0,
+                direct: [0; DIRECT_CNT],
+                indirect: 0,
+                double_indirect: 0,
+            },
+            padding: [0; INODE_PADDING],
+            checksum: 0,
+        }
+    }
+
+    /// Store the checksum, and return the sector to write.
+    fn seal(&mut self) -> &[u8; SECTOR_SIZE] {
+        let sector = unsafe { mem::transmute::<&mut DiskInode, &mut [u8; SECTOR_SIZE]>(self) };
+        checksum::seal(sector);
+        sector
+    }
+}
+
+/// Where the pointer to a content sector is kept.
+enum Slot {
+    Direct(usize),
+    Indirect(usize),
+    /// In the double indirect sector, then in the indirect sector it points to.
+    DoubleIndirect(usize, usize),
+}
+
+impl Slot {
+    /// Slot of the `idx`th content sector.
+    ///
+    /// # Errors
+    /// [`OsError::FileTooLarge`] if a file can't have that many sectors.
+    fn of(idx: usize) -> Result<Self> {
+        if idx < DIRECT_CNT {
+            return Ok(Self::Direct(idx));
+        }
+        let idx = idx - DIRECT_CNT;
+        if idx < PTRS_PER_SECTOR {
+            return Ok(Self::Indirect(idx));
+        }
+        let idx = idx - PTRS_PER_SECTOR;
+        if idx < PTRS_PER_SECTOR * PTRS_PER_SECTOR {
+            return Ok(Self::DoubleIndirect(
+                idx / PTRS_PER_SECTOR,
+                idx % PTRS_PER_SECTOR,
+            ));
+        }
+        Err(OsError::FileTooLarge)
+    }
+}
+
+/// Read an index sector. A null one is all holes.
+fn read_index(sector: Inum) -> Result<Index> {
+    let mut index = [0; PTRS_PER_SECTOR];
+    if sector != 0 {
+        journal::read_sector(sector, unsafe {
+            mem::transmute::<&mut Index, &mut [u8; SECTOR_SIZE]>(&mut index)
+        })?;
+    }
+    Ok(index)
+}
+
+/// Index sectors are metadata, and always written through the journal.
+fn write_index(sector: Inum, index: &Index) -> Result<()> {
+    journal::write_sector(sector, unsafe {
+        mem::transmute::<&Index, &[u8; SECTOR_SIZE]>(index)
+    })
+}
+
+/// Free a metadata sector, dropping its write held by the transaction.
+fn free_meta(sector: Inum, freemap: &mut FreeMap) -> Result<()> {
+    freemap.dealloc(sector, 1)?;
+    journal::discard(sector);
+    Ok(())
+}
+
+/// Number of sectors at the start of `sectors` that follow each other on the
+/// disk, up to `max`, which can be transferred in one request.
+fn run_len(sectors: &[Inum], max: usize) -> usize {
+    let max = cmp::min(max, RUN_MAX);
+    1 + sectors
+        .windows(2)
+        .take(max - 1)
+        .take_while(|pair| pair[1] == pair[0] + 1)
+        .count()
+}
+
+/// Length of the part of a buffer at `addr`, of `len` bytes, before the next
+/// page boundary.
+fn in_page(addr: usize, len: usize) -> usize {
+    cmp::min(PG_SIZE - (addr & PG_MASK), len)
+}
+
+/// Split `buf`, which may be in user space, at page boundaries and translate
+/// the pieces into the kernel buffers virtio transfers to.
+///
+/// # Errors
+/// [`OsError::BadPtr`] if a page of it isn't mapped.
+fn segments_mut(mut buf: &mut [u8]) -> Result<Vec<&mut [u8]>> {
+    let mut segments = Vec::new();
+    while !buf.is_empty() {
+        let len = in_page(buf.as_ptr() as usize, buf.len());
+        let (segment, rest) = mem::take(&mut buf).split_at_mut(len);
+        segments.push(segment.translate().ok_or(OsError::BadPtr)?);
+        buf = rest;
+    }
+    Ok(segments)
+}
+
+/// See [`segments_mut()`].
+fn segments(mut buf: &[u8]) -> Result<Vec<&[u8]>> {
+    let mut segments = Vec::new();
+    while !buf.is_empty() {
+        let (segment, rest) = buf.split_at(in_page(buf.as_ptr() as usize, buf.len()));
+        segments.push(segment.translate().ok_or(OsError::BadPtr)?);
+        buf = rest;
+    }
+    Ok(segments)
 }
 
 /// In memory inode descriptor.
@@ -43,28 +226,67 @@ struct DiskInodeInner {
 struct InodeDesc {
     /// Sector number. Inumber interchangeably.
     sector: Inum,
+    /// Type of the file, checked when the inode is read.
+    kind: FileType,
     /// Whether to remove this inode on drop.
     removed: bool,
-    /// Allocated-bytes - file-len.
-    /// Used for lazy shrink.
-    shrink_len: u32,
     /// Deny write to a running file.
     deny_write: u32,
+    /// Whether the content is metadata, which is written through the journal.
+    journaled: bool,
+    /// Whether access and modification times are kept. The free map doesn't
+    /// keep them, since writing its inode back is part of committing, and
+    /// nothing does on a read-only disk.
+    timed: bool,
+    /// Whether the times in memory are newer than those on the disk.
+    dirty: bool,
 }
 
 impl InodeDesc {
-    fn new(sector: Inum, shrink_len: u32) -> Self {
+    fn new(sector: Inum, kind: FileType) -> Self {
         Self {
             sector,
+            kind,
             removed: false,
             deny_write: 0,
-            shrink_len,
+            journaled: kind == FileType::Dir
+                || kind == FileType::Symlink
+                || sector == FREE_MAP_SECTOR,
+            timed: sector != FREE_MAP_SECTOR && !Virtio::get().lock().read_only(),
+            dirty: false,
         }
     }
+
+    /// Read a content sector.
+    fn read_sector(&self, sector: Inum, buf: &mut [u8; SECTOR_SIZE]) -> Result<()> {
+        if self.journaled {
+            journal::read_sector(sector, buf)
+        } else {
+            Virtio::read_sector(sector as _, buf)
+        }
+    }
+
+    /// Write a content sector.
+    fn write_sector(&self, sector: Inum, buf: &[u8; SECTOR_SIZE]) -> Result<()> {
+        if self.journaled {
+            journal::write_sector(sector, buf)
+        } else {
+            Virtio::write_sector(sector as _, buf)
+        }
+    }
+
+    /// Free a content sector.
+    fn free_sector(&self, sector: Inum, freemap: &mut FreeMap) -> Result<()> {
+        freemap.dealloc(sector, 1)?;
+        if self.journaled {
+            journal::discard(sector);
+        }
+        Ok(())
+    }
 }
 
-/// Wrapper of in memory inode.
-pub struct Inode(Mutex<(InodeDesc, DiskInode)>);
+/// Wrapper of in memory inode, with the advisory locks on it.
+pub struct Inode(Mutex<(InodeDesc, DiskInode)>, FileLocks);
 
 impl Inode {
     /// Tag to remove the inode on drop.
@@ -72,33 +294,81 @@ impl Inode {
         self.0.lock().0.removed = true;
     }
 
-    /// Create an inode at `sector` with length of `len`.
+    pub fn kind(&self) -> FileType {
+        self.0.lock().0.kind
+    }
+
+    /// Count a new name of the inode.
+    pub fn link(&self) -> Result<()> {
+        let (desc, data) = &mut *self.0.lock();
+        data.inner.nlink += 1;
+        Self::flush(desc, data)
+    }
+
+    /// Forget a name of the inode. Once the last one is gone,
+    /// the inode is removed when dropped.
     ///
-    /// `sector` must be a sector allocated from free map. Also, the content must be
-    /// pre allocated from free map. This will not do any sector allocation.
-    pub fn create(sector: Inum, start: Inum, len: usize) -> Result<Arc<Self>> {
-        // Create file on the disk.
-        let sector_num = bytes_to_sectors(len);
-        let disk_inode = DiskInode {
-            inner: DiskInodeInner {
-                start: start as _,
-                len: len as _,
-                magic: INODE_MAGIC,
-            },
-            padding: [0; INODE_PADDING],
-        };
-        unsafe {
-            Virtio::write_sector(sector as _, mem::transmute(&disk_inode));
+    /// # Errors
+    /// [`OsError::UnknownFormat`] if the inode has no name to forget, which
+    /// only a corrupted inode has.
+    pub fn unlink(&self) -> Result<()> {
+        let (desc, data) = &mut *self.0.lock();
+        if data.inner.nlink == 0 {
+            return Err(OsError::UnknownFormat);
         }
+        data.inner.nlink -= 1;
+        if data.inner.nlink == 0 {
+            desc.removed = true;
+        }
+        Self::flush(desc, data)
+    }
 
-        // Zero the file.
-        let zeros = [0; SECTOR_SIZE];
-        for i in 0..sector_num {
-            Virtio::write_sector((start + i) as _, &zeros);
+    /// Create an empty inode of `kind` at `sector`.
+    ///
+    /// `sector` must be a sector allocated from free map. Content sectors are
+    /// allocated as it's written.
+    pub fn create(sector: Inum, kind: FileType) -> Result<Arc<Self>> {
+        let disk_inode = DiskInode::new(kind as u16, kind.default_mode(), 1, time_ms() as u64);
+        Self::install(sector, kind, disk_inode)
+    }
+
+    /// Create an inode of `kind` at `sector` with length of `len`, whose
+    /// content is the sectors from `start`, each filled with `fill`.
+    ///
+    /// This is how the free map and the root dir are made when formatting, before
+    /// sectors can be allocated on demand. The content must be pre allocated from
+    /// free map, and fit in the direct pointers.
+    pub fn create_contiguous(
+        sector: Inum,
+        start: Inum,
+        len: usize,
+        kind: FileType,
+        fill: &[u8; SECTOR_SIZE],
+    ) -> Result<Arc<Self>> {
+        let cnt = bytes_to_sectors(len);
+        if cnt as usize > DIRECT_CNT {
+            return Err(OsError::FileTooLarge);
         }
 
-        let desc = InodeDesc::new(sector, 0);
-        Ok(Arc::from(Self(Mutex::new((desc, disk_inode)))))
+        let mut disk_inode = DiskInode::new(kind as u16, kind.default_mode(), 1, time_ms() as u64);
+        disk_inode.inner.len = len as u32;
+        disk_inode.inner.blocks = cnt;
+        for i in 0..cnt {
+            disk_inode.inner.direct[i as usize] = start + i;
+            Virtio::write_sector((start + i) as _, fill)?;
+        }
+        Self::install(sector, kind, disk_inode)
+    }
+
+    /// Write a new inode record to the disk, and keep it in memory.
+    fn install(sector: Inum, kind: FileType, mut disk_inode: DiskInode) -> Result<Arc<Self>> {
+        journal::write_sector(sector, disk_inode.seal())?;
+
+        let desc = InodeDesc::new(sector, kind);
+        Ok(Arc::from(Self(
+            Mutex::new((desc, disk_inode)),
+            FileLocks::default(),
+        )))
     }
 
     /// Open the inode at `sector`.
@@ -106,88 +376,463 @@ impl Inode {
     /// # Return
     /// - `Ok(Arc<Inode>)`: successfully opened the inode.
     /// - `Err(InvalidInode)`: failed, specifically, the inode magic is incorrect.
+    /// - `Err(ChecksumMismatch)`: the inode is corrupted.
+    /// - `Err(UnknownFormat)`: the inode has an unknown type.
     pub fn open(sector: Inum) -> Result<Arc<Self>> {
-        let desc = InodeDesc::new(sector, 0);
-        let mut data = DiskInode {
-            inner: DiskInodeInner {
-                start: 0,
-                len: 0,
-                magic: 0,
-            },
-            padding: [0; INODE_PADDING],
+        let (kind, data) = Self::read_record(sector)?;
+        let desc = InodeDesc::new(sector, kind);
+        Ok(Arc::from(Self(
+            Mutex::new((desc, data)),
+            FileLocks::default(),
+        )))
+    }
+
+    /// Read the inode record from the disk again, dropping changes in memory.
+    pub(super) fn reload(&self) -> Result<()> {
+        let (desc, data) = &mut *self.0.lock();
+        *data = Self::read_record(desc.sector)?.1;
+        desc.dirty = false;
+        Ok(())
+    }
+
+    /// Read the inode record at `sector`, checking its magic, checksum and type.
+    fn read_record(sector: Inum) -> Result<(FileType, DiskInode)> {
+        let mut data = DiskInode::new(0, 0, 0, 0);
+        journal::read_sector(sector, unsafe {
+            mem::transmute::<&mut DiskInode, &mut [u8; SECTOR_SIZE]>(&mut data)
+        })?;
+
+        if data.inner.magic != INODE_MAGIC {
+            return Err(OsError::OpenInvalidInode);
+        }
+        checksum::verify(unsafe { mem::transmute::<&DiskInode, &[u8; SECTOR_SIZE]>(&data) })?;
+        let kind = FileType::from_raw(data.inner.kind).ok_or(OsError::UnknownFormat)?;
+        Ok((kind, data))
+    }
+
+    /// Content sectors of the file, `0` for holes.
+    pub fn content_sectors(&self) -> Result<Vec<Inum>> {
+        let inner = &self.0.lock().1.inner;
+        Self::sectors(inner, 0..bytes_to_sectors(inner.len as usize) as usize)
+    }
+
+    /// Write the inode record to the disk.
+    fn flush(desc: &mut InodeDesc, data: &mut DiskInode) -> Result<()> {
+        desc.dirty = false;
+        journal::write_sector(desc.sector, data.seal())
+    }
+
+    /// Content sectors in `range`, `0` for holes.
+    ///
+    /// Each index sector on the way is read once.
+    fn sectors(inner: &DiskInodeInner, range: Range<usize>) -> Result<Vec<Inum>> {
+        let mut indirect = None;
+        let mut double_indirect = None;
+        let mut mid: Option<(usize, Index)> = None;
+        range
+            .map(|idx| match Slot::of(idx) {
+                Ok(Slot::Direct(i)) => Ok(inner.direct[i]),
+                Ok(Slot::Indirect(i)) => {
+                    let index = match indirect {
+                        Some(ref index) => index,
+                        None => indirect.insert(read_index(inner.indirect)?),
+                    };
+                    Ok(index[i])
+                }
+                Ok(Slot::DoubleIndirect(i, j)) => {
+                    let outer = match double_indirect {
+                        Some(ref outer) => outer,
+                        None => double_indirect.insert(read_index(inner.double_indirect)?),
+                    };
+                    match mid {
+                        Some((k, ref index)) if k == i => Ok(index[j]),
+                        _ => {
+                            let index = read_index(outer[i])?;
+                            mid = Some((i, index));
+                            Ok(index[j])
+                        }
+                    }
+                }
+                Err(_) => Ok(0),
+            })
+            .collect()
+    }
+
+    /// Whether writing `cnt` bytes at `off`, or at the end if `off` is `None`,
+    /// extends the file or fills holes.
+    fn needs_alloc(inner: &DiskInodeInner, off: Option<usize>, cnt: usize) -> Result<bool> {
+        let at = off.unwrap_or(inner.len as usize);
+        let end = at.saturating_add(cnt);
+        Ok((inner.len as usize) < end
+            || Self::sectors(inner, at / SECTOR_SIZE..bytes_to_sectors(end) as usize)?.contains(&0))
+    }
+
+    /// Allocate the `idx`th content sector, which is a hole, along with the
+    /// index sectors leading to it, as close after `hint` as the free map
+    /// allows. The new sector isn't initialized.
+    fn alloc_sector(
+        inner: &mut DiskInodeInner,
+        idx: usize,
+        hint: Inum,
+        freemap: &mut FreeMap,
+    ) -> Result<Inum> {
+        let blocks = &mut inner.blocks;
+        match Slot::of(idx)? {
+            Slot::Direct(i) => Self::alloc_ptr(&mut inner.direct[i], false, hint, blocks, freemap),
+            Slot::Indirect(i) => {
+                let index = Self::alloc_ptr(&mut inner.indirect, true, hint, blocks, freemap)?;
+                Self::alloc_in(index, i, false, hint, blocks, freemap)
+            }
+            Slot::DoubleIndirect(i, j) => {
+                let outer =
+                    Self::alloc_ptr(&mut inner.double_indirect, true, hint, blocks, freemap)?;
+                let index = Self::alloc_in(outer, i, true, hint, blocks, freemap)?;
+                Self::alloc_in(index, j, false, hint, blocks, freemap)
+            }
+        }
+    }
+
+    /// The sector `ptr` points to, allocating one near `hint` if it's null.
+    /// New index sectors are all holes.
+    fn alloc_ptr(
+        ptr: &mut Inum,
+        is_index: bool,
+        hint: Inum,
+        blocks: &mut u32,
+        freemap: &mut FreeMap,
+    ) -> Result<Inum> {
+        if *ptr == 0 {
+            let sector = freemap.alloc_near(hint, 1)?;
+            if is_index {
+                if let Err(e) = write_index(sector, &[0; PTRS_PER_SECTOR]) {
+                    free_meta(sector, freemap)?;
+                    return Err(e);
+                }
+            }
+            *ptr = sector;
+            *blocks += 1;
+        }
+        Ok(*ptr)
+    }
+
+    /// Like [`Self::alloc_ptr()`], for the `i`th pointer in index sector `index`.
+    fn alloc_in(
+        index: Inum,
+        i: usize,
+        is_index: bool,
+        hint: Inum,
+        blocks: &mut u32,
+        freemap: &mut FreeMap,
+    ) -> Result<Inum> {
+        let mut ptrs = read_index(index)?;
+        if ptrs[i] == 0 {
+            let sector = Self::alloc_ptr(&mut ptrs[i], is_index, hint, blocks, freemap)?;
+            if let Err(e) = write_index(index, &ptrs) {
+                free_meta(sector, freemap)?;
+                *blocks -= 1;
+                return Err(e);
+            }
+        }
+        Ok(ptrs[i])
+    }
+
+    /// Allocate the holes in `sectors`, content sectors from the `first`th on,
+    /// marking new ones in `fresh`. Each goes after the sector before it, or
+    /// after the inode at `near` for the first one of the file.
+    ///
+    /// Returns the number of leading sectors that can be written, which falls
+    /// short once the disk is full or [`ALLOC_MAX`] sectors are allocated.
+    ///
+    /// # Errors
+    /// Why the first sector can't be allocated, if it can't.
+    fn fill_holes(
+        inner: &mut DiskInodeInner,
+        near: Inum,
+        first: usize,
+        sectors: &mut [Inum],
+        fresh: &mut [bool],
+    ) -> Result<usize> {
+        let mut prev = match first.checked_sub(1) {
+            Some(idx) => Self::sectors(inner, idx..first)?[0],
+            None => 0,
         };
-        unsafe {
-            Virtio::read_sector(sector as _, mem::transmute(&mut data));
+        let mut freemap = DISKFS.free_map.lock();
+        let mut allocated = 0;
+        for (i, sector) in sectors.iter_mut().enumerate() {
+            if *sector != 0 {
+                prev = *sector;
+                continue;
+            }
+            if allocated == ALLOC_MAX {
+                return Ok(i);
+            }
+            let hint = if prev != 0 { prev + 1 } else { near + 1 };
+            match Self::alloc_sector(inner, first + i, hint, &mut freemap) {
+                Ok(new) => {
+                    prev = new;
+                    *sector = new;
+                    fresh[i] = true;
+                    allocated += 1;
+                }
+                Err(e) if i == 0 => return Err(e),
+                Err(_) => return Ok(i),
+            }
         }
+        Ok(sectors.len())
+    }
 
-        if data.inner.magic != INODE_MAGIC {
-            Err(OsError::OpenInvalidInode)
-        } else {
-            Ok(Arc::from(Self(Mutex::new((desc, data)))))
+    /// Free the content sectors in `range`, leaving holes. Index sectors left
+    /// pointing to nothing are freed as well.
+    fn free_sectors(
+        desc: &InodeDesc,
+        inner: &mut DiskInodeInner,
+        range: Range<usize>,
+        freemap: &mut FreeMap,
+    ) -> Result<()> {
+        let mut freed = 0;
+        let direct = cmp::min(range.start, DIRECT_CNT)..cmp::min(range.end, DIRECT_CNT);
+        for ptr in inner.direct[direct].iter_mut().filter(|ptr| **ptr != 0) {
+            desc.free_sector(*ptr, freemap)?;
+            *ptr = 0;
+            freed += 1;
         }
+
+        let shift = |range: &Range<usize>, cnt| {
+            range.start.saturating_sub(cnt)..range.end.saturating_sub(cnt)
+        };
+        let range = shift(&range, DIRECT_CNT);
+        freed += Self::free_under(desc, &mut inner.indirect, range.clone(), 1, freemap)?;
+        let range = shift(&range, PTRS_PER_SECTOR);
+        freed += Self::free_under(desc, &mut inner.double_indirect, range, 2, freemap)?;
+
+        inner.blocks -= freed;
+        Ok(())
     }
 
-    fn resize_inner(desc: &mut InodeDesc, data: &mut DiskInode, size: usize) -> Result<()> {
-        let newlen = size as u32;
-        let flush_len = |a: &InodeDesc, b: &mut DiskInode| {
-            b.inner.len = newlen;
-            unsafe {
-                Virtio::write_sector(a.sector as _, &mem::transmute_copy(b));
+    /// Free the content sectors in `range` under index sector `index`, which
+    /// has `depth` levels of index sectors, `1` for an indirect sector.
+    ///
+    /// Returns the number of sectors freed.
+    fn free_under(
+        desc: &InodeDesc,
+        index: &mut Inum,
+        range: Range<usize>,
+        depth: u32,
+        freemap: &mut FreeMap,
+    ) -> Result<u32> {
+        if *index == 0 || range.is_empty() {
+            return Ok(0);
+        }
+
+        // Content sectors under each pointer.
+        let span = PTRS_PER_SECTOR.pow(depth - 1);
+        let old = read_index(*index)?;
+        let mut ptrs = old;
+        let mut freed = 0;
+        for (i, ptr) in ptrs.iter_mut().enumerate() {
+            let first = i * span;
+            let sub =
+                range.start.saturating_sub(first)..cmp::min(range.end.saturating_sub(first), span);
+            if *ptr == 0 || sub.is_empty() {
+                continue;
             }
+            if depth == 1 {
+                desc.free_sector(*ptr, freemap)?;
+                *ptr = 0;
+                freed += 1;
+            } else {
+                freed += Self::free_under(desc, ptr, sub, depth - 1, freemap)?;
+            }
+        }
+
+        if ptrs.iter().all(|ptr| *ptr == 0) {
+            free_meta(*index, freemap)?;
+            *index = 0;
+            freed += 1;
+        } else if ptrs != old {
+            write_index(*index, &ptrs)?;
+        }
+        Ok(freed)
+    }
+
+    /// Zero `range` of the `idx`th content sector, unless it's a hole.
+    fn zero_partial(
+        desc: &InodeDesc,
+        inner: &DiskInodeInner,
+        idx: usize,
+        range: Range<usize>,
+    ) -> Result<()> {
+        let sector = Self::sectors(inner, idx..idx + 1)?[0];
+        if sector == 0 || range.is_empty() {
+            return Ok(());
+        }
+        let mut bounce = [0; SECTOR_SIZE];
+        desc.read_sector(sector, &mut bounce)?;
+        bounce[range].fill(0);
+        desc.write_sector(sector, &bounce)
+    }
+
+    /// Write `buf` at `off`, or at the end of the file if `off` is `None`,
+    /// returning where it was written and the bytes written.
+    ///
+    /// Holes written to are allocated. The write is cut short if the disk
+    /// fills up.
+    fn write_inner(&self, buf: &[u8], off: Option<usize>) -> Result<(usize, usize)> {
+        if self.0.lock().0.deny_write > 0 {
+            return Err(OsError::InvalidFileMode);
+        }
+
+        // Extending and allocating change metadata, which must be done in a
+        // transaction. The transaction should be begun before the inode lock
+        // is taken, and ended after it's released.
+        let mut tx = None;
+        // We must acquire lock during the whole process
+        // to avoid being resized by other threads.
+        let mut guard = self.0.lock();
+        while tx.is_none() && Self::needs_alloc(&guard.1.inner, off, buf.len())? {
+            drop(guard);
+            tx = Some(journal::begin());
+            guard = self.0.lock();
+        }
+        let (desc, data) = &mut *guard;
+
+        // Appends go to the end as it is now, with the inode locked till
+        // they are done, so they are atomic.
+        let written_at = off.unwrap_or(data.inner.len as usize);
+        let end = written_at
+            .checked_add(buf.len())
+            .filter(|end| *end <= SECTORS_MAX * SECTOR_SIZE)
+            .ok_or(OsError::FileTooLarge)?;
+
+        let first = written_at / SECTOR_SIZE;
+        let mut sectors = Self::sectors(&data.inner, first..bytes_to_sectors(end) as usize)?;
+        let mut fresh = vec![false; sectors.len()];
+        let writable = if tx.is_some() {
+            Self::fill_holes(
+                &mut data.inner,
+                desc.sector,
+                first,
+                &mut sectors,
+                &mut fresh,
+            )?
+        } else {
+            sectors.len()
         };
+        let end = cmp::min(end, (first + writable) * SECTOR_SIZE);
 
-        let mut freemap = DISKFS.free_map.lock();
-        if newlen == data.inner.len {
-            Ok(())
-        }
-        // Lazy shrink.
-        else if newlen < data.inner.len {
-            let shrink = data.inner.len - newlen;
-            // The new len is immediately flush to disk.
-            // So if the new len is still shorter,
-            // we confirm a shorter length than the last
-            // newest length (may be shrunk by other threads).
-            // So the shrink_len can be updated by adding.
-            desc.shrink_len += shrink;
-            // Immediately flush to disk.
-            flush_len(desc, data);
-            Ok(())
-        }
-        // Extend.
-        else {
-            // Try to alloc in-place.
-            let oldlen = data.inner.len + desc.shrink_len;
-            if newlen <= oldlen {
-                // No need to re alloc, but should flush new len to disk.
-                desc.shrink_len = oldlen - newlen;
-                flush_len(desc, data);
-                Ok(())
+        let mut off = written_at;
+        while off < end {
+            let idx = off / SECTOR_SIZE - first;
+            let sector = sectors[idx];
+            let sector_offset = off % SECTOR_SIZE;
+            let chunk_size = cmp::min(SECTOR_SIZE - sector_offset, end - off);
+            let bytes_written = off - written_at;
+
+            if !desc.journaled && chunk_size == SECTOR_SIZE {
+                // Whole sectors following each other on the disk are written
+                // in one request, straight from the buffer.
+                let len = run_len(&sectors[idx..], (end - off) / SECTOR_SIZE) * SECTOR_SIZE;
+                let segments = segments(&buf[bytes_written..bytes_written + len])?;
+                Virtio::write_sectors(sector as _, &segments)?;
+                off += len;
+                continue;
             } else {
-                let cnt = bytes_to_sectors(newlen as _) - bytes_to_sectors(oldlen as _);
-                let start = data.inner.start + bytes_to_sectors(oldlen as _);
-                // Try in-place extend.
-                if freemap.in_place_alloc(start, cnt).is_ok() {
-                    desc.shrink_len = 0;
-                    flush_len(desc, data);
-                    return Ok(());
-                } else {
-                    let cnt = bytes_to_sectors(newlen as _);
-                    let old_start = data.inner.start;
-                    let new_start = freemap.alloc(cnt)?;
-                    // Copy.
-                    let mut bounce = [0u8; SECTOR_SIZE];
-                    for i in 0..bytes_to_sectors(oldlen as _) {
-                        Virtio::read_sector((old_start + i) as _, &mut bounce);
-                        Virtio::write_sector((new_start + i) as _, &bounce);
-                    }
-                    freemap.dealloc(old_start, bytes_to_sectors(oldlen as _));
-                    desc.shrink_len = 0;
-                    data.inner.start = new_start;
-                    flush_len(desc, data);
-                    Ok(())
+                // We need a bounce buffer, preserving old bytes which should not be written.
+                // A newly allocated sector has garbage instead, which is zeroed.
+                let mut bounce = [0; SECTOR_SIZE];
+                if !fresh[idx] {
+                    desc.read_sector(sector as _, &mut bounce)?;
                 }
+                bounce[sector_offset..sector_offset + chunk_size]
+                    .copy_from_slice(&buf[bytes_written..bytes_written + chunk_size]);
+                desc.write_sector(sector as _, &bounce)?;
             }
+
+            off += chunk_size;
         }
+
+        if (data.inner.len as usize) < end {
+            data.inner.len = end as u32;
+        }
+        if desc.timed {
+            data.inner.mtime = time_ms() as u64;
+            desc.dirty = true;
+        }
+        if tx.is_some() {
+            Self::flush(desc, data)?;
+        }
+
+        // Release the inode before committing.
+        drop(guard);
+        if let Some(tx) = tx {
+            tx.commit()?;
+        }
+
+        Ok((written_at, end - written_at))
+    }
+
+    fn resize_inner(desc: &mut InodeDesc, data: &mut DiskInode, size: usize) -> Result<()> {
+        if size > SECTORS_MAX * SECTOR_SIZE {
+            return Err(OsError::FileTooLarge);
+        }
+
+        // Growing leaves a hole. Shrinking frees the sectors past the new end,
+        // and zeros the rest of the last one, so the hole left if it grows
+        // again reads as zeros.
+        if size < data.inner.len as usize {
+            let kept = bytes_to_sectors(size) as usize;
+            let mut freemap = DISKFS.free_map.lock();
+            Self::free_sectors(desc, &mut data.inner, kept..usize::MAX, &mut freemap)?;
+            drop(freemap);
+            if size % SECTOR_SIZE != 0 {
+                let tail = size % SECTOR_SIZE..SECTOR_SIZE;
+                Self::zero_partial(desc, &data.inner, size / SECTOR_SIZE, tail)?;
+            }
+        }
+
+        data.inner.len = size as u32;
+        data.inner.mtime = time_ms() as u64;
+        Self::flush(desc, data)
+    }
+
+    fn punch_hole_inner(
+        desc: &mut InodeDesc,
+        data: &mut DiskInode,
+        off: usize,
+        len: usize,
+    ) -> Result<()> {
+        if desc.deny_write > 0 {
+            return Err(OsError::InvalidFileMode);
+        }
+
+        let size = data.inner.len as usize;
+        let end = cmp::min(off.saturating_add(len), size);
+        if off >= end {
+            return Ok(());
+        }
+        // Past the end of the file, the last sector is zeros already.
+        let end = if end == size {
+            bytes_to_sectors(end) as usize * SECTOR_SIZE
+        } else {
+            end
+        };
+
+        let (head, tail) = (off / SECTOR_SIZE, end / SECTOR_SIZE);
+        if off % SECTOR_SIZE != 0 {
+            let head_end = cmp::min(SECTOR_SIZE, end - head * SECTOR_SIZE);
+            Self::zero_partial(desc, &data.inner, head, off % SECTOR_SIZE..head_end)?;
+        }
+        if end % SECTOR_SIZE != 0 && (off % SECTOR_SIZE == 0 || tail > head) {
+            Self::zero_partial(desc, &data.inner, tail, 0..end % SECTOR_SIZE)?;
+        }
+        let whole = bytes_to_sectors(off) as usize..tail;
+        if !whole.is_empty() {
+            let mut freemap = DISKFS.free_map.lock();
+            Self::free_sectors(desc, &mut data.inner, whole, &mut freemap)?;
+        }
+
+        data.inner.mtime = time_ms() as u64;
+        Self::flush(desc, data)
     }
 }
 
@@ -200,152 +845,131 @@ impl Vnode for Inode {
         self.0.lock().1.inner.len as _
     }
 
-    fn read_at(&self, buf: &mut [u8], mut off: usize) -> Result<usize> {
-        let mut bytes_read = 0;
-        let mut buf_left = buf.len(); // Bytes left in `buf`.
+    fn stat(&self) -> Stat {
+        let (desc, data) = &*self.0.lock();
+        Stat {
+            inum: desc.sector as usize,
+            kind: desc.kind,
+            mode: data.inner.mode,
+            nlink: data.inner.nlink,
+            size: data.inner.len as usize,
+            blocks: data.inner.blocks as usize,
+            atime: data.inner.atime,
+            mtime: data.inner.mtime,
+            ctime: data.inner.ctime,
+        }
+    }
 
+    fn read_at(&self, buf: &mut [u8], off: usize) -> Result<usize> {
         // We must acquire lock during the whole process
         // to avoid being resized by other threads.
-        let guard = self.0.lock();
-        let (_, data) = &*guard;
+        let mut guard = self.0.lock();
+        let (desc, data) = &mut *guard;
 
-        let start = data.inner.start;
-        let len = data.inner.len as usize;
+        let end = cmp::min(data.inner.len as usize, off.saturating_add(buf.len()));
+        let first = off / SECTOR_SIZE;
+        let sectors = Self::sectors(&data.inner, first..bytes_to_sectors(end) as usize)?;
 
-        loop {
+        let mut pos = off;
+        while pos < end {
             // Read from `sector` at `sector_offset`.
-            let sector = start as usize + off / SECTOR_SIZE;
-            let sector_offset = off % SECTOR_SIZE;
-
-            let inode_left = len.saturating_sub(off); // Bytes left in inode.
-            let sector_left = SECTOR_SIZE - sector_offset; // Bytes left in sector.
-            let chunk_size = cmp::min(cmp::min(inode_left, sector_left), buf_left); //  Actual bytes to read.
-            if chunk_size == 0 {
-                break;
-            }
-
-            let page_off = (buf.as_ptr() as usize + bytes_read) & PG_MASK;
-
-            if (chunk_size == SECTOR_SIZE) && (page_off <= PG_SIZE - SECTOR_SIZE) {
-                // Virtio only supports kernel buffers.
-                // So we need to convert the possible user buffer into kernel buffer.
-                let buf_kvm: &mut [u8; SECTOR_SIZE] = (&mut buf
-                    [bytes_read..bytes_read + SECTOR_SIZE])
-                    .translate()
-                    .ok_or(OsError::BadPtr)?
-                    .try_into()
-                    .unwrap();
-                Virtio::read_sector(sector as _, buf_kvm);
+            let sector = sectors[pos / SECTOR_SIZE - first];
+            let sector_offset = pos % SECTOR_SIZE;
+            let chunk_size = cmp::min(SECTOR_SIZE - sector_offset, end - pos);
+            let bytes_read = pos - off;
+
+            if sector == 0 {
+                // A hole.
+                buf[bytes_read..bytes_read + chunk_size].fill(0);
+            } else if !desc.journaled && chunk_size == SECTOR_SIZE {
+                // Whole sectors following each other on the disk are read
+                // in one request, straight into the buffer.
+                let idx = pos / SECTOR_SIZE - first;
+                let len = run_len(&sectors[idx..], (end - pos) / SECTOR_SIZE) * SECTOR_SIZE;
+                let mut segments = segments_mut(&mut buf[bytes_read..bytes_read + len])?;
+                Virtio::read_sectors(sector as _, &mut segments)?;
+                pos += len;
+                continue;
             } else {
                 // We need a bounce buffer.
                 let mut bounce = [0; SECTOR_SIZE];
-                Virtio::read_sector(sector as _, &mut bounce);
+                desc.read_sector(sector as _, &mut bounce)?;
                 buf[bytes_read..bytes_read + chunk_size]
                     .copy_from_slice(&bounce[sector_offset..sector_offset + chunk_size]);
             }
 
-            // Advance.
-            buf_left -= chunk_size;
-            off += chunk_size;
-            bytes_read += chunk_size;
+            pos += chunk_size;
         }
 
-        Ok(bytes_read)
-    }
-
-    fn write_at(&self, buf: &[u8], mut off: usize) -> Result<usize> {
-        if self.0.lock().0.deny_write > 0 {
-            return Err(OsError::InvalidFileMode);
+        // Persisted lazily, see `close()`. Like relatime, a read changes the
+        // access time only if it would tell something, so reading doesn't
+        // commit a transaction on each close.
+        let now = time_ms() as u64;
+        let stale = data.inner.atime <= data.inner.mtime
+            || now.saturating_sub(data.inner.atime) >= ATIME_PERIOD;
+        if desc.timed && stale {
+            data.inner.atime = now;
+            desc.dirty = true;
         }
 
-        let mut bytes_written = 0;
-        let mut buf_left = buf.len();
+        Ok(pos.saturating_sub(off))
+    }
 
-        // We must acquire lock during the whole process
-        // to avoid being resized by other threads.
-        let mut guard = self.0.lock();
-        let (desc, data) = &mut *guard;
+    fn write_at(&self, buf: &[u8], off: usize) -> Result<usize> {
+        Ok(self.write_inner(buf, Some(off))?.1)
+    }
 
-        let mut start = data.inner.start;
-        let mut len = data.inner.len as usize;
+    fn append(&self, buf: &[u8]) -> Result<(usize, usize)> {
+        self.write_inner(buf, None)
+    }
 
-        if len < off + buf.len() {
-            let newlen = off + buf.len();
+    fn resize(&self, newlen: usize) -> Result<()> {
+        let tx = journal::begin();
+        {
+            let (desc, data) = &mut *self.0.lock();
             Self::resize_inner(desc, data, newlen)?;
-            start = data.inner.start;
-            len = data.inner.len as usize;
         }
+        tx.commit()
+    }
 
-        loop {
-            let sector = start as usize + off / SECTOR_SIZE;
-            let sector_offset = off % SECTOR_SIZE;
+    /// Sectors wholly in the range are freed, and bytes of the sectors partly
+    /// in it zeroed.
+    fn punch_hole(&self, off: usize, len: usize) -> Result<()> {
+        let tx = journal::begin();
+        {
+            let (desc, data) = &mut *self.0.lock();
+            Self::punch_hole_inner(desc, data, off, len)?;
+        }
+        tx.commit()
+    }
 
-            let inode_left = len.saturating_sub(off);
-            let sector_left = SECTOR_SIZE - sector_offset;
-            let chunk_size = cmp::min(cmp::min(inode_left, sector_left), buf_left);
-            if chunk_size == 0 {
-                break;
+    fn close(&self) -> Result<()> {
+        let tx = {
+            let (desc, _) = &*self.0.lock();
+            if !desc.removed && !desc.dirty {
+                return Ok(());
             }
+            journal::begin()
+        };
 
-            let page_off = (buf.as_ptr() as usize + bytes_written) & PG_MASK;
-
-            if (chunk_size == SECTOR_SIZE) && (page_off <= PG_SIZE - SECTOR_SIZE) {
-                // Virtio only supports kernel buffers.
-                // So we need to convert the possible user buffer into kernel buffer.
-                let buf_kvm: &[u8; SECTOR_SIZE] = (&buf
-                    [bytes_written..bytes_written + SECTOR_SIZE])
-                    .translate()
-                    .ok_or(OsError::BadPtr)?
-                    .try_into()
-                    .unwrap();
-                Virtio::write_sector(sector as _, buf_kvm);
-            } else {
-                // We need a bounce buffer, preserving old bytes which should not be written.
-                let mut bounce = [0; SECTOR_SIZE];
-                Virtio::read_sector(sector as _, &mut bounce);
-                bounce[sector_offset..sector_offset + chunk_size]
-                    .copy_from_slice(&buf[bytes_written..bytes_written + chunk_size]);
-                Virtio::write_sector(sector as _, &bounce);
+        {
+            let mut l = self.0.lock();
+            let (desc, data) = l.deref_mut();
+            if desc.dirty && !desc.removed {
+                Self::flush(desc, data)?;
+            }
+            if desc.removed {
+                // Remove the inode from the disk. It has no name left.
+                let mut freemap = DISKFS.free_map.lock();
+                Self::free_sectors(desc, &mut data.inner, 0..usize::MAX, &mut freemap)?;
+                free_meta(desc.sector, &mut freemap)?;
             }
-
-            buf_left -= chunk_size;
-            off += chunk_size;
-            bytes_written += chunk_size;
         }
-
-        Ok(bytes_written)
+        tx.commit()
     }
 
-    fn resize(&self, newlen: usize) -> Result<()> {
-        let mut guard = self.0.lock();
-        let (desc, data) = &mut *guard;
-        Self::resize_inner(desc, data, newlen)
-    }
-
-    fn close(&self) {
-        let l = self.0.lock();
-        let (desc, data) = l.deref();
-        if desc.shrink_len > 0 {
-            // Sector count to deallocate.
-            // == floor(desc.shrink_len / SECTOR_SIZE)
-            let cnt = desc.shrink_len / SECTOR_SIZE as u32;
-            // The start sector to deallocate.
-            // == inner.start + ceil(inner.len / SECTOR_SIZE)
-            let sector =
-                data.inner.start + (data.inner.len + SECTOR_SIZE as u32 - 1) / SECTOR_SIZE as u32;
-            let mut freemap = DISKFS.free_map.lock();
-            freemap.dealloc(sector, cnt);
-        }
-        if desc.removed {
-            // Remove the inode from the disk.
-            let mut rootdir = DISKFS.root_dir.lock();
-            rootdir
-                .remove(desc.sector)
-                .expect("Failed to remove from root dir");
-            let mut freemap = DISKFS.free_map.lock();
-            freemap.dealloc(data.inner.start as _, bytes_to_sectors(data.inner.len as _));
-            freemap.dealloc(desc.sector, 1);
-        }
+    fn locks(&self) -> Option<&FileLocks> {
+        Some(&self.1)
     }
 
     fn deny_write(&self) {
@@ -359,6 +983,11 @@ impl Vnode for Inode {
 
 impl Drop for Inode {
     fn drop(&mut self) {
-        self.close()
+        // Nobody is left to tell. The transaction is aborted, so the sectors
+        // of a removed inode are leaked rather than half freed.
+        if let Err(e) = self.close() {
+            let sector = self.0.lock().0.sector;
+            kprintln!("Failed to close inode {}: {:?}", sector, e);
+        }
     }
 }
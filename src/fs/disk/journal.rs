@@ -0,0 +1,380 @@
+//! Metadata journal.
+//!
+//! Metadata sectors (inodes, index sectors, directory contents and the free
+//! map) written inside a [`Transaction`] are held in memory. When the outermost
+//! transaction ends, they are first copied into the journal region, then
+//! a header naming their home sectors is written, and only after that are they
+//! installed to their home locations.
+//!
+//! A crash before the header reaches the disk loses the whole transaction,
+//! while a crash after it is repaired by [`load()`] at mount time, which redoes
+//! the installation. Either way, the metadata never ends up half updated.
+//!
+//! A transaction ends either by being committed, or by being aborted if it's
+//! dropped uncommitted, say on an error. Aborting drops its writes and undoes
+//! what it did in memory, so a failed operation leaves nothing behind.
+//!
+//! File contents are not journaled.
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::mem;
+use core::sync::atomic::{AtomicU32, Ordering::SeqCst};
+
+use super::{Inum, DISKFS};
+use crate::device::virtio::{Virtio, SECTOR_SIZE};
+use crate::sync::{Lazy, Mutex, Semaphore};
+use crate::thread;
+use crate::{OsError, Result};
+
+const JOURNAL_MAGIC: u32 = 0x4a524e4c;
+
+/// Maximum number of sectors a transaction may write.
+pub(super) const JOURNAL_CAPACITY: usize = (SECTOR_SIZE - 8) / mem::size_of::<Inum>();
+
+/// Journal length in sector, including the header.
+pub(super) const JOURNAL_SECTOR_LEN: u32 = JOURNAL_CAPACITY as u32 + 1;
+
+/// The first sector of the journal region.
+///
+/// Sector `i` of a committed transaction is logged at `start + 1 + i`.
+#[repr(C)]
+struct JournalHeader {
+    magic: u32,
+    /// Number of logged sectors. Zero if there is nothing to replay.
+    cnt: u32,
+    /// Home sector of each logged sector.
+    homes: [Inum; JOURNAL_CAPACITY],
+}
+
+struct Journal {
+    /// Start sector of the journal region.
+    start: AtomicU32,
+    /// Serializes transactions between threads.
+    gate: Semaphore,
+    state: Mutex<JournalState>,
+}
+
+struct JournalState {
+    /// Tid of the thread running the open transaction.
+    owner: Option<isize>,
+    /// Nesting depth of the open transaction.
+    depth: usize,
+    /// Whether a nested transaction was aborted, which aborts the outermost one.
+    aborted: bool,
+    /// Sectors written by the open transaction.
+    pending: Vec<(Inum, Box<[u8; SECTOR_SIZE]>)>,
+    /// Sectors of committed transactions that failed to be installed. They are
+    /// logged again with the next transaction.
+    unsettled: Vec<(Inum, Box<[u8; SECTOR_SIZE]>)>,
+}
+
+static JOURNAL: Lazy<Journal> = Lazy::new(|| Journal {
+    start: AtomicU32::new(0),
+    gate: Semaphore::new(1),
+    state: Mutex::new(JournalState {
+        owner: None,
+        depth: 0,
+        aborted: false,
+        pending: Vec::new(),
+        unsettled: Vec::new(),
+    }),
+});
+
+/// An open transaction. Its writes are committed with [`Transaction::commit()`]
+/// of the outermost transaction of the thread. Dropping one uncommitted aborts it.
+///
+/// Transactions must be begun before taking any inode, directory or
+/// free map lock, and ended after releasing them.
+pub(super) struct Transaction {
+    /// Whether it's committed.
+    done: bool,
+}
+
+/// Begin a transaction.
+///
+/// Blocks while another thread has a transaction open. Calls nested in
+/// a transaction of the current thread join it.
+pub(super) fn begin() -> Transaction {
+    let tid = thread::current().id();
+    {
+        let mut state = JOURNAL.state.lock();
+        if state.owner == Some(tid) {
+            state.depth += 1;
+            return Transaction { done: false };
+        }
+    }
+
+    JOURNAL.gate.down();
+    let mut state = JOURNAL.state.lock();
+    state.owner = Some(tid);
+    state.depth = 1;
+    Transaction { done: false }
+}
+
+impl Transaction {
+    /// Commit the transaction. A nested one is committed with the outermost.
+    ///
+    /// # Errors
+    /// Why the transaction couldn't be committed, in which case it's aborted,
+    /// or [`OsError::TransactionAborted`] if a nested one was aborted.
+    pub(super) fn commit(mut self) -> Result<()> {
+        self.done = true;
+        {
+            let mut state = JOURNAL.state.lock();
+            state.depth -= 1;
+            if state.depth > 0 {
+                return Ok(());
+            }
+            if state.aborted {
+                drop(state);
+                abort();
+                return Err(OsError::TransactionAborted);
+            }
+        }
+
+        // The free map is kept in memory, log its dirty part as well.
+        let mut free_map = DISKFS.free_map.lock();
+        match free_map.flush().and_then(|_| JOURNAL.commit()) {
+            Ok(()) => {
+                // What the transaction freed is no longer used on the disk either.
+                free_map.settle();
+                drop(free_map);
+                end();
+                Ok(())
+            }
+            Err(e) => {
+                drop(free_map);
+                abort();
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        {
+            let mut state = JOURNAL.state.lock();
+            state.depth -= 1;
+            if state.depth > 0 {
+                state.aborted = true;
+                return;
+            }
+        }
+        abort();
+    }
+}
+
+/// Abort the outermost transaction, dropping its writes and undoing its
+/// changes to the free map and the inodes in memory.
+fn abort() {
+    let written = {
+        let mut state = JOURNAL.state.lock();
+        let written = !state.pending.is_empty();
+        state.pending.clear();
+        written
+    };
+    let inodes = DISKFS.rollback(written);
+    end();
+    // Dropping the last holder of an inode may begin another transaction.
+    drop(inodes);
+}
+
+/// End the outermost transaction, letting other threads begin theirs.
+fn end() {
+    {
+        let mut state = JOURNAL.state.lock();
+        state.owner = None;
+        state.aborted = false;
+    }
+    JOURNAL.gate.up();
+}
+
+/// Read a metadata sector, seeing writes of the open transaction.
+pub(super) fn read_sector(sector: Inum, buf: &mut [u8; SECTOR_SIZE]) -> Result<()> {
+    {
+        let state = JOURNAL.state.lock();
+        let mut held = state.pending.iter().chain(state.unsettled.iter());
+        if let Some((_, data)) = held.find(|(home, _)| *home == sector) {
+            buf.copy_from_slice(&data[..]);
+            return Ok(());
+        }
+    }
+    Virtio::read_sector(sector as _, buf)
+}
+
+/// Write a metadata sector.
+///
+/// Inside a transaction of the current thread the write is held until commit,
+/// otherwise it goes directly to the disk.
+///
+/// # Errors
+/// [`OsError::JournalFull`] if the transaction writes more than
+/// [`JOURNAL_CAPACITY`] distinct sectors.
+pub(super) fn write_sector(sector: Inum, buf: &[u8; SECTOR_SIZE]) -> Result<()> {
+    let mut state = JOURNAL.state.lock();
+    if state.owner != Some(thread::current().id()) {
+        drop(state);
+        return Virtio::write_sector(sector as _, buf);
+    }
+
+    if let Some((_, data)) = state.pending.iter_mut().find(|(home, _)| *home == sector) {
+        data.copy_from_slice(buf);
+    } else if state.pending.len() + state.unsettled.len() < JOURNAL_CAPACITY {
+        state.pending.push((sector, Box::new(*buf)));
+    } else {
+        return Err(OsError::JournalFull);
+    }
+    Ok(())
+}
+
+/// Drop the held write of a metadata sector that has been freed, so it isn't
+/// installed over whatever the sector is reused for.
+pub(super) fn discard(sector: Inum) {
+    let mut state = JOURNAL.state.lock();
+    if state.owner == Some(thread::current().id()) {
+        state.pending.retain(|(home, _)| *home != sector);
+    }
+}
+
+/// Load the journal region at `start`, and replay the transaction
+/// that was committed but possibly not installed.
+///
+/// On a `read_only` disk the transaction can't be installed, so its sectors
+/// are held unsettled in memory instead, and read from there.
+///
+/// # Errors
+/// [`OsError::UnknownFormat`] if there isn't a journal at `start`.
+pub(super) fn load(start: Inum, read_only: bool) -> Result<()> {
+    let mut header = read_header(start)?;
+    if header.magic != JOURNAL_MAGIC || header.cnt as usize > JOURNAL_CAPACITY {
+        return Err(OsError::UnknownFormat);
+    }
+
+    if header.cnt > 0 {
+        #[cfg(feature = "debug")]
+        kprintln!("Journal replays {} sectors", header.cnt);
+
+        let mut buf = [0u8; SECTOR_SIZE];
+        for i in 0..header.cnt {
+            let home = header.homes[i as usize];
+            Virtio::read_sector((start + 1 + i) as _, &mut buf)?;
+            if read_only {
+                JOURNAL.state.lock().unsettled.push((home, Box::new(buf)));
+            } else {
+                Virtio::write_sector(home as _, &buf)?;
+            }
+        }
+        if !read_only {
+            header.cnt = 0;
+            write_header(start, &header)?;
+        }
+    }
+
+    JOURNAL.start.store(start, SeqCst);
+    Ok(())
+}
+
+/// Write an empty journal at `start`.
+pub(super) fn format(start: Inum) -> Result<()> {
+    let header = JournalHeader {
+        magic: JOURNAL_MAGIC,
+        cnt: 0,
+        homes: [0; JOURNAL_CAPACITY],
+    };
+    write_header(start, &header)
+}
+
+impl Journal {
+    /// Commit the open transaction, along with the sectors left unsettled by
+    /// earlier ones.
+    ///
+    /// # Errors
+    /// Why the transaction couldn't be committed. Once the header is written
+    /// it's committed, and failing to install it only leaves its sectors
+    /// unsettled.
+    fn commit(&self) -> Result<()> {
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        if state.pending.is_empty() && state.unsettled.is_empty() {
+            return Ok(());
+        }
+
+        // Newer writes of a sector take the place of unsettled ones.
+        let pending = &state.pending;
+        state
+            .unsettled
+            .retain(|(home, _)| pending.iter().all(|(sector, _)| sector != home));
+        let log: Vec<_> = state.unsettled.iter().chain(pending.iter()).collect();
+
+        let start = self.start.load(SeqCst);
+        let mut header = JournalHeader {
+            magic: JOURNAL_MAGIC,
+            cnt: 0,
+            homes: [0; JOURNAL_CAPACITY],
+        };
+
+        // (1) Log the sectors.
+        for (i, (home, data)) in log.iter().enumerate() {
+            Virtio::write_sector((start + 1 + i as u32) as _, data)?;
+            header.homes[i] = *home;
+        }
+        // The log must be on disk before the header points at it.
+        Virtio::flush()?;
+        // (2) Commit. From now on the transaction survives a crash.
+        header.cnt = log.len() as u32;
+        write_header(start, &header)?;
+        Virtio::flush()?;
+
+        let installed = (|| {
+            // (3) Install the sectors to their home locations.
+            for (home, data) in log.iter() {
+                Virtio::write_sector(*home as _, data)?;
+            }
+            // The home locations must be on disk before the log is dropped.
+            Virtio::flush()?;
+            // (4) Nothing left to replay.
+            header.cnt = 0;
+            write_header(start, &header)
+        })();
+
+        match installed {
+            Ok(()) => state.unsettled.clear(),
+            Err(e) => {
+                // Replayed on the next mount if it comes to that.
+                kprintln!("Journal failed to install a transaction: {:?}", e);
+                let pending = mem::take(&mut state.pending);
+                state.unsettled.extend(pending);
+            }
+        }
+        state.pending.clear();
+        Ok(())
+    }
+}
+
+fn read_header(start: Inum) -> Result<JournalHeader> {
+    let mut header = JournalHeader {
+        magic: 0,
+        cnt: 0,
+        homes: [0; JOURNAL_CAPACITY],
+    };
+    unsafe {
+        Virtio::read_sector(
+            start as _,
+            mem::transmute::<&mut JournalHeader, &mut [u8; SECTOR_SIZE]>(&mut header),
+        )?;
+    }
+    Ok(header)
+}
+
+fn write_header(start: Inum, header: &JournalHeader) -> Result<()> {
+    unsafe {
+        Virtio::write_sector(
+            start as _,
+            mem::transmute::<&JournalHeader, &[u8; SECTOR_SIZE]>(header),
+        )
+    }
+}
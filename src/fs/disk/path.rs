@@ -6,7 +6,7 @@ pub struct Path(alloc::string::String);
 
 impl Path {
     pub fn exists(path: Self) -> bool {
-        super::DISKFS.get().root_dir.lock().exists(&path)
+        super::DISKFS.get().exists(&path)
     }
 }
 
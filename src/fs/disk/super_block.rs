@@ -0,0 +1,140 @@
+//! Superblock.
+//!
+//! The superblock lives in the first sector of the disk and describes
+//! the layout of the file system. It is checked on mount, so that a
+//! foreign or corrupted disk is rejected rather than overwritten.
+use core::mem;
+
+use super::journal::JOURNAL_SECTOR_LEN;
+use super::{Inum, FREE_MAP_SECTOR, ROOT_DIR_SECTOR};
+use crate::device::virtio::{Virtio, SECTOR_SIZE};
+use crate::{OsError, Result};
+
+/// Sector of the superblock.
+pub(super) const SUPER_BLOCK_SECTOR: Inum = 0;
+
+const SUPER_MAGIC: u32 = 0x5441434f;
+
+/// Version of the on-disk format. Bumped on every incompatible change.
+const FS_VERSION: u32 = 5;
+
+const SUPER_PADDING: usize = SECTOR_SIZE - mem::size_of::<SuperBlockInner>();
+
+/// The superblock on the disk.
+///
+/// Size of this must be `SECTOR_SIZE`.
+#[repr(C)]
+pub(super) struct SuperBlock {
+    inner: SuperBlockInner,
+    padding: [u8; SUPER_PADDING],
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct SuperBlockInner {
+    magic: u32,
+    version: u32,
+    /// Number of sectors managed by the file system.
+    sectors: u32,
+    /// Inumber of sector free bitmap.
+    free_map: Inum,
+    /// Inumber of root dir.
+    root_dir: Inum,
+    /// Start sector of the journal.
+    journal_start: u32,
+    /// Journal length in sector.
+    journal_len: u32,
+    /// Non-zero if the file system was unmounted cleanly.
+    clean: u32,
+}
+
+impl SuperBlock {
+    /// Describe a fresh file system spanning `sectors` sectors.
+    pub(super) fn new(sectors: u32) -> Self {
+        Self {
+            inner: SuperBlockInner {
+                magic: SUPER_MAGIC,
+                version: FS_VERSION,
+                sectors,
+                free_map: FREE_MAP_SECTOR,
+                root_dir: ROOT_DIR_SECTOR,
+                journal_start: sectors - JOURNAL_SECTOR_LEN,
+                journal_len: JOURNAL_SECTOR_LEN,
+                clean: 1,
+            },
+            padding: [0; SUPER_PADDING],
+        }
+    }
+
+    /// Load and validate the superblock of a disk with `capacity` sectors.
+    ///
+    /// # Errors
+    /// - [`OsError::BadSuperBlock`]: there isn't a file system of ours on the disk,
+    ///   or its layout doesn't fit the disk.
+    /// - [`OsError::FsVersionMismatch`]: the file system has another format version.
+    pub(super) fn load(capacity: u64) -> Result<Self> {
+        let mut sb = Self {
+            inner: SuperBlockInner {
+                magic: 0,
+                version: 0,
+                sectors: 0,
+                free_map: 0,
+                root_dir: 0,
+                journal_start: 0,
+                journal_len: 0,
+                clean: 0,
+            },
+            padding: [0; SUPER_PADDING],
+        };
+        unsafe {
+            Virtio::read_sector(
+                SUPER_BLOCK_SECTOR as _,
+                mem::transmute::<&mut SuperBlock, &mut [u8; SECTOR_SIZE]>(&mut sb),
+            )?;
+        }
+
+        let inner = &sb.inner;
+        if inner.magic != SUPER_MAGIC {
+            return Err(OsError::BadSuperBlock);
+        }
+        if inner.version != FS_VERSION {
+            return Err(OsError::FsVersionMismatch);
+        }
+        if inner.sectors as u64 > capacity
+            || inner.free_map != FREE_MAP_SECTOR
+            || inner.root_dir != ROOT_DIR_SECTOR
+            || inner.journal_len != JOURNAL_SECTOR_LEN
+            || inner.journal_start.checked_add(inner.journal_len) != Some(inner.sectors)
+        {
+            return Err(OsError::BadSuperBlock);
+        }
+
+        Ok(sb)
+    }
+
+    /// Write the superblock back to the disk.
+    pub(super) fn flush(&self) -> Result<()> {
+        unsafe {
+            Virtio::write_sector(
+                SUPER_BLOCK_SECTOR as _,
+                mem::transmute::<&SuperBlock, &[u8; SECTOR_SIZE]>(self),
+            )
+        }
+    }
+
+    pub(super) fn sectors(&self) -> u32 {
+        self.inner.sectors
+    }
+
+    pub(super) fn journal_start(&self) -> Inum {
+        self.inner.journal_start
+    }
+
+    pub(super) fn is_clean(&self) -> bool {
+        self.inner.clean != 0
+    }
+
+    pub(super) fn set_clean(&mut self, clean: bool) {
+        self.inner.clean = clean as u32;
+    }
+}
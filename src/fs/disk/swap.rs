@@ -1,35 +1,157 @@
 //! Swap file.
 //!
+//! The swap file is divided into page sized slots. A slot is allocated to
+//! hold an evicted page, written and read back a whole page at a time, and
+//! freed once the page is loaded again or no longer needed. Freeing a slot
+//! punches a hole over it, so the disk space goes back to the free map.
 // Swap may not be used.
 #![allow(dead_code)]
+use alloc::vec;
+use alloc::vec::Vec;
+
 use super::DISKFS;
 use crate::fs::{File, FileSys};
-use crate::io::Seek;
+use crate::io::prelude::*;
 use crate::mem::PG_SIZE;
-use crate::sync::{Lazy, Mutex, MutexGuard, Primitive};
+use crate::sync::{Lazy, Mutex};
+use crate::{OsError, Result};
 
 pub struct Swap;
 
-static SWAPFILE: Lazy<Mutex<File>> = Lazy::new(|| {
-    Mutex::new(
-        DISKFS
-            .open(".glbswap".into())
-            .expect("swap file \".glbswap\" should exist"),
-    )
+/// Path of the swap file on the disk.
+pub(super) const SWAP_PATH: &str = ".glbswap";
+
+/// A page sized slot of the swap file, see [`Swap::alloc_slot()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SwapSlot(usize);
+
+impl SwapSlot {
+    /// Index of the slot, counted in pages from the start of the swap file.
+    pub fn index(&self) -> usize {
+        self.0
+    }
+}
+
+/// Usage of the swap file, see [`Swap::stats()`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SwapStats {
+    /// Number of slots in the swap file.
+    pub slots: usize,
+    /// Number of slots allocated.
+    pub used: usize,
+    /// Pages read back since boot.
+    pub reads: usize,
+    /// Pages written out since boot.
+    pub writes: usize,
+}
+
+struct SwapFile {
+    file: File,
+    /// One bit for each slot, set if it's allocated.
+    bitmap: Vec<u64>,
+    stats: SwapStats,
+}
+
+static SWAPFILE: Lazy<Mutex<SwapFile>> = Lazy::new(|| {
+    let file = DISKFS
+        .open(SWAP_PATH.into())
+        .expect("swap file \".glbswap\" should exist");
+    // Round down.
+    let slots = file.len().unwrap() / PG_SIZE;
+    Mutex::new(SwapFile {
+        file,
+        bitmap: vec![0; (slots + 63) / 64],
+        stats: SwapStats {
+            slots,
+            ..SwapStats::default()
+        },
+    })
 });
 
 impl Swap {
     pub fn len() -> usize {
-        SWAPFILE.lock().len().unwrap()
+        SWAPFILE.lock().file.len().unwrap()
     }
 
     pub fn page_num() -> usize {
-        // Round down.
-        Self::len() / PG_SIZE
+        SWAPFILE.lock().stats.slots
     }
 
-    /// TODO: Design high-level interfaces, or do in lab3?
-    pub fn lock() -> MutexGuard<'static, File, Primitive> {
-        SWAPFILE.lock()
+    /// Allocate a free slot.
+    ///
+    /// # Errors
+    /// [`OsError::SwapFull`] if all slots are in use.
+    pub fn alloc_slot() -> Result<SwapSlot> {
+        let mut swap = SWAPFILE.lock();
+        let slots = swap.stats.slots;
+        let (word, bits) = swap
+            .bitmap
+            .iter_mut()
+            .enumerate()
+            .find(|(_, bits)| **bits != !0)
+            .ok_or(OsError::SwapFull)?;
+        let slot = word * 64 + bits.trailing_ones() as usize;
+        // Bits of the last word past the last slot are never set.
+        if slot >= slots {
+            return Err(OsError::SwapFull);
+        }
+        *bits |= 1 << (slot % 64);
+        swap.stats.used += 1;
+        Ok(SwapSlot(slot))
+    }
+
+    /// Free `slot`. What was written to it is lost.
+    ///
+    /// # Errors
+    /// [`OsError::DoubleFree`] if it isn't allocated.
+    pub fn free_slot(slot: SwapSlot) -> Result<()> {
+        let mut swap = SWAPFILE.lock();
+        swap.check(slot).map_err(|_| OsError::DoubleFree)?;
+        let off = slot.0 * PG_SIZE;
+        swap.file.punch_hole(off..off + PG_SIZE)?;
+        swap.bitmap[slot.0 / 64] &= !(1 << (slot.0 % 64));
+        swap.stats.used -= 1;
+        Ok(())
+    }
+
+    /// Write `frame` to `slot`.
+    ///
+    /// # Errors
+    /// [`OsError::InvalidArgument`] if `slot` isn't allocated.
+    pub fn write_page(slot: SwapSlot, frame: &[u8; PG_SIZE]) -> Result<()> {
+        let mut swap = SWAPFILE.lock();
+        swap.check(slot)?;
+        swap.file.seek(SeekFrom::Start(slot.0 * PG_SIZE))?;
+        swap.file.write_all(frame)?;
+        swap.stats.writes += 1;
+        Ok(())
+    }
+
+    /// Read `slot` into `frame`. A slot never written reads as zeros.
+    ///
+    /// # Errors
+    /// [`OsError::InvalidArgument`] if `slot` isn't allocated.
+    pub fn read_page(slot: SwapSlot, frame: &mut [u8; PG_SIZE]) -> Result<()> {
+        let mut swap = SWAPFILE.lock();
+        swap.check(slot)?;
+        swap.file.seek(SeekFrom::Start(slot.0 * PG_SIZE))?;
+        swap.file.read_exact(frame)?;
+        swap.stats.reads += 1;
+        Ok(())
+    }
+
+    pub fn stats() -> SwapStats {
+        SWAPFILE.lock().stats
+    }
+}
+
+impl SwapFile {
+    /// # Errors
+    /// [`OsError::InvalidArgument`] if `slot` isn't allocated.
+    fn check(&self, slot: SwapSlot) -> Result<()> {
+        match self.bitmap.get(slot.0 / 64) {
+            Some(bits) if bits & (1 << (slot.0 % 64)) != 0 => Ok(()),
+            _ => Err(OsError::InvalidArgument),
+        }
     }
 }
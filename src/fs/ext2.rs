@@ -0,0 +1,661 @@
+//! Read-only ext2 file system.
+//!
+//! Reads disk images made on the host by `mke2fs -t ext2 -d <dir>`, on the
+//! same virtio disk the disk fs uses. Files are found through the group
+//! descriptors and inode tables, and their content through the direct and
+//! indirect blocks of their inodes. Symlinks are followed.
+//!
+//! Images using incompatible features other than file types in directory
+//! entries, such as extents, aren't supported. Anything changing the file
+//! system fails with [`OsError::ReadOnlyFs`].
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::sync::Weak;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::convert::TryInto;
+
+use crate::device::virtio::{Virtio, SECTOR_SIZE};
+use crate::{OsError, Result};
+
+use super::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                   FileSys                                  */
+/* -------------------------------------------------------------------------- */
+
+const ROOT_INUM: u32 = 2;
+
+/// Maximum number of symlinks followed in one lookup.
+const SYMLINK_DEPTH_MAX: usize = 8;
+
+/// An ext2 file system on a virtio disk.
+///
+/// ## Examples
+/// ```ignore
+/// let fs = Ext2Fs::mount(Virtio::get())?;
+/// VFS.mount("/ext2", Arc::new(Mounted(Box::new(fs))))?;
+/// let file = VFS.open("/ext2/bin/echo")?;
+/// ```
+pub struct Ext2Fs {
+    volume: Arc<Volume>,
+    root: Arc<Ext2Node>,
+    /// Nodes in use, so each file has one, whose advisory locks all its
+    /// opens share.
+    nodes: Mutex<BTreeMap<u32, Weak<Ext2Node>>>,
+}
+
+impl FileSys for Ext2Fs {
+    type Device = &'static Mutex<Virtio>;
+    type Path = String;
+
+    /// # Errors
+    /// [`OsError::UnknownFormat`] if there isn't a supported ext2 file system
+    /// on the disk.
+    fn mount(device: Self::Device) -> Result<Self> {
+        let volume = Arc::new(Volume::load(device.lock().capacity())?);
+        let root = Ext2Node::open(&volume, ROOT_INUM)?;
+        if root.kind != FileType::Dir {
+            return Err(OsError::UnknownFormat);
+        }
+        let mut nodes = BTreeMap::new();
+        nodes.insert(ROOT_INUM, Arc::downgrade(&root));
+        Ok(Self {
+            volume,
+            root,
+            nodes: Mutex::new(nodes),
+        })
+    }
+
+    fn unmount(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn open(&self, id: Self::Path) -> Result<File> {
+        Ok(File::new(self.lookup(&id)?))
+    }
+
+    fn close(&self, _file: File) {}
+
+    fn create(&self, _id: Self::Path) -> Result<File> {
+        Err(OsError::ReadOnlyFs)
+    }
+
+    fn remove(&self, _id: Self::Path) -> Result<()> {
+        Err(OsError::ReadOnlyFs)
+    }
+
+    fn rename(&self, _from: Self::Path, _to: Self::Path) -> Result<()> {
+        Err(OsError::ReadOnlyFs)
+    }
+
+    fn mkdir(&self, _id: Self::Path) -> Result<()> {
+        Err(OsError::ReadOnlyFs)
+    }
+
+    fn readdir(&self, id: Self::Path) -> Result<ReadDir> {
+        let entries = self
+            .read_dir(&*self.lookup(&id)?)?
+            .into_iter()
+            .filter(|entry| entry.name != "." && entry.name != "..")
+            .map(|entry| {
+                Ok(DirEntry {
+                    kind: self.entry_kind(&entry)?,
+                    name: entry.name,
+                    inum: entry.inum as usize,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(ReadDir::new(entries))
+    }
+
+    /// Counts are the ones kept in the superblock. The largest free extent is
+    /// found in the block bitmaps.
+    fn statfs(&self) -> Result<StatFs> {
+        let volume = &self.volume;
+        let per_block = volume.block_size / SECTOR_SIZE;
+        let (mut run, mut largest) = (0, 0);
+        let mut bitmap = vec![0; volume.block_size];
+        for (group, desc) in volume.groups.iter().enumerate() {
+            volume.read_block(desc.block_bitmap, &mut bitmap)?;
+            let first = volume.first_data_block + group as u32 * volume.blocks_per_group;
+            let cnt = min(volume.blocks_per_group, volume.blocks_count - first);
+            for i in 0..cnt as usize {
+                if bitmap[i / 8] & (1 << (i % 8)) == 0 {
+                    run += 1;
+                    largest = largest.max(run);
+                } else {
+                    run = 0;
+                }
+            }
+        }
+
+        Ok(StatFs {
+            blocks: volume.blocks_count as usize * per_block,
+            free_blocks: volume.free_blocks as usize * per_block,
+            largest_free: largest * per_block,
+            inodes: (volume.inodes_count - volume.free_inodes) as usize,
+            free_inodes: volume.free_inodes as usize,
+            swap_blocks: 0,
+        })
+    }
+}
+
+impl Ext2Fs {
+    /// Look up `path` from the root dir, following symlinks on the way.
+    ///
+    /// # Errors
+    /// [`OsError::SymlinkLoop`] if more than [`SYMLINK_DEPTH_MAX`] symlinks are met.
+    fn lookup(&self, path: &str) -> Result<Arc<Ext2Node>> {
+        let mut names: VecDeque<String> = components(path).map(String::from).collect();
+        let mut node = self.root.clone();
+        let mut depth = 0;
+
+        while let Some(name) = names.pop_front() {
+            let entry = self
+                .read_dir(&node)?
+                .into_iter()
+                .find(|entry| entry.name == name)
+                .ok_or(OsError::NoSuchFile)?;
+            let next = self.node(entry.inum)?;
+
+            if next.kind == FileType::Symlink {
+                if depth == SYMLINK_DEPTH_MAX {
+                    return Err(OsError::SymlinkLoop);
+                }
+                depth += 1;
+
+                // Relative targets are looked up from the dir holding the link.
+                let target = next.read_link()?;
+                for name in components(&target).rev() {
+                    names.push_front(name.into());
+                }
+                if target.starts_with('/') {
+                    node = self.root.clone();
+                }
+                continue;
+            }
+            node = next;
+        }
+        Ok(node)
+    }
+
+    /// Entries of `dir`, including `.` and `..`.
+    ///
+    /// # Errors
+    /// [`OsError::BadDirEntry`] if an entry runs past its block.
+    fn read_dir(&self, dir: &Ext2Node) -> Result<Vec<Entry>> {
+        if dir.kind != FileType::Dir {
+            return Err(OsError::NotDir);
+        }
+        let mut entries = Vec::new();
+        // Entries never cross a block, so each block is read and parsed by
+        // itself, however large the directory claims to be.
+        let mut content = vec![0; self.volume.block_size];
+        for pos in (0..dir.size).step_by(self.volume.block_size) {
+            let len = dir.read_at(&mut content, pos)?;
+            let block = &content[..len];
+            let mut off = 0;
+            while off + DIR_ENTRY_HEADER <= block.len() {
+                let raw = &block[off..];
+                let inum = u32::from_le_bytes(raw[..4].try_into().unwrap());
+                let rec_len = u16::from_le_bytes([raw[4], raw[5]]) as usize;
+                let name_len = raw[6] as usize;
+                if rec_len < DIR_ENTRY_HEADER
+                    || rec_len > raw.len()
+                    || DIR_ENTRY_HEADER + name_len > rec_len
+                {
+                    return Err(OsError::BadDirEntry);
+                }
+                // Unused entries have inode 0.
+                if inum != 0 {
+                    let name = &raw[DIR_ENTRY_HEADER..DIR_ENTRY_HEADER + name_len];
+                    entries.push(Entry {
+                        name: String::from_utf8_lossy(name).into_owned(),
+                        inum,
+                        file_type: raw[7],
+                    });
+                }
+                off += rec_len;
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Type of the file an entry names, from the entry if it keeps it, and
+    /// from its inode if not.
+    fn entry_kind(&self, entry: &Entry) -> Result<FileType> {
+        if self.volume.incompat & INCOMPAT_FILETYPE != 0 {
+            return Ok(match entry.file_type {
+                2 => FileType::Dir,
+                7 => FileType::Symlink,
+                _ => FileType::File,
+            });
+        }
+        Ok(self.node(entry.inum)?.kind)
+    }
+
+    /// The node of inode `inum`, shared while it's in use.
+    fn node(&self, inum: u32) -> Result<Arc<Ext2Node>> {
+        let mut nodes = self.nodes.lock();
+        if let Some(node) = nodes.get(&inum).and_then(Weak::upgrade) {
+            return Ok(node);
+        }
+        let node = Ext2Node::open(&self.volume, inum)?;
+        nodes.retain(|_, node| node.strong_count() > 0);
+        nodes.insert(inum, Arc::downgrade(&node));
+        Ok(node)
+    }
+}
+
+/// Names in `path`.
+fn components(path: &str) -> impl DoubleEndedIterator<Item = &str> {
+    path.split('/')
+        .filter(|name| !name.is_empty() && *name != ".")
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                   Volume                                   */
+/* -------------------------------------------------------------------------- */
+
+const EXT2_MAGIC: u16 = 0xef53;
+
+/// The superblock is always 1024 bytes into the disk.
+const SUPER_BLOCK_OFF: u64 = 1024;
+
+/// Directory entries keep their file type, instead of only the inode.
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+
+/// Inodes are this long in revision 0 file systems.
+const GOOD_OLD_INODE_SIZE: usize = 128;
+
+/// A block group descriptor.
+struct Group {
+    block_bitmap: u32,
+    inode_table: u32,
+}
+
+/// Layout of the file system, from its superblock and group descriptors.
+struct Volume {
+    block_size: usize,
+    blocks_count: u32,
+    inodes_count: u32,
+    free_blocks: u32,
+    free_inodes: u32,
+    first_data_block: u32,
+    blocks_per_group: u32,
+    inodes_per_group: u32,
+    inode_size: usize,
+    incompat: u32,
+    groups: Vec<Group>,
+}
+
+impl Volume {
+    /// Read the superblock and group descriptors of a disk with `capacity`
+    /// sectors.
+    ///
+    /// # Errors
+    /// [`OsError::UnknownFormat`] if there isn't an ext2 file system on it,
+    /// or it uses incompatible features.
+    fn load(capacity: u64) -> Result<Self> {
+        let mut sb = [0; 1024];
+        read_disk(SUPER_BLOCK_OFF, &mut sb)?;
+        let u32_at = |off: usize| u32::from_le_bytes(sb[off..off + 4].try_into().unwrap());
+        let u16_at = |off: usize| u16::from_le_bytes([sb[off], sb[off + 1]]);
+
+        if u16_at(56) != EXT2_MAGIC || u32_at(24) > 6 {
+            return Err(OsError::UnknownFormat);
+        }
+        let (inode_size, incompat) = match u32_at(76) {
+            0 => (GOOD_OLD_INODE_SIZE, 0),
+            _ => (u16_at(88) as usize, u32_at(96)),
+        };
+        let mut volume = Self {
+            block_size: 1024 << u32_at(24),
+            blocks_count: u32_at(4),
+            inodes_count: u32_at(0),
+            free_blocks: u32_at(12),
+            free_inodes: u32_at(16),
+            first_data_block: u32_at(20),
+            blocks_per_group: u32_at(32),
+            inodes_per_group: u32_at(40),
+            inode_size,
+            incompat,
+            groups: Vec::new(),
+        };
+        let groups = (volume.blocks_count.saturating_sub(volume.first_data_block) as u64
+            + volume.blocks_per_group as u64
+            - 1)
+        .checked_div(volume.blocks_per_group as u64)
+        .unwrap_or(0);
+        if incompat & !INCOMPAT_FILETYPE != 0
+            || volume.blocks_count as u64 * (volume.block_size / SECTOR_SIZE) as u64 > capacity
+            || volume.first_data_block >= volume.blocks_count
+            || groups == 0
+            || volume.inodes_per_group == 0
+            || volume.inodes_count as u64 > groups * volume.inodes_per_group as u64
+            || !inode_size.is_power_of_two()
+            || inode_size < GOOD_OLD_INODE_SIZE
+            || inode_size > volume.block_size
+        {
+            return Err(OsError::UnknownFormat);
+        }
+
+        // The descriptors are in the block after the superblock.
+        let mut table = vec![0; groups as usize * GROUP_DESC_LEN];
+        volume.read_bytes(volume.first_data_block + 1, 0, &mut table)?;
+        for desc in table.chunks(GROUP_DESC_LEN) {
+            let u32_at = |off: usize| u32::from_le_bytes(desc[off..off + 4].try_into().unwrap());
+            let group = Group {
+                block_bitmap: u32_at(0),
+                inode_table: u32_at(8),
+            };
+            if volume.check_block(group.block_bitmap).is_err()
+                || volume.check_block(group.inode_table).is_err()
+            {
+                return Err(OsError::UnknownFormat);
+            }
+            volume.groups.push(group);
+        }
+        Ok(volume)
+    }
+
+    /// # Errors
+    /// [`OsError::UnknownFormat`] if `block` is past the end of the file system.
+    fn check_block(&self, block: u32) -> Result<()> {
+        if block >= self.blocks_count {
+            return Err(OsError::UnknownFormat);
+        }
+        Ok(())
+    }
+
+    /// Read `buf.len()` bytes from `off` in `block` on.
+    fn read_bytes(&self, block: u32, off: usize, buf: &mut [u8]) -> Result<()> {
+        read_disk(block as u64 * self.block_size as u64 + off as u64, buf)
+    }
+
+    fn read_block(&self, block: u32, buf: &mut [u8]) -> Result<()> {
+        self.read_bytes(block, 0, &mut buf[..self.block_size])
+    }
+
+    /// Read inode `inum` from its inode table.
+    ///
+    /// # Errors
+    /// [`OsError::OpenInvalidInode`] if there isn't such an inode.
+    fn read_inode(&self, inum: u32) -> Result<[u8; GOOD_OLD_INODE_SIZE]> {
+        if inum == 0 || inum > self.inodes_count {
+            return Err(OsError::OpenInvalidInode);
+        }
+        let index = (inum - 1) as usize;
+        let group = &self.groups[index / self.inodes_per_group as usize];
+        let off = (index % self.inodes_per_group as usize) * self.inode_size;
+        let mut raw = [0; GOOD_OLD_INODE_SIZE];
+        self.read_bytes(
+            group.inode_table + (off / self.block_size) as u32,
+            off % self.block_size,
+            &mut raw,
+        )?;
+        Ok(raw)
+    }
+
+    /// Most blocks a file has, those its block pointers reach.
+    fn blocks_max(&self) -> u64 {
+        let n = (self.block_size / 4) as u64;
+        DIRECT_BLOCKS as u64 + n + n * n + n * n * n
+    }
+
+    /// The block holding block `index` of a file, 0 for a hole, given the 15
+    /// block pointers of its inode. Indirect blocks are read as needed, so a
+    /// large file doesn't take memory for a map of all its blocks.
+    ///
+    /// # Errors
+    /// [`OsError::FileTooLarge`] if the pointers don't reach that far.
+    fn block_of(&self, pointers: &[u32; 15], index: usize) -> Result<u32> {
+        let n = (self.block_size / 4) as u64;
+        let (mut block, mut index, mut span) = if index < DIRECT_BLOCKS {
+            (pointers[index], 0, 1)
+        } else {
+            // The last three are single, double and triple indirect.
+            let mut index = (index - DIRECT_BLOCKS) as u64;
+            let mut span = 1;
+            let mut found = None;
+            for depth in 1..=3 {
+                span *= n;
+                if index < span {
+                    found = Some((pointers[DIRECT_BLOCKS - 1 + depth], index, span));
+                    break;
+                }
+                index -= span;
+            }
+            found.ok_or(OsError::FileTooLarge)?
+        };
+
+        while span > 1 && block != 0 {
+            self.check_block(block)?;
+            span /= n;
+            let mut raw = [0; 4];
+            self.read_bytes(block, (index / span) as usize * 4, &mut raw)?;
+            block = u32::from_le_bytes(raw);
+            index %= span;
+        }
+        if block != 0 {
+            self.check_block(block)?;
+        }
+        Ok(block)
+    }
+}
+
+/// Read `buf.len()` bytes at byte `off` of the disk.
+fn read_disk(off: u64, buf: &mut [u8]) -> Result<()> {
+    let mut sector_buf = [0; SECTOR_SIZE];
+    let mut done = 0;
+    while done < buf.len() {
+        let pos = off + done as u64;
+        let in_sector = (pos % SECTOR_SIZE as u64) as usize;
+        let cnt = min(SECTOR_SIZE - in_sector, buf.len() - done);
+        Virtio::read_sector(pos / SECTOR_SIZE as u64, &mut sector_buf)?;
+        buf[done..done + cnt].copy_from_slice(&sector_buf[in_sector..in_sector + cnt]);
+        done += cnt;
+    }
+    Ok(())
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                 Directories                                */
+/* -------------------------------------------------------------------------- */
+
+const GROUP_DESC_LEN: usize = 32;
+
+/// Inode, record length, name length and file type.
+const DIR_ENTRY_HEADER: usize = 8;
+
+/// A file in a directory.
+struct Entry {
+    name: String,
+    inum: u32,
+    /// Only kept with [`INCOMPAT_FILETYPE`].
+    file_type: u8,
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Vnode                                   */
+/* -------------------------------------------------------------------------- */
+
+/// Pointers to content blocks in an inode, before the indirect ones.
+const DIRECT_BLOCKS: usize = 12;
+
+const S_IFMT: u16 = 0xf000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFREG: u16 = 0x8000;
+const S_IFLNK: u16 = 0xa000;
+
+/// Where the content of a file is.
+enum Content {
+    /// Block pointers of the inode, direct and then indirect, 0 for holes.
+    Blocks([u32; 15]),
+    /// Short symlink targets are kept in the block pointers instead.
+    Inline([u8; 60]),
+}
+
+struct Ext2Node {
+    volume: Arc<Volume>,
+    inum: u32,
+    kind: FileType,
+    mode: u16,
+    nlink: u32,
+    size: usize,
+    /// Space taken in 512-byte units.
+    sectors: u32,
+    content: Content,
+    locks: FileLocks,
+}
+
+impl Ext2Node {
+    /// # Errors
+    /// [`OsError::InvalidFileMode`] if it's not a regular file, a directory
+    /// or a symlink.
+    fn open(volume: &Arc<Volume>, inum: u32) -> Result<Arc<Self>> {
+        let raw = volume.read_inode(inum)?;
+        let u32_at = |off: usize| u32::from_le_bytes(raw[off..off + 4].try_into().unwrap());
+        let mode = u16::from_le_bytes([raw[0], raw[1]]);
+        let kind = match mode & S_IFMT {
+            S_IFDIR => FileType::Dir,
+            S_IFREG => FileType::File,
+            S_IFLNK => FileType::Symlink,
+            _ => return Err(OsError::InvalidFileMode),
+        };
+        let mut size = u32_at(4) as u64;
+        // Regular files keep the upper half of their size in place of the
+        // directory ACL.
+        if kind == FileType::File {
+            size |= (u32_at(108) as u64) << 32;
+        }
+        let size: usize = size.try_into().map_err(|_| OsError::FileTooLarge)?;
+        let sectors = u32_at(28);
+        let acl_sectors = match u32_at(104) {
+            0 => 0,
+            _ => (volume.block_size / SECTOR_SIZE) as u32,
+        };
+
+        let mut pointers = [0; 15];
+        for (i, pointer) in pointers.iter_mut().enumerate() {
+            *pointer = u32_at(40 + i * 4);
+        }
+        let content = if kind == FileType::Symlink && sectors == acl_sectors {
+            if size > 60 {
+                return Err(OsError::BadDirEntry);
+            }
+            Content::Inline(raw[40..100].try_into().unwrap())
+        } else {
+            let cnt = (size as u64 + volume.block_size as u64 - 1) / volume.block_size as u64;
+            if cnt > volume.blocks_max() {
+                return Err(OsError::FileTooLarge);
+            }
+            Content::Blocks(pointers)
+        };
+
+        Ok(Arc::new(Self {
+            volume: volume.clone(),
+            inum,
+            kind,
+            mode: mode & 0o777,
+            nlink: u16::from_le_bytes([raw[26], raw[27]]) as u32,
+            size,
+            sectors,
+            content,
+            locks: FileLocks::default(),
+        }))
+    }
+
+    /// # Errors
+    /// [`OsError::FileTooLarge`] if the target is longer than a block, which
+    /// ext2 doesn't make.
+    fn read_link(&self) -> Result<String> {
+        if self.size > self.volume.block_size {
+            return Err(OsError::FileTooLarge);
+        }
+        let mut target = vec![0; self.size];
+        self.read_at(&mut target, 0)?;
+        String::from_utf8(target).map_err(|_| OsError::CstrFormatErr)
+    }
+}
+
+impl Vnode for Ext2Node {
+    fn read_at(&self, buf: &mut [u8], off: usize) -> Result<usize> {
+        if off >= self.size {
+            return Ok(0);
+        }
+        let cnt = min(buf.len(), self.size - off);
+        let pointers = match &self.content {
+            Content::Blocks(pointers) => pointers,
+            Content::Inline(data) => {
+                buf[..cnt].copy_from_slice(&data[off..off + cnt]);
+                return Ok(cnt);
+            }
+        };
+
+        let block_size = self.volume.block_size;
+        let mut done = 0;
+        while done < cnt {
+            let pos = off + done;
+            let in_block = pos % block_size;
+            let len = min(block_size - in_block, cnt - done);
+            match self.volume.block_of(pointers, pos / block_size)? {
+                0 => buf[done..done + len].fill(0),
+                block => self
+                    .volume
+                    .read_bytes(block, in_block, &mut buf[done..done + len])?,
+            }
+            done += len;
+        }
+        Ok(cnt)
+    }
+
+    fn write_at(&self, _buf: &[u8], _off: usize) -> Result<usize> {
+        Err(OsError::ReadOnlyFs)
+    }
+
+    fn deny_write(&self) {}
+    fn allow_write(&self) {}
+
+    fn inum(&self) -> usize {
+        self.inum as usize
+    }
+
+    fn len(&self) -> usize {
+        self.size
+    }
+
+    /// Ext2 keeps times as seconds since 1970, not since boot, so times are
+    /// all 0.
+    fn stat(&self) -> Stat {
+        Stat {
+            inum: self.inum as usize,
+            kind: self.kind,
+            mode: self.mode,
+            nlink: self.nlink,
+            size: self.size,
+            blocks: self.sectors as usize,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
+        }
+    }
+
+    fn resize(&self, _size: usize) -> Result<()> {
+        Err(OsError::ReadOnlyFs)
+    }
+
+    fn close(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn punch_hole(&self, _off: usize, _len: usize) -> Result<()> {
+        Err(OsError::ReadOnlyFs)
+    }
+
+    fn locks(&self) -> Option<&FileLocks> {
+        Some(&self.locks)
+    }
+}
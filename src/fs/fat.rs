@@ -0,0 +1,599 @@
+//! Read-only FAT32 file system.
+//!
+//! Reads disk images made on the host by tools like `mkfs.vfat -F 32` and
+//! `mtools`, on the same virtio disk the disk fs uses. Long file names are
+//! supported, and names are matched ignoring ASCII case, as FAT does.
+//!
+//! Anything changing the file system fails with [`OsError::ReadOnlyFs`].
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Weak;
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::convert::TryInto;
+
+use crate::device::virtio::{Virtio, SECTOR_SIZE};
+use crate::{OsError, Result};
+
+use super::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                   FileSys                                  */
+/* -------------------------------------------------------------------------- */
+
+/// Inumber of the root directory. Other files are numbered by where their
+/// directory entry is, in entries from the start of the disk.
+const ROOT_INUM: usize = 1;
+
+/// A FAT32 file system on a virtio disk.
+///
+/// ## Examples
+/// ```ignore
+/// let fs = FatFs::mount(Virtio::get())?;
+/// VFS.mount("/fat", Arc::new(Mounted(Box::new(fs))))?;
+/// let file = VFS.open("/fat/Long File Name.txt")?;
+/// ```
+pub struct FatFs {
+    volume: Arc<Volume>,
+    root: Arc<FatNode>,
+    /// Nodes in use, so each file has one, whose advisory locks all its
+    /// opens share.
+    nodes: Mutex<BTreeMap<usize, Weak<FatNode>>>,
+}
+
+impl FileSys for FatFs {
+    type Device = &'static Mutex<Virtio>;
+    type Path = String;
+
+    /// # Errors
+    /// [`OsError::UnknownFormat`] if there isn't a FAT32 file system on the disk.
+    fn mount(device: Self::Device) -> Result<Self> {
+        let volume = Arc::new(Volume::load(device.lock().capacity())?);
+        let root = Arc::new(FatNode {
+            volume: volume.clone(),
+            inum: ROOT_INUM,
+            kind: FileType::Dir,
+            len: 0,
+            chain: volume.chain(volume.root_cluster)?,
+            locks: FileLocks::default(),
+        });
+        Ok(Self {
+            volume,
+            root,
+            nodes: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    fn unmount(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn open(&self, id: Self::Path) -> Result<File> {
+        Ok(File::new(self.lookup(&id)?))
+    }
+
+    fn close(&self, _file: File) {}
+
+    fn create(&self, _id: Self::Path) -> Result<File> {
+        Err(OsError::ReadOnlyFs)
+    }
+
+    fn remove(&self, _id: Self::Path) -> Result<()> {
+        Err(OsError::ReadOnlyFs)
+    }
+
+    fn rename(&self, _from: Self::Path, _to: Self::Path) -> Result<()> {
+        Err(OsError::ReadOnlyFs)
+    }
+
+    fn mkdir(&self, _id: Self::Path) -> Result<()> {
+        Err(OsError::ReadOnlyFs)
+    }
+
+    fn readdir(&self, id: Self::Path) -> Result<ReadDir> {
+        let dir = self.lookup(&id)?;
+        if dir.kind != FileType::Dir {
+            return Err(OsError::NotDir);
+        }
+        let entries = self
+            .volume
+            .read_dir(&dir.chain)?
+            .into_iter()
+            .map(|entry| DirEntry {
+                kind: entry.kind(),
+                name: entry.name,
+                inum: entry.inum,
+            })
+            .collect();
+        Ok(ReadDir::new(entries))
+    }
+
+    /// Free space is counted in the FAT. FAT has no inodes, a file only takes
+    /// its directory entry.
+    fn statfs(&self) -> Result<StatFs> {
+        let volume = &self.volume;
+        let (mut free, mut run, mut largest) = (0, 0, 0);
+        let mut cache = FatCache::default();
+        for cluster in 2..volume.clusters + 2 {
+            if volume.fat_entry(cluster, &mut cache)? == 0 {
+                free += 1;
+                run += 1;
+                largest = largest.max(run);
+            } else {
+                run = 0;
+            }
+        }
+
+        let per_cluster = volume.sectors_per_cluster as usize;
+        Ok(StatFs {
+            blocks: volume.total_sectors as usize,
+            free_blocks: free * per_cluster,
+            largest_free: largest * per_cluster,
+            ..StatFs::default()
+        })
+    }
+}
+
+impl FatFs {
+    fn lookup(&self, path: &str) -> Result<Arc<FatNode>> {
+        components(path).try_fold(self.root.clone(), |dir, name| {
+            if dir.kind != FileType::Dir {
+                return Err(OsError::NotDir);
+            }
+            let entry = self
+                .volume
+                .read_dir(&dir.chain)?
+                .into_iter()
+                .find(|entry| entry.name.eq_ignore_ascii_case(name))
+                .ok_or(OsError::NoSuchFile)?;
+            self.node(&entry)
+        })
+    }
+
+    /// The node of the file `entry` names, shared while it's in use.
+    fn node(&self, entry: &Entry) -> Result<Arc<FatNode>> {
+        let mut nodes = self.nodes.lock();
+        if let Some(node) = nodes.get(&entry.inum).and_then(Weak::upgrade) {
+            return Ok(node);
+        }
+        let node = FatNode::new(&self.volume, entry)?;
+        nodes.retain(|_, node| node.strong_count() > 0);
+        nodes.insert(entry.inum, Arc::downgrade(&node));
+        Ok(node)
+    }
+}
+
+/// Names in `path`.
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/')
+        .filter(|name| !name.is_empty() && *name != ".")
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                   Volume                                   */
+/* -------------------------------------------------------------------------- */
+
+/// FAT entries from this on end a cluster chain.
+const FAT_EOC: u32 = 0x0fff_fff8;
+/// The upper 4 bits of a FAT entry are reserved.
+const FAT_MASK: u32 = 0x0fff_ffff;
+
+/// Layout of the file system, from its boot sector.
+struct Volume {
+    sectors_per_cluster: u32,
+    /// First sector of the first FAT. Other copies are ignored.
+    fat_start: u32,
+    /// First sector of cluster 2, the first data cluster.
+    data_start: u32,
+    /// Number of data clusters.
+    clusters: u32,
+    root_cluster: u32,
+    total_sectors: u32,
+}
+
+impl Volume {
+    /// Read the boot sector of a disk with `capacity` sectors.
+    ///
+    /// # Errors
+    /// [`OsError::UnknownFormat`] if there isn't a FAT32 file system on it,
+    /// or it doesn't use 512-byte sectors.
+    fn load(capacity: u64) -> Result<Self> {
+        let mut boot = [0; SECTOR_SIZE];
+        Virtio::read_sector(0, &mut boot)?;
+        let u16_at = |off: usize| u16::from_le_bytes([boot[off], boot[off + 1]]) as u32;
+        let u32_at = |off: usize| u32::from_le_bytes(boot[off..off + 4].try_into().unwrap());
+
+        let sectors_per_cluster = boot[13] as u32;
+        let reserved = u16_at(14);
+        let fats = boot[16] as u32;
+        let total_sectors = match u16_at(19) {
+            0 => u32_at(32),
+            total => total,
+        };
+        let fat_len = u32_at(36);
+        // Only FAT32 has no fixed root dir and no 16-bit FAT length.
+        if boot[510..] != [0x55, 0xaa]
+            || u16_at(11) != SECTOR_SIZE as u32
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved == 0
+            || fats == 0
+            || u16_at(17) != 0
+            || u16_at(22) != 0
+            || fat_len == 0
+            || total_sectors as u64 > capacity
+        {
+            return Err(OsError::UnknownFormat);
+        }
+
+        let data_start = fats
+            .checked_mul(fat_len)
+            .and_then(|len| len.checked_add(reserved))
+            .filter(|start| *start < total_sectors)
+            .ok_or(OsError::UnknownFormat)?;
+        // Clusters past the end of the FAT can't be used either.
+        let clusters = min(
+            (total_sectors - data_start) / sectors_per_cluster,
+            fat_len
+                .saturating_mul((SECTOR_SIZE / 4) as u32)
+                .saturating_sub(2),
+        );
+        Ok(Self {
+            sectors_per_cluster,
+            fat_start: reserved,
+            data_start,
+            clusters,
+            root_cluster: u32_at(44),
+            total_sectors,
+        })
+    }
+
+    fn cluster_len(&self) -> usize {
+        self.sectors_per_cluster as usize * SECTOR_SIZE
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        self.data_start as u64 + (cluster - 2) as u64 * self.sectors_per_cluster as u64
+    }
+
+    /// The FAT entry of `cluster`, read through `cache`.
+    fn fat_entry(&self, cluster: u32, cache: &mut FatCache) -> Result<u32> {
+        let off = cluster as usize * 4;
+        let sector = self.fat_start as u64 + (off / SECTOR_SIZE) as u64;
+        if cache.sector != Some(sector) {
+            Virtio::read_sector(sector, &mut cache.buf)?;
+            cache.sector = Some(sector);
+        }
+        let off = off % SECTOR_SIZE;
+        Ok(u32::from_le_bytes(cache.buf[off..off + 4].try_into().unwrap()) & FAT_MASK)
+    }
+
+    /// Clusters in the chain from `start`, none if it's 0.
+    ///
+    /// # Errors
+    /// [`OsError::UnknownFormat`] if the chain is broken or loops.
+    fn chain(&self, start: u32) -> Result<Vec<u32>> {
+        let mut chain = Vec::new();
+        let mut cluster = start;
+        let mut cache = FatCache::default();
+        if start == 0 {
+            return Ok(chain);
+        }
+        loop {
+            if cluster < 2 || cluster - 2 >= self.clusters || chain.len() == self.clusters as usize
+            {
+                return Err(OsError::UnknownFormat);
+            }
+            chain.push(cluster);
+            cluster = self.fat_entry(cluster, &mut cache)?;
+            if cluster >= FAT_EOC {
+                return Ok(chain);
+            }
+        }
+    }
+
+    /// Read `buf.len()` bytes at `off` of the content in `chain`, which must
+    /// have that many.
+    fn read(&self, chain: &[u32], buf: &mut [u8], off: usize) -> Result<()> {
+        let mut sector_buf = [0; SECTOR_SIZE];
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = off + done;
+            let cluster = chain[pos / self.cluster_len()];
+            let in_cluster = pos % self.cluster_len();
+            let sector = self.cluster_sector(cluster) + (in_cluster / SECTOR_SIZE) as u64;
+            let in_sector = pos % SECTOR_SIZE;
+            let cnt = min(SECTOR_SIZE - in_sector, buf.len() - done);
+            Virtio::read_sector(sector, &mut sector_buf)?;
+            buf[done..done + cnt].copy_from_slice(&sector_buf[in_sector..in_sector + cnt]);
+            done += cnt;
+        }
+        Ok(())
+    }
+
+    /// Entries of the directory in `chain`, without `.`, `..` and the volume
+    /// label.
+    fn read_dir(&self, chain: &[u32]) -> Result<Vec<Entry>> {
+        let mut entries = Vec::new();
+        let mut long = LongName::default();
+        let mut buf = [0; SECTOR_SIZE];
+        for &cluster in chain {
+            for i in 0..self.sectors_per_cluster as u64 {
+                let sector = self.cluster_sector(cluster) + i;
+                Virtio::read_sector(sector, &mut buf)?;
+                for (j, raw) in buf.chunks(ENTRY_LEN).enumerate() {
+                    match raw[0] {
+                        // No entries after this one.
+                        0 => return Ok(entries),
+                        // A deleted one.
+                        0xe5 => {
+                            long.clear();
+                            continue;
+                        }
+                        _ => {}
+                    }
+                    let attr = raw[11];
+                    if attr & ATTR_LONG_MASK == ATTR_LONG_NAME {
+                        long.push(raw);
+                        continue;
+                    }
+
+                    let name = long
+                        .take(short_checksum(&raw[..11]))
+                        .unwrap_or_else(|| short_name(raw));
+                    if attr & ATTR_VOLUME_ID != 0 || name == "." || name == ".." {
+                        continue;
+                    }
+                    let u16_at = |off: usize| u16::from_le_bytes([raw[off], raw[off + 1]]) as u32;
+                    entries.push(Entry {
+                        name,
+                        inum: sector as usize * (SECTOR_SIZE / ENTRY_LEN) + j,
+                        attr,
+                        cluster: u16_at(20) << 16 | u16_at(26),
+                        size: u32::from_le_bytes(raw[28..32].try_into().unwrap()),
+                    });
+                }
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// The FAT sector read last, as walking a chain mostly reads the same one.
+struct FatCache {
+    sector: Option<u64>,
+    buf: [u8; SECTOR_SIZE],
+}
+
+impl Default for FatCache {
+    fn default() -> Self {
+        Self {
+            sector: None,
+            buf: [0; SECTOR_SIZE],
+        }
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                 Directories                                */
+/* -------------------------------------------------------------------------- */
+
+const ENTRY_LEN: usize = 32;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+/// Long name entries have all of the lower 4 attribute bits.
+const ATTR_LONG_NAME: u8 = 0x0f;
+const ATTR_LONG_MASK: u8 = 0x3f;
+
+/// Offsets of the 13 UCS-2 characters in a long name entry.
+const LONG_NAME_CHARS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// A file in a directory.
+struct Entry {
+    name: String,
+    inum: usize,
+    attr: u8,
+    /// First cluster, 0 for an empty file.
+    cluster: u32,
+    size: u32,
+}
+
+impl Entry {
+    fn kind(&self) -> FileType {
+        match self.attr & ATTR_DIRECTORY {
+            0 => FileType::File,
+            _ => FileType::Dir,
+        }
+    }
+}
+
+/// A long name, collected from the entries before the short one it belongs to.
+///
+/// The entries come last part first, numbered down to 1, and each has the
+/// checksum of the short name.
+#[derive(Default)]
+struct LongName {
+    chars: Vec<u16>,
+    checksum: u8,
+    /// Number of the part expected next, `Some(0)` once all are there, or
+    /// `None` if there isn't a valid name.
+    next: Option<u8>,
+}
+
+impl LongName {
+    fn push(&mut self, raw: &[u8]) {
+        let order = raw[0] & 0x1f;
+        let expected = if raw[0] & 0x40 != 0 {
+            self.chars = vec![0xffff; order as usize * LONG_NAME_CHARS.len()];
+            self.checksum = raw[13];
+            Some(order)
+        } else {
+            self.next.filter(|_| raw[13] == self.checksum)
+        };
+        if order == 0 || expected != Some(order) {
+            self.clear();
+            return;
+        }
+
+        let at = (order as usize - 1) * LONG_NAME_CHARS.len();
+        for (i, off) in LONG_NAME_CHARS.iter().enumerate() {
+            self.chars[at + i] = u16::from_le_bytes([raw[*off], raw[off + 1]]);
+        }
+        self.next = Some(order - 1);
+    }
+
+    fn clear(&mut self) {
+        self.next = None;
+    }
+
+    /// The name, if it's complete and belongs to the short name of `checksum`.
+    fn take(&mut self, checksum: u8) -> Option<String> {
+        if self.next.take() != Some(0) || checksum != self.checksum {
+            return None;
+        }
+        let len = self
+            .chars
+            .iter()
+            .position(|c| *c == 0 || *c == 0xffff)
+            .unwrap_or(self.chars.len());
+        let name = char::decode_utf16(self.chars[..len].iter().copied())
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+        Some(name)
+    }
+}
+
+/// Checksum of an 8.3 name, kept in the long name entries of the file.
+fn short_checksum(short: &[u8]) -> u8 {
+    short
+        .iter()
+        .fold(0u8, |sum, byte| sum.rotate_right(1).wrapping_add(*byte))
+}
+
+/// The 8.3 name of an entry, in lower case where its flags say so.
+fn short_name(raw: &[u8]) -> String {
+    let trim = |bytes: &[u8]| {
+        let len = bytes.iter().rposition(|b| *b != b' ').map_or(0, |i| i + 1);
+        bytes[..len].to_vec()
+    };
+    let mut base = trim(&raw[..8]);
+    let mut ext = trim(&raw[8..11]);
+    // A leading 0xe5 is stored as 0x05, since 0xe5 marks deleted entries.
+    if base.first() == Some(&0x05) {
+        base[0] = 0xe5;
+    }
+    if raw[12] & 0x08 != 0 {
+        base.make_ascii_lowercase();
+    }
+    if raw[12] & 0x10 != 0 {
+        ext.make_ascii_lowercase();
+    }
+
+    let mut name = String::from_utf8_lossy(&base).into_owned();
+    if !ext.is_empty() {
+        name.push('.');
+        name.push_str(&String::from_utf8_lossy(&ext));
+    }
+    name
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Vnode                                   */
+/* -------------------------------------------------------------------------- */
+
+struct FatNode {
+    volume: Arc<Volume>,
+    inum: usize,
+    kind: FileType,
+    /// Length of a file. Directories take all of their clusters.
+    len: usize,
+    chain: Vec<u32>,
+    locks: FileLocks,
+}
+
+impl FatNode {
+    /// # Errors
+    /// [`OsError::BadDirEntry`] if the file is longer than its clusters.
+    fn new(volume: &Arc<Volume>, entry: &Entry) -> Result<Arc<Self>> {
+        let chain = volume.chain(entry.cluster)?;
+        let len = match entry.kind() {
+            FileType::Dir => 0,
+            _ => entry.size as usize,
+        };
+        if len > chain.len() * volume.cluster_len() {
+            return Err(OsError::BadDirEntry);
+        }
+        Ok(Arc::new(Self {
+            volume: volume.clone(),
+            inum: entry.inum,
+            kind: entry.kind(),
+            len,
+            chain,
+            locks: FileLocks::default(),
+        }))
+    }
+}
+
+impl Vnode for FatNode {
+    fn read_at(&self, buf: &mut [u8], off: usize) -> Result<usize> {
+        let len = self.len();
+        if off >= len {
+            return Ok(0);
+        }
+        let cnt = min(buf.len(), len - off);
+        self.volume.read(&self.chain, &mut buf[..cnt], off)?;
+        Ok(cnt)
+    }
+
+    fn write_at(&self, _buf: &[u8], _off: usize) -> Result<usize> {
+        Err(OsError::ReadOnlyFs)
+    }
+
+    fn deny_write(&self) {}
+    fn allow_write(&self) {}
+
+    fn inum(&self) -> usize {
+        self.inum
+    }
+
+    fn len(&self) -> usize {
+        match self.kind {
+            FileType::Dir => self.chain.len() * self.volume.cluster_len(),
+            _ => self.len,
+        }
+    }
+
+    /// FAT keeps dates, not times since boot, so times are all 0.
+    fn stat(&self) -> Stat {
+        Stat {
+            inum: self.inum,
+            kind: self.kind,
+            mode: match self.kind {
+                FileType::Dir => 0o555,
+                _ => 0o444,
+            },
+            nlink: 1,
+            size: self.len(),
+            blocks: self.chain.len() * self.volume.sectors_per_cluster as usize,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
+        }
+    }
+
+    fn resize(&self, _size: usize) -> Result<()> {
+        Err(OsError::ReadOnlyFs)
+    }
+
+    fn close(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn punch_hole(&self, _off: usize, _len: usize) -> Result<()> {
+        Err(OsError::ReadOnlyFs)
+    }
+
+    fn locks(&self) -> Option<&FileLocks> {
+        Some(&self.locks)
+    }
+}
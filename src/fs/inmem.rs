@@ -1,8 +1,12 @@
-use alloc::boxed::Box;
-use alloc::sync::Weak;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
 use alloc::vec::Vec;
 use core::cmp::min;
+use core::mem;
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering::SeqCst};
 
+use crate::mem::malloc::Heap;
+use crate::sbi::timer::time_ms;
 use crate::{OsError, Result};
 
 use super::*;
@@ -11,108 +15,444 @@ use super::*;
 /*                                   FileSys                                  */
 /* -------------------------------------------------------------------------- */
 
-/// An in-memory pseudo file system, wrapping memory buffers
-/// with file-like interfaces.
+/// Inumber of the root directory.
+const ROOT_INUM: usize = 1;
+
+/// Largest file, so one file can't take the whole kernel heap.
+const FILE_SIZE_MAX: usize = 8 << 20;
+
+/// An in-memory file system, a tmpfs.
+///
+/// Files and directories live in the kernel heap and are gone once
+/// unmounted. Paths are relative to the root, whether or not they
+/// start with `/`.
 ///
-/// # Panic
-/// This struct only support [`FileSys::open()`], which
-/// copy and wrap a byte buffer into a [`File`]. Calls to
-/// [`FileSys::close()`], [`FileSys::create()`] and
-/// [`FileSys::remove()`] will panic.
+/// ## Examples
+/// ```ignore
+/// let fs = MemFs::mount(())?;
+/// fs.mkdir("/dir".into())?;
+/// let mut file = fs.create("/dir/file".into())?;
+/// file.write_all(b"scratch")?;
+/// ```
 pub struct MemFs {
-    oft: Mutex<Vec<Weak<Inode>>>,
+    root: Arc<Inode>,
+    /// Serializes lookups and changes to the tree, so a lookup never sees
+    /// a half done rename.
+    tree: Mutex<()>,
+    next_inum: AtomicUsize,
 }
 
 impl FileSys for MemFs {
     type Device = ();
-    type Path = Box<[u8]>;
+    type Path = String;
 
     fn mount(_device: Self::Device) -> Result<Self> {
         Ok(Self {
-            oft: Mutex::new(Vec::new()),
+            root: Arc::new(Inode::new(ROOT_INUM, FileType::Dir)),
+            tree: Mutex::new(()),
+            next_inum: AtomicUsize::new(ROOT_INUM + 1),
         })
     }
 
-    fn unmount(&self) {
-        unimplemented!();
+    /// Drop all files. The ones still opened stay readable until closed.
+    fn unmount(&self) -> Result<()> {
+        let _tree = self.tree.lock();
+        if let Data::Dir(ref mut entries) = *self.root.data.lock() {
+            entries.clear();
+        }
+        Ok(())
     }
 
     fn open(&self, id: Self::Path) -> Result<File> {
-        let buf = Mutex::new(id);
-        let vnode = Arc::new(Inode { buf });
-        let weak = Arc::downgrade(&vnode);
-        self.oft.lock().push(weak);
+        let _tree = self.tree.lock();
+        Ok(File::new(self.lookup(&id)?))
+    }
 
-        Ok(File::new(vnode))
+    fn close(&self, _file: File) {}
+
+    /// Create an empty file, or truncate the existing one.
+    fn create(&self, id: Self::Path) -> Result<File> {
+        let _tree = self.tree.lock();
+        let (dir, name) = self.parent(&id)?;
+        if let Ok(inode) = dir.child(&name) {
+            if inode.kind == FileType::Dir {
+                return Err(OsError::IsDir);
+            }
+            inode.resize(0)?;
+            return Ok(File::new(inode));
+        }
+        let inode = self.new_inode(FileType::File);
+        dir.insert(&name, inode.clone())?;
+        Ok(File::new(inode))
     }
 
-    fn close(&self, _file: File) {
-        unimplemented!();
+    /// Remove a file or an empty directory.
+    fn remove(&self, id: Self::Path) -> Result<()> {
+        let _tree = self.tree.lock();
+        let (dir, name) = self.parent(&id)?;
+        let inode = dir.child(&name)?;
+        if !inode.is_empty_dir()? {
+            return Err(OsError::DirNotEmpty);
+        }
+        dir.remove(&name)?;
+        inode.nlink.fetch_sub(1, SeqCst);
+        Ok(())
     }
 
-    fn create(&self, _id: Self::Path) -> Result<File> {
-        unimplemented!();
+    /// Move `from` to `to`, replacing the file at `to` if any.
+    fn rename(&self, from: Self::Path, to: Self::Path) -> Result<()> {
+        let _tree = self.tree.lock();
+        let (from_dir, from_name) = self.parent(&from)?;
+        let (to_dir, to_name) = self.parent(&to)?;
+        let inode = from_dir.child(&from_name)?;
+
+        // A directory can't be moved into itself.
+        if inode.kind == FileType::Dir {
+            let mut dir = self.root.clone();
+            for name in components(&to).take(components(&to).count() - 1) {
+                if Arc::ptr_eq(&dir, &inode) {
+                    return Err(OsError::InvalidArgument);
+                }
+                dir = dir.child(name)?;
+            }
+            if Arc::ptr_eq(&dir, &inode) {
+                return Err(OsError::InvalidArgument);
+            }
+        }
+
+        let replaced = match to_dir.child(&to_name) {
+            Ok(old) if Arc::ptr_eq(&old, &inode) => return Ok(()),
+            Ok(old) => Some(old),
+            Err(_) => None,
+        };
+        if let Some(old) = &replaced {
+            match (inode.kind == FileType::Dir, old.kind == FileType::Dir) {
+                (false, true) => return Err(OsError::IsDir),
+                (true, false) => return Err(OsError::NotDir),
+                (true, true) if !old.is_empty_dir()? => return Err(OsError::DirNotEmpty),
+                _ => {}
+            }
+            to_dir.remove(&to_name)?;
+            old.nlink.fetch_sub(1, SeqCst);
+        }
+        to_dir.insert(&to_name, inode)?;
+        from_dir.remove(&from_name)?;
+        Ok(())
+    }
+
+    /// Create an empty directory at `path`.
+    ///
+    /// # Errors
+    /// [`OsError::CreateExistInode`] if `path` exists.
+    fn mkdir(&self, path: Self::Path) -> Result<()> {
+        let _tree = self.tree.lock();
+        let (dir, name) = self.parent(&path)?;
+        if dir.child(&name).is_ok() {
+            return Err(OsError::CreateExistInode);
+        }
+        dir.insert(&name, self.new_inode(FileType::Dir))
     }
 
-    fn remove(&self, _id: Self::Path) -> Result<()> {
-        unimplemented!();
+    fn readdir(&self, id: Self::Path) -> Result<ReadDir> {
+        let _tree = self.tree.lock();
+        let dir = self.lookup(&id)?;
+        let entries = match *dir.data.lock() {
+            Data::Dir(ref entries) => entries
+                .iter()
+                .map(|(name, inode)| DirEntry {
+                    name: name.clone(),
+                    inum: inode.inum,
+                    kind: inode.kind,
+                })
+                .collect(),
+            Data::File(_) => return Err(OsError::NotDir),
+        };
+        Ok(ReadDir::new(entries))
+    }
+
+    /// Files live in the kernel heap, so the free space is what the heap has
+    /// left. It isn't contiguous, but any part of it can hold a file.
+    fn statfs(&self) -> Result<StatFs> {
+        let _tree = self.tree.lock();
+        let (mut inodes, mut blocks) = (0, 0);
+        let mut dirs = vec![self.root.clone()];
+        while let Some(dir) = dirs.pop() {
+            inodes += 1;
+            if let Data::Dir(ref entries) = *dir.data.lock() {
+                for inode in entries.values() {
+                    match *inode.data.lock() {
+                        Data::Dir(_) => dirs.push(inode.clone()),
+                        Data::File(ref data) => {
+                            inodes += 1;
+                            blocks += (data.len() + 511) / 512;
+                        }
+                    }
+                }
+            }
+        }
+
+        let free = Heap::get().free();
+        Ok(StatFs {
+            blocks: blocks + free / 512,
+            free_blocks: free / 512,
+            largest_free: free / 512,
+            inodes,
+            free_inodes: free / mem::size_of::<Inode>(),
+            swap_blocks: 0,
+        })
+    }
+}
+
+impl MemFs {
+    fn new_inode(&self, kind: FileType) -> Arc<Inode> {
+        Arc::new(Inode::new(self.next_inum.fetch_add(1, SeqCst), kind))
+    }
+
+    fn lookup(&self, path: &str) -> Result<Arc<Inode>> {
+        components(path).try_fold(self.root.clone(), |dir, name| dir.child(name))
+    }
+
+    /// The directory `path` is in, and its last name.
+    fn parent(&self, path: &str) -> Result<(Arc<Inode>, String)> {
+        let name = components(path)
+            .next_back()
+            .ok_or(OsError::InvalidArgument)?;
+        let count = components(path).count();
+        let dir = components(path)
+            .take(count - 1)
+            .try_fold(self.root.clone(), |dir, name| dir.child(name))?;
+        if dir.kind != FileType::Dir {
+            return Err(OsError::NotDir);
+        }
+        Ok((dir, String::from(name)))
     }
 }
 
+/// Names in `path`.
+fn components(path: &str) -> impl DoubleEndedIterator<Item = &str> {
+    path.split('/')
+        .filter(|name| !name.is_empty() && *name != ".")
+}
+
 /* -------------------------------------------------------------------------- */
 /*                                    Inode                                   */
 /* -------------------------------------------------------------------------- */
 
-// TODO: should it be pub or not
+enum Data {
+    File(Vec<u8>),
+    Dir(BTreeMap<String, Arc<Inode>>),
+}
+
+/// Truncate or zero-extend `data` to `len` bytes.
+///
+/// # Errors
+/// [`OsError::FileTooLarge`] if `len` is over [`FILE_SIZE_MAX`], or the heap
+/// has no room for it.
+fn resize_file(data: &mut Vec<u8>, len: usize) -> Result<()> {
+    if len > FILE_SIZE_MAX {
+        return Err(OsError::FileTooLarge);
+    }
+    if len > data.len() {
+        data.try_reserve_exact(len - data.len())
+            .map_err(|_| OsError::FileTooLarge)?;
+    }
+    data.resize(len, 0);
+    Ok(())
+}
+
 struct Inode {
-    buf: Mutex<Box<[u8]>>,
+    inum: usize,
+    kind: FileType,
+    data: Mutex<Data>,
+    nlink: AtomicU32,
+    deny_write: AtomicUsize,
+    locks: FileLocks,
+    atime: AtomicU64,
+    mtime: AtomicU64,
+    ctime: u64,
+}
+
+impl Inode {
+    fn new(inum: usize, kind: FileType) -> Self {
+        let data = match kind {
+            FileType::Dir => Data::Dir(BTreeMap::new()),
+            _ => Data::File(Vec::new()),
+        };
+        let now = time_ms() as u64;
+        Self {
+            inum,
+            kind,
+            data: Mutex::new(data),
+            nlink: AtomicU32::new(1),
+            deny_write: AtomicUsize::new(0),
+            locks: FileLocks::default(),
+            atime: AtomicU64::new(now),
+            mtime: AtomicU64::new(now),
+            ctime: now,
+        }
+    }
+
+    /// The entry of given name in this directory.
+    fn child(&self, name: &str) -> Result<Arc<Inode>> {
+        match *self.data.lock() {
+            Data::Dir(ref entries) => entries.get(name).cloned().ok_or(OsError::NoSuchFile),
+            Data::File(_) => Err(OsError::NotDir),
+        }
+    }
+
+    /// # Errors
+    /// [`OsError::CstrFormatErr`] if the name is empty, `.`, `..` or has a `/`.
+    fn insert(&self, name: &str, inode: Arc<Inode>) -> Result<()> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(OsError::CstrFormatErr);
+        }
+        match *self.data.lock() {
+            Data::Dir(ref mut entries) => entries.insert(String::from(name), inode),
+            Data::File(_) => return Err(OsError::NotDir),
+        };
+        self.mtime.store(time_ms() as u64, SeqCst);
+        Ok(())
+    }
+
+    fn remove(&self, name: &str) -> Result<Arc<Inode>> {
+        let inode = match *self.data.lock() {
+            Data::Dir(ref mut entries) => entries.remove(name).ok_or(OsError::NoSuchFile)?,
+            Data::File(_) => return Err(OsError::NotDir),
+        };
+        self.mtime.store(time_ms() as u64, SeqCst);
+        Ok(inode)
+    }
+
+    /// Whether this is a file, or a directory without entries.
+    fn is_empty_dir(&self) -> Result<bool> {
+        match *self.data.lock() {
+            Data::Dir(ref entries) => Ok(entries.is_empty()),
+            Data::File(_) => Ok(true),
+        }
+    }
+
+    /// Write `buf` at `off`, or at the end if `off` is `None`, returning
+    /// where it was written and the bytes written.
+    fn write_inner(&self, buf: &[u8], off: Option<usize>) -> Result<(usize, usize)> {
+        if self.deny_write.load(SeqCst) > 0 {
+            return Err(OsError::InvalidFileMode);
+        }
+        // Protect during the whole process.
+        let mut lock = self.data.lock();
+        let data = match *lock {
+            Data::File(ref mut data) => data,
+            Data::Dir(_) => return Err(OsError::IsDir),
+        };
+        let off = off.unwrap_or(data.len());
+        let end = off.checked_add(buf.len()).ok_or(OsError::FileTooLarge)?;
+        if data.len() < end {
+            resize_file(data, end)?;
+        }
+
+        data[off..off + buf.len()].copy_from_slice(buf);
+        self.mtime.store(time_ms() as u64, SeqCst);
+        Ok((off, buf.len()))
+    }
 }
 
 impl Vnode for Inode {
     fn inum(&self) -> usize {
-        self.buf.lock().as_ptr() as usize
+        self.inum
     }
 
     fn len(&self) -> usize {
-        self.buf.lock().len()
+        match *self.data.lock() {
+            Data::File(ref buf) => buf.len(),
+            Data::Dir(_) => 0,
+        }
+    }
+
+    fn stat(&self) -> Stat {
+        Stat {
+            inum: self.inum,
+            kind: self.kind,
+            mode: self.kind.default_mode(),
+            nlink: self.nlink.load(SeqCst),
+            size: self.len(),
+            blocks: (self.len() + 511) / 512,
+            atime: self.atime.load(SeqCst),
+            mtime: self.mtime.load(SeqCst),
+            ctime: self.ctime,
+        }
     }
 
     fn read_at(&self, buf: &mut [u8], off: usize) -> Result<usize> {
         // Protect during the whole process.
-        let lock = self.buf.lock();
-        if off >= lock.len() {
-            return Err(OsError::UnexpectedEOF);
+        let lock = self.data.lock();
+        let data = match *lock {
+            Data::File(ref data) => data,
+            Data::Dir(_) => return Err(OsError::IsDir),
+        };
+        if off >= data.len() {
+            return Ok(0);
         }
 
-        let len = min(lock.len() - off, buf.len());
+        let len = min(data.len() - off, buf.len());
 
-        buf[..len].copy_from_slice(&lock[off..off + len]);
+        buf[..len].copy_from_slice(&data[off..off + len]);
+        self.atime.store(time_ms() as u64, SeqCst);
         Ok(len)
     }
 
+    /// Write `buf` at `off`, growing the file if needed.
     fn write_at(&self, buf: &[u8], off: usize) -> Result<usize> {
-        // Protect during the whole process.
-        let mut lock = self.buf.lock();
-        if off >= lock.len() {
-            return Err(OsError::UnexpectedEOF);
+        Ok(self.write_inner(buf, Some(off))?.1)
+    }
+
+    fn append(&self, buf: &[u8]) -> Result<(usize, usize)> {
+        self.write_inner(buf, None)
+    }
+
+    /// Truncate or zero-extend the file to `size`.
+    fn resize(&self, size: usize) -> Result<()> {
+        if self.deny_write.load(SeqCst) > 0 {
+            return Err(OsError::InvalidFileMode);
+        }
+        match *self.data.lock() {
+            Data::File(ref mut data) => resize_file(data, size)?,
+            Data::Dir(_) => return Err(OsError::IsDir),
         }
+        self.mtime.store(time_ms() as u64, SeqCst);
+        Ok(())
+    }
 
-        let len = min(lock.len() - off, buf.len());
+    /// The range is zeroed, its space isn't freed.
+    fn punch_hole(&self, off: usize, len: usize) -> Result<()> {
+        if self.deny_write.load(SeqCst) > 0 {
+            return Err(OsError::InvalidFileMode);
+        }
+        match *self.data.lock() {
+            Data::File(ref mut data) => {
+                let end = min(off.saturating_add(len), data.len());
+                if off < end {
+                    data[off..end].fill(0);
+                }
+            }
+            Data::Dir(_) => return Err(OsError::IsDir),
+        }
+        self.mtime.store(time_ms() as u64, SeqCst);
+        Ok(())
+    }
 
-        let nb = &mut lock[off..off + len];
-        nb.copy_from_slice(&buf[..len]);
-        Ok(len)
+    /// Content is freed with the last [`Arc`] of the inode.
+    fn close(&self) -> Result<()> {
+        Ok(())
     }
 
-    fn resize(&self, _size: usize) -> Result<()> {
-        unimplemented!();
+    fn locks(&self) -> Option<&FileLocks> {
+        Some(&self.locks)
     }
 
-    fn close(&self) {
-        unimplemented!();
+    fn deny_write(&self) {
+        self.deny_write.fetch_add(1, SeqCst);
     }
 
-    // TODO: Impl deny write for mem file.
-    fn deny_write(&self) {}
-    fn allow_write(&self) {}
+    fn allow_write(&self) {
+        self.deny_write.fetch_sub(1, SeqCst);
+    }
 }
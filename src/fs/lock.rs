@@ -0,0 +1,122 @@
+//! Advisory file locks.
+//!
+//! A lock covers a byte range of a file and is either shared or exclusive.
+//! Locks belong to a [`File`] and its clones, and are released when the last
+//! of them is dropped. They are advisory: reads and writes ignore them.
+use alloc::vec::Vec;
+use core::cmp::{max, min};
+use core::ops::Range;
+
+use crate::sync::{Condvar, Mutex};
+use crate::{OsError, Result};
+
+#[cfg(doc)]
+use super::File;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockKind {
+    /// Any number of owners may hold shared locks on overlapping ranges.
+    Shared,
+    /// No one else may hold a lock overlapping an exclusive one.
+    Exclusive,
+}
+
+/// A lock held on `range` of a file.
+#[derive(Clone)]
+struct FileLock {
+    owner: usize,
+    kind: LockKind,
+    range: Range<usize>,
+}
+
+impl FileLock {
+    fn overlaps(&self, range: &Range<usize>) -> bool {
+        self.range.start < range.end && range.start < self.range.end
+    }
+}
+
+/// Locks held on a file, kept by its in-memory inode.
+pub struct FileLocks {
+    locks: Mutex<Vec<FileLock>>,
+    released: Condvar,
+}
+
+impl Default for FileLocks {
+    fn default() -> Self {
+        Self {
+            locks: Mutex::new(Vec::new()),
+            released: Condvar::new(),
+        }
+    }
+}
+
+impl FileLocks {
+    /// Lock `range` for `owner`, replacing the locks it holds in the range.
+    /// If someone else holds a conflicting lock, wait for it to be released
+    /// if `wait` is set.
+    ///
+    /// # Errors
+    /// [`OsError::WouldBlock`] if the range can't be locked now, and `wait`
+    /// isn't set.
+    pub fn lock(
+        &self,
+        owner: usize,
+        kind: LockKind,
+        range: Range<usize>,
+        wait: bool,
+    ) -> Result<()> {
+        let mut locks = self.locks.lock();
+        while locks.iter().any(|lock| {
+            lock.owner != owner
+                && lock.overlaps(&range)
+                && (lock.kind == LockKind::Exclusive || kind == LockKind::Exclusive)
+        }) {
+            if !wait {
+                return Err(OsError::WouldBlock);
+            }
+            self.released.wait(&mut locks);
+        }
+
+        Self::cut(&mut locks, owner, &range);
+        locks.push(FileLock { owner, kind, range });
+        // Turning an exclusive lock into a shared one may let others in.
+        self.released.notify_all();
+        Ok(())
+    }
+
+    /// Unlock `range` for `owner`. Parts of it that aren't locked are ignored.
+    pub fn unlock(&self, owner: usize, range: Range<usize>) {
+        let mut locks = self.locks.lock();
+        Self::cut(&mut locks, owner, &range);
+        self.released.notify_all();
+    }
+
+    /// Release all locks of `owner`.
+    pub fn release(&self, owner: usize) {
+        let mut locks = self.locks.lock();
+        locks.retain(|lock| lock.owner != owner);
+        self.released.notify_all();
+    }
+
+    /// Remove `range` from the locks of `owner`, splitting those partly in it.
+    fn cut(locks: &mut Vec<FileLock>, owner: usize, range: &Range<usize>) {
+        let mut kept = Vec::new();
+        for lock in locks.drain(..) {
+            if lock.owner != owner || !lock.overlaps(range) {
+                kept.push(lock);
+                continue;
+            }
+            let before = lock.range.start..min(lock.range.end, range.start);
+            let after = max(lock.range.start, range.end)..lock.range.end;
+            for part in [before, after] {
+                if !part.is_empty() {
+                    kept.push(FileLock {
+                        range: part,
+                        ..lock.clone()
+                    });
+                }
+            }
+        }
+        *locks = kept;
+    }
+}
@@ -0,0 +1,297 @@
+//! Process file system.
+//!
+//! Read-only files rendering kernel state as text, mounted at `/proc`:
+//!
+//! - `threads`: id, name, status, base and effective priority of each thread.
+//! - `meminfo`: usage of the kernel heap and the page allocators.
+//! - `uptime`: timer ticks and milliseconds since boot.
+//! - `interrupts`: traps handled since boot, by cause.
+//! - `<tid>/maps`: user mappings of a user thread.
+//!
+//! The content of a file is rendered when it's opened, and doesn't change
+//! while it stays open.
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cmp::min;
+use core::fmt::Write as _;
+use core::sync::atomic::Ordering::{Relaxed, SeqCst};
+
+use crate::mem::malloc::Heap;
+use crate::mem::palloc::UserPool;
+use crate::mem::{PTEFlags, Palloc};
+use crate::sbi::timer::{time_ms, timer_ticks};
+use crate::thread::{Manager, Thread};
+use crate::trap::COUNTERS;
+use crate::{OsError, Result};
+
+use super::*;
+
+/* -------------------------------------------------------------------------- */
+/*                                   FileSys                                  */
+/* -------------------------------------------------------------------------- */
+
+/// Inumber of the root directory.
+const ROOT_INUM: usize = 1;
+/// Inumbers of per-thread entries start from here, two for each thread.
+const TID_INUM_BASE: usize = 0x1000;
+
+/// Renders the content of a file.
+type Render = fn() -> String;
+
+/// Files in the root directory, and how to render them.
+const FILES: [(&str, Render); 4] = [
+    ("threads", threads),
+    ("meminfo", meminfo),
+    ("uptime", uptime),
+    ("interrupts", interrupts),
+];
+
+pub struct ProcFs;
+
+/// What a path in procfs refers to.
+enum Entry {
+    Root,
+    File(usize),
+    ThreadDir(Arc<Thread>),
+    Maps(Arc<Thread>),
+}
+
+impl ProcFs {
+    fn lookup(&self, path: &str) -> Result<Entry> {
+        let names: Vec<&str> = path
+            .split('/')
+            .filter(|name| !name.is_empty() && *name != ".")
+            .collect();
+        match names[..] {
+            [] => Ok(Entry::Root),
+            [name] => {
+                if let Some(idx) = FILES.iter().position(|(n, _)| *n == name) {
+                    return Ok(Entry::File(idx));
+                }
+                Ok(Entry::ThreadDir(user_thread(name)?))
+            }
+            [tid, "maps"] => Ok(Entry::Maps(user_thread(tid)?)),
+            _ => Err(OsError::NoSuchFile),
+        }
+    }
+}
+
+/// A thread with a user address space, by its id.
+fn user_thread(tid: &str) -> Result<Arc<Thread>> {
+    let tid: isize = tid.parse().map_err(|_| OsError::NoSuchFile)?;
+    Manager::get()
+        .all()
+        .into_iter()
+        .find(|t| t.id() == tid && t.pagetable.is_some())
+        .ok_or(OsError::NoSuchFile)
+}
+
+impl FileSys for ProcFs {
+    type Device = ();
+    type Path = String;
+
+    fn mount(_device: Self::Device) -> Result<Self> {
+        Ok(Self)
+    }
+
+    fn unmount(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn open(&self, id: Self::Path) -> Result<File> {
+        let (inum, content) = match self.lookup(&id)? {
+            Entry::Root | Entry::ThreadDir(_) => return Err(OsError::IsDir),
+            Entry::File(idx) => (ROOT_INUM + 1 + idx, (FILES[idx].1)()),
+            Entry::Maps(thread) => (TID_INUM_BASE + thread.id() as usize * 2 + 1, maps(&thread)),
+        };
+        Ok(File::new(Arc::new(Snapshot {
+            inum,
+            buf: content.into_bytes().into_boxed_slice(),
+            time: time_ms() as u64,
+        })))
+    }
+
+    fn close(&self, _file: File) {}
+
+    fn create(&self, id: Self::Path) -> Result<File> {
+        self.lookup(&id)?;
+        Err(OsError::InvalidArgument)
+    }
+
+    fn remove(&self, id: Self::Path) -> Result<()> {
+        self.lookup(&id)?;
+        Err(OsError::InvalidArgument)
+    }
+
+    fn rename(&self, from: Self::Path, _to: Self::Path) -> Result<()> {
+        self.lookup(&from)?;
+        Err(OsError::InvalidArgument)
+    }
+
+    fn readdir(&self, id: Self::Path) -> Result<ReadDir> {
+        let entries = match self.lookup(&id)? {
+            Entry::Root => {
+                let files = FILES.iter().enumerate().map(|(idx, (name, _))| DirEntry {
+                    name: name.to_string(),
+                    inum: ROOT_INUM + 1 + idx,
+                    kind: FileType::File,
+                });
+                let threads = Manager::get()
+                    .all()
+                    .into_iter()
+                    .filter(|t| t.pagetable.is_some())
+                    .map(|t| DirEntry {
+                        name: t.id().to_string(),
+                        inum: TID_INUM_BASE + t.id() as usize * 2,
+                        kind: FileType::Dir,
+                    });
+                files.chain(threads).collect()
+            }
+            Entry::ThreadDir(thread) => alloc::vec![DirEntry {
+                name: String::from("maps"),
+                inum: TID_INUM_BASE + thread.id() as usize * 2 + 1,
+                kind: FileType::File,
+            }],
+            Entry::File(_) | Entry::Maps(_) => return Err(OsError::NotDir),
+        };
+        Ok(ReadDir::new(entries))
+    }
+
+    /// Files are rendered on open, nothing is stored.
+    fn statfs(&self) -> Result<StatFs> {
+        Ok(StatFs::default())
+    }
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                  Rendering                                 */
+/* -------------------------------------------------------------------------- */
+
+fn threads() -> String {
+    let mut s = String::from("tid\tstatus\tpri\teff\tname\n");
+    for t in Manager::get().all() {
+        let _ = writeln!(
+            s,
+            "{}\t{:?}\t{}\t{}\t{}",
+            t.id(),
+            t.status(),
+            t.priority.load(SeqCst),
+            t.priority(),
+            t.name()
+        );
+    }
+    s
+}
+
+fn meminfo() -> String {
+    let heap = Heap::get();
+    format!(
+        "heap_total\t{}\nheap_allocated\t{}\nheap_free\t{}\n\
+         palloc_total\t{}\npalloc_allocated\t{}\n\
+         userpool_total\t{}\nuserpool_allocated\t{}\n",
+        heap.total(),
+        heap.allocated(),
+        heap.free(),
+        Palloc::total(),
+        Palloc::allocated(),
+        UserPool::total(),
+        UserPool::allocated(),
+    )
+}
+
+fn uptime() -> String {
+    format!("ticks\t{}\nms\t{}\n", timer_ticks(), time_ms())
+}
+
+fn interrupts() -> String {
+    format!(
+        "timer\t{}\nvirtio\t{}\nsyscall\t{}\npagefault\t{}\n",
+        COUNTERS.timer.load(Relaxed),
+        COUNTERS.virtio.load(Relaxed),
+        COUNTERS.syscall.load(Relaxed),
+        COUNTERS.pagefault.load(Relaxed),
+    )
+}
+
+/// One line per mapping: `start-end perms pa`.
+fn maps(thread: &Thread) -> String {
+    let mappings = match thread.pagetable {
+        Some(ref pt) => pt.lock().user_mappings(),
+        None => Vec::new(),
+    };
+    let mut s = String::new();
+    for (va, pa, size, flags) in mappings {
+        let perm = |flag, ch| if flags.contains(flag) { ch } else { '-' };
+        let _ = writeln!(
+            s,
+            "{:#010x}-{:#010x} {}{}{} {:#x}",
+            va,
+            va + size,
+            perm(PTEFlags::R, 'r'),
+            perm(PTEFlags::W, 'w'),
+            perm(PTEFlags::X, 'x'),
+            pa
+        );
+    }
+    s
+}
+
+/* -------------------------------------------------------------------------- */
+/*                                    Inode                                   */
+/* -------------------------------------------------------------------------- */
+
+/// Content of a procfs file, as it was when opened.
+struct Snapshot {
+    inum: usize,
+    buf: Box<[u8]>,
+    time: u64,
+}
+
+impl Vnode for Snapshot {
+    fn inum(&self) -> usize {
+        self.inum
+    }
+
+    fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    fn stat(&self) -> Stat {
+        Stat {
+            inum: self.inum,
+            kind: FileType::File,
+            mode: 0o444,
+            nlink: 1,
+            size: self.len(),
+            blocks: 0,
+            atime: self.time,
+            mtime: self.time,
+            ctime: self.time,
+        }
+    }
+
+    fn read_at(&self, buf: &mut [u8], off: usize) -> Result<usize> {
+        if off >= self.buf.len() {
+            return Ok(0);
+        }
+        let len = min(self.buf.len() - off, buf.len());
+        buf[..len].copy_from_slice(&self.buf[off..off + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, _buf: &[u8], _off: usize) -> Result<usize> {
+        Err(OsError::InvalidFileMode)
+    }
+
+    fn resize(&self, _size: usize) -> Result<()> {
+        Err(OsError::InvalidFileMode)
+    }
+
+    fn close(&self) -> Result<()> {
+        Ok(())
+    }
+    fn deny_write(&self) {}
+    fn allow_write(&self) {}
+}
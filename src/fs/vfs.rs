@@ -0,0 +1,324 @@
+//! Virtual file system.
+//!
+//! [`FileSys`] is generic over its path and device types, so different file
+//! systems can't be used through one interface. [`DynFileSys`] is the object
+//! safe counterpart, taking paths as `&str`, and [`VFS`] maps path prefixes
+//! to the file systems mounted there.
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Deref;
+
+use super::devfs::DevFs;
+use super::disk::DISKFS;
+use super::ext2::Ext2Fs;
+use super::fat::FatFs;
+use super::inmem::MemFs;
+use super::procfs::ProcFs;
+use super::{File, FileSys, FileType, OpenFlags, ReadDir, StatFs};
+use crate::device::virtio::Virtio;
+use crate::sync::{Lazy, Mutex};
+use crate::{OsError, Result};
+
+/// A mounted file system. Paths are relative to its mount point.
+pub trait DynFileSys: Sync + Send {
+    fn unmount(&self) -> Result<()>;
+    fn open(&self, path: &str) -> Result<File>;
+    fn create(&self, path: &str) -> Result<File>;
+    fn remove(&self, path: &str) -> Result<()>;
+    fn rename(&self, from: &str, to: &str) -> Result<()>;
+    fn mkdir(&self, path: &str) -> Result<()>;
+    fn readdir(&self, path: &str) -> Result<ReadDir>;
+    fn statfs(&self) -> Result<StatFs>;
+    fn sync(&self) -> Result<()>;
+    fn escape(&self, path: &str, follow: bool) -> Option<String>;
+}
+
+/// A [`FileSys`] behind a pointer, such as `&'static DiskFs` or `Box<MemFs>`,
+/// as a [`DynFileSys`].
+pub struct Mounted<P>(pub P);
+
+impl<P, T> DynFileSys for Mounted<P>
+where
+    P: Deref<Target = T> + Sync + Send,
+    T: FileSys,
+    for<'a> T::Path: From<&'a str>,
+{
+    fn unmount(&self) -> Result<()> {
+        self.0.unmount()
+    }
+
+    fn open(&self, path: &str) -> Result<File> {
+        self.0.open(path.into())
+    }
+
+    fn create(&self, path: &str) -> Result<File> {
+        self.0.create(path.into())
+    }
+
+    fn remove(&self, path: &str) -> Result<()> {
+        self.0.remove(path.into())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.0.rename(from.into(), to.into())
+    }
+
+    fn mkdir(&self, path: &str) -> Result<()> {
+        self.0.mkdir(path.into())
+    }
+
+    fn readdir(&self, path: &str) -> Result<ReadDir> {
+        self.0.readdir(path.into())
+    }
+
+    fn statfs(&self) -> Result<StatFs> {
+        self.0.statfs()
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.0.sync()
+    }
+
+    fn escape(&self, path: &str, follow: bool) -> Option<String> {
+        self.0.escape(path.into(), follow)
+    }
+}
+
+/// Global virtual file system, with [`DISKFS`] mounted at `/`, [`DevFs`] at
+/// `/dev`, [`ProcFs`] at `/proc` and a [`MemFs`] at `/tmp`.
+///
+/// A disk formatted as FAT32 or ext2 is mounted at `/` read-only, as
+/// [`FatFs`] or [`Ext2Fs`], instead of the disk fs.
+///
+/// # Usage
+/// ```ignore
+/// VFS.mount("/scratch", Arc::new(Mounted(Box::new(MemFs::mount(())?))))?;
+/// let file = VFS.create("/scratch/file")?;
+/// VFS.unmount("/scratch")?;
+/// ```
+pub static VFS: Lazy<Vfs> = Lazy::new(|| {
+    let vfs = Vfs {
+        mounts: Mutex::new(Vec::new()),
+        creating: Mutex::new(()),
+    };
+    vfs.mount("/", root_fs()).expect("Failed to mount root fs");
+    let devfs = DevFs::mount(()).expect("Failed to mount devfs");
+    vfs.mount("/dev", Arc::new(Mounted(Box::new(devfs))))
+        .expect("Failed to mount devfs");
+    let procfs = ProcFs::mount(()).expect("Failed to mount procfs");
+    vfs.mount("/proc", Arc::new(Mounted(Box::new(procfs))))
+        .expect("Failed to mount procfs");
+    let tmpfs = MemFs::mount(()).expect("Failed to mount tmpfs");
+    vfs.mount("/tmp", Arc::new(Mounted(Box::new(tmpfs))))
+        .expect("Failed to mount tmpfs");
+    vfs
+});
+
+/// The file system on the disk, probed for each format in turn.
+fn root_fs() -> Arc<dyn DynFileSys> {
+    if let Ok(fatfs) = FatFs::mount(Virtio::get()) {
+        return Arc::new(Mounted(Box::new(fatfs)));
+    }
+    if let Ok(ext2fs) = Ext2Fs::mount(Virtio::get()) {
+        return Arc::new(Mounted(Box::new(ext2fs)));
+    }
+    let diskfs: &'static _ = &*DISKFS;
+    Arc::new(Mounted(diskfs))
+}
+
+/// Maximum number of symlinks leading out of a file system followed in one lookup.
+const SYMLINK_DEPTH_MAX: usize = 8;
+
+/// A mount point, as names from `/`, and the file system mounted there.
+type Mount = (Vec<String>, Arc<dyn DynFileSys>);
+
+/// The mount table.
+pub struct Vfs {
+    mounts: Mutex<Vec<Mount>>,
+    /// Held while opening with [`OpenFlags::CREAT`], so two of them never
+    /// both create the file.
+    creating: Mutex<()>,
+}
+
+impl Vfs {
+    /// Mount `fs` at `path`, covering whatever was visible there.
+    ///
+    /// # Errors
+    /// [`OsError::CreateExistInode`] if something is mounted at `path` already.
+    pub fn mount(&self, path: &str, fs: Arc<dyn DynFileSys>) -> Result<()> {
+        let point = names(path);
+        let mut mounts = self.mounts.lock();
+        if mounts.iter().any(|(p, _)| *p == point) {
+            return Err(OsError::CreateExistInode);
+        }
+        mounts.push((point, fs));
+        Ok(())
+    }
+
+    /// Unmount the file system at `path`.
+    ///
+    /// # Errors
+    /// - [`OsError::NoSuchFile`]: nothing is mounted at `path`.
+    /// - Errors of the file system's own unmount, after which it's unmounted
+    ///   anyway, though maybe not cleanly.
+    pub fn unmount(&self, path: &str) -> Result<()> {
+        let point = names(path);
+        let fs = {
+            let mut mounts = self.mounts.lock();
+            let i = mounts
+                .iter()
+                .position(|(p, _)| *p == point)
+                .ok_or(OsError::NoSuchFile)?;
+            mounts.remove(i).1
+        };
+        fs.unmount()
+    }
+
+    /// Unmount everything, the most recently mounted first.
+    ///
+    /// # Errors
+    /// The first error of a file system's unmount. The rest are unmounted
+    /// anyway.
+    pub fn unmount_all(&self) -> Result<()> {
+        let mounts = core::mem::take(&mut *self.mounts.lock());
+        let mut result = Ok(());
+        for (_, fs) in mounts.into_iter().rev() {
+            let res = fs.unmount();
+            if result.is_ok() {
+                result = res;
+            }
+        }
+        result
+    }
+
+    pub fn open(&self, path: &str) -> Result<File> {
+        let (fs, path) = self.resolve(path, true)?;
+        fs.open(&path)
+    }
+
+    /// Open the file at `path` as `flags` tell.
+    ///
+    /// # Errors
+    /// - [`OsError::CreateExistInode`]: with `CREAT | EXCL`, the file exists.
+    /// - [`OsError::IsDir`]: opening a directory for writing.
+    pub fn open_with(&self, path: &str, flags: OpenFlags) -> Result<File> {
+        let (fs, path) = self.resolve(path, true)?;
+        let file = if flags.contains(OpenFlags::CREAT) {
+            let _creating = self.creating.lock();
+            match fs.open(&path) {
+                Ok(_) if flags.contains(OpenFlags::EXCL) => return Err(OsError::CreateExistInode),
+                Ok(file) => file,
+                Err(OsError::NoSuchFile) => fs.create(&path)?,
+                Err(e) => return Err(e),
+            }
+        } else {
+            fs.open(&path)?
+        };
+
+        let mut file = file.with_flags(flags);
+        if flags.writable() && file.stat().kind == FileType::Dir {
+            return Err(OsError::IsDir);
+        }
+        if flags.contains(OpenFlags::TRUNC) && flags.writable() {
+            file.set_len(0)?;
+        }
+        Ok(file)
+    }
+
+    pub fn create(&self, path: &str) -> Result<File> {
+        let (fs, path) = self.resolve(path, true)?;
+        fs.create(&path)
+    }
+
+    pub fn remove(&self, path: &str) -> Result<()> {
+        let (fs, path) = self.resolve(path, false)?;
+        fs.remove(&path)
+    }
+
+    /// # Errors
+    /// [`OsError::CrossDevice`] if `from` and `to` are in different file systems.
+    pub fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let (fs, from) = self.resolve(from, false)?;
+        let (to_fs, to) = self.resolve(to, false)?;
+        if !Arc::ptr_eq(&fs, &to_fs) {
+            return Err(OsError::CrossDevice);
+        }
+        fs.rename(&from, &to)
+    }
+
+    /// # Errors
+    /// [`OsError::CreateExistInode`] if `path` exists.
+    pub fn mkdir(&self, path: &str) -> Result<()> {
+        let (fs, path) = self.resolve(path, false)?;
+        fs.mkdir(&path)
+    }
+
+    pub fn readdir(&self, path: &str) -> Result<ReadDir> {
+        let (fs, path) = self.resolve(path, true)?;
+        fs.readdir(&path)
+    }
+
+    /// Capacity and usage of the file system `path` lies in.
+    pub fn statfs(&self, path: &str) -> Result<StatFs> {
+        self.resolve(path, true)?.0.statfs()
+    }
+
+    /// Sync every mounted file system.
+    pub fn sync(&self) -> Result<()> {
+        let mounts: Vec<_> = self
+            .mounts
+            .lock()
+            .iter()
+            .map(|(_, fs)| fs.clone())
+            .collect();
+        for fs in mounts {
+            fs.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Find the file system `path` lies in, and the path relative to it.
+    ///
+    /// A symlink to an absolute path is followed from the mount table, since
+    /// it may lead to another file system. So is the last name if `follow`.
+    ///
+    /// # Errors
+    /// [`OsError::SymlinkLoop`] if more than [`SYMLINK_DEPTH_MAX`] such symlinks are met.
+    fn resolve(&self, path: &str, follow: bool) -> Result<(Arc<dyn DynFileSys>, String)> {
+        let mut path = String::from(path);
+        for _ in 0..=SYMLINK_DEPTH_MAX {
+            let (fs, rest) = self.mount_of(&path)?;
+            match fs.escape(&rest, follow) {
+                Some(target) => path = target,
+                None => return Ok((fs, rest)),
+            }
+        }
+        Err(OsError::SymlinkLoop)
+    }
+
+    /// Find the file system `path` lies in, by the longest matching mount point,
+    /// and the path relative to it.
+    fn mount_of(&self, path: &str) -> Result<(Arc<dyn DynFileSys>, String)> {
+        let names = names(path);
+        let mounts = self.mounts.lock();
+        let (point, fs) = mounts
+            .iter()
+            .filter(|(point, _)| names.starts_with(point))
+            .max_by_key(|(point, _)| point.len())
+            .ok_or(OsError::NoSuchFile)?;
+
+        let mut rest = String::from("/");
+        rest.push_str(&names[point.len()..].join("/"));
+        Ok((fs.clone(), rest))
+    }
+}
+
+/// Names in `path` from `/`.
+fn names(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter(|name| !name.is_empty() && *name != ".")
+        .map(String::from)
+        .collect()
+}
@@ -30,7 +30,7 @@ use core::{ptr, slice, str};
 use fdt::{standard_nodes::MemoryRegion, Fdt};
 use riscv::register;
 
-use fs::{disk::DISKFS, FileSys};
+use fs::vfs::VFS;
 use mem::PhysAddr;
 
 extern "C" {
@@ -131,13 +131,25 @@ pub extern "C" fn main(hart_id: usize, dtb: usize) -> ! {
             }
             if str == "whoami" {
                 kprintln!("2300013067 Luo Siyuan");
+            } else if str == "ls" || str.starts_with("ls ") {
+                let dir = str[2..].trim();
+                match VFS.readdir(dir) {
+                    Ok(entries) => {
+                        for entry in entries {
+                            kprintln!("{:>6} {:?}\t{}", entry.inum, entry.kind, entry.name);
+                        }
+                    }
+                    Err(e) => kprintln!("ls: {:?}", e),
+                }
             } else if str == "exit" {
                 break;
             }
         }
     }
 
-    DISKFS.unmount();
+    if let Err(e) = VFS.unmount_all() {
+        kprintln!("Failed to unmount: {:?}", e);
+    }
     kprintln!("Goodbye, World!");
 
     sbi::reset(
@@ -21,6 +21,7 @@
 
 mod entry;
 
+use alloc::vec::Vec;
 use core::ptr;
 use core::{arch::asm, mem::transmute};
 
@@ -87,6 +88,47 @@ impl PageTable {
         })
     }
 
+    /// User mappings as `(va, pa, size, flags)`, with adjacent pages of the same
+    /// permissions merged.
+    pub fn user_mappings(&self) -> Vec<(usize, usize, usize, PTEFlags)> {
+        fn walk_imp(
+            pgt: &PageTable,
+            level: u32,
+            base: usize,
+            maps: &mut Vec<(usize, usize, usize, PTEFlags)>,
+        ) {
+            let shift = PG_SHIFT + 9 * level as usize;
+            for (idx, entry) in pgt.entries.iter().enumerate() {
+                if !entry.is_valid() || entry.is_global() {
+                    continue;
+                }
+                let va = base | idx << shift;
+                if !entry.is_leaf() {
+                    let table = unsafe { PageTable::from_raw(entry.pa().into_va() as *mut _) };
+                    walk_imp(&table, level - 1, va, maps);
+                    continue;
+                }
+                if !entry.is_user() {
+                    continue;
+                }
+                let (pa, size) = (entry.pa().value(), 1 << shift);
+                let flags = entry.flag() & (PTEFlags::R | PTEFlags::W | PTEFlags::X | PTEFlags::U);
+                match maps.last_mut() {
+                    Some(last)
+                        if last.0 + last.2 == va && last.1 + last.2 == pa && last.3 == flags =>
+                    {
+                        last.2 += size
+                    }
+                    _ => maps.push((va, pa, size, flags)),
+                }
+            }
+        }
+
+        let mut maps = Vec::new();
+        walk_imp(self, 2, 0, &mut maps);
+        maps
+    }
+
     /// Free all memory used by this pagetable back to where they were allocated.
     pub unsafe fn destroy(&mut self) {
         unsafe fn destroy_imp(pgt: &mut PageTable, level: usize) {
@@ -118,6 +118,16 @@ pub struct Palloc(Lazy<Mutex<BuddyAllocator, Intr>>);
 unsafe impl Sync for Palloc {}
 
 impl Palloc {
+    /// Bytes allocated from the pool.
+    pub fn allocated() -> usize {
+        Self::instance().lock().allocated * PG_SIZE
+    }
+
+    /// Bytes the pool manages.
+    pub fn total() -> usize {
+        Self::instance().lock().total
+    }
+
     /// Initialize the page-based allocator
     pub unsafe fn init(start: usize, end: usize) {
         Self::instance().lock().insert_range(start, end);
@@ -145,6 +155,16 @@ pub struct UserPool(Lazy<Mutex<BuddyAllocator, Intr>>);
 unsafe impl Sync for UserPool {}
 
 impl UserPool {
+    /// Bytes allocated from the pool.
+    pub fn allocated() -> usize {
+        Self::instance().lock().allocated * PG_SIZE
+    }
+
+    /// Bytes the pool manages.
+    pub fn total() -> usize {
+        Self::instance().lock().total
+    }
+
     /// Allocate n pages of consecutive space
     pub unsafe fn alloc_pages(n: usize) -> *mut u8 {
         Self::instance().lock().alloc(n)
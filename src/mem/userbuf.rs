@@ -1,5 +1,7 @@
 #![allow(dead_code)]
 
+use alloc::string::String;
+use alloc::vec::Vec;
 use core::arch::global_asm;
 
 use crate::error::OsError;
@@ -45,6 +47,44 @@ fn write_user_byte(user_src: *const u8, value: u8) -> Result<()> {
     }
 }
 
+/// Read a NUL-terminated string of at most `max` bytes from user space.
+///
+/// ## Return
+/// - `Err(BadPtr)`: A page fault happened, or the string isn't UTF-8.
+/// - `Err(ArgumentTooLong)`: No NUL within `max` bytes.
+pub fn read_user_str(user_src: *const u8, max: usize) -> Result<String> {
+    let mut bytes = Vec::new();
+    for i in 0..=max {
+        match read_user_byte(user_src.wrapping_add(i))? {
+            0 => return String::from_utf8(bytes).map_err(|_| OsError::BadPtr),
+            byte => bytes.push(byte),
+        }
+    }
+    Err(OsError::ArgumentTooLong)
+}
+
+/// Copy `dst.len()` bytes from user space at `user_src` into `dst`.
+///
+/// ## Return
+/// - `Err(BadPtr)`: A page fault happened, part of `dst` may have been copied.
+pub fn read_user_buf(user_src: *const u8, dst: &mut [u8]) -> Result<()> {
+    for (i, byte) in dst.iter_mut().enumerate() {
+        *byte = read_user_byte(user_src.wrapping_add(i))?;
+    }
+    Ok(())
+}
+
+/// Copy `src` into user space at `user_dst`.
+///
+/// ## Return
+/// - `Err(BadPtr)`: A page fault happened, part of `src` may have been copied.
+pub fn write_user_buf(user_dst: *mut u8, src: &[u8]) -> Result<()> {
+    for (i, byte) in src.iter().enumerate() {
+        write_user_byte(user_dst.wrapping_add(i), *byte)?;
+    }
+    Ok(())
+}
+
 extern "C" {
     pub fn __knrl_read_usr_byte(user_src: *const u8, byte_ptr: *const u8) -> u8;
     pub fn __knrl_read_usr_byte_pc();
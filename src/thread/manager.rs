@@ -84,6 +84,11 @@ impl Manager {
         interrupt::set(old);
     }
 
+    /// All alive and not yet destroyed threads.
+    pub fn all(&self) -> Vec<Arc<Thread>> {
+        self.all.lock().clone()
+    }
+
     /// Register a **new** thread
     pub(super) fn register(&self, thread: Arc<Thread>) {
         // Register it into the scheduler
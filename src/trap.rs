@@ -8,6 +8,7 @@ use crate::device::{plic, virtio};
 use crate::sbi;
 use crate::thread;
 use core::arch;
+use core::sync::atomic::{AtomicUsize, Ordering::Relaxed};
 
 use riscv::register::scause::{Exception::*, Interrupt::*, Trap::*};
 use riscv::register::sstatus::*;
@@ -29,6 +30,25 @@ pub struct Frame {
     pub sepc: usize,
 }
 
+/* -------------------------------------------------------------------------- */
+/*                                  COUNTERS                                  */
+/* -------------------------------------------------------------------------- */
+
+/// Number of traps handled since boot, by cause.
+pub struct Counters {
+    pub timer: AtomicUsize,
+    pub virtio: AtomicUsize,
+    pub syscall: AtomicUsize,
+    pub pagefault: AtomicUsize,
+}
+
+pub static COUNTERS: Counters = Counters {
+    timer: AtomicUsize::new(0),
+    virtio: AtomicUsize::new(0),
+    syscall: AtomicUsize::new(0),
+    pagefault: AtomicUsize::new(0),
+};
+
 pub fn set_strap_entry() {
     unsafe { stvec::write(trap_entry_k as usize, stvec::TrapMode::Direct) }
 }
@@ -62,6 +82,7 @@ pub extern "C" fn trap_handler(frame: &mut Frame) {
             let args = [frame.x[10], frame.x[11], frame.x[12]];
             #[cfg(feature = "debug")]
             kprintln!("[TRAP] User ECall, ID={}, args={:?}", id, args);
+            COUNTERS.syscall.fetch_add(1, Relaxed);
             unsafe { riscv::register::sstatus::set_sie() };
             // Increase sepc by 1 to skip ecall.
             frame.sepc += 4;
@@ -71,6 +92,7 @@ pub extern "C" fn trap_handler(frame: &mut Frame) {
         Interrupt(SupervisorTimer) => {
             kprintln!("--------------------- received interrupt ---------------------");
             sbi::timer::tick();
+            COUNTERS.timer.fetch_add(1, Relaxed);
             unsafe { riscv::register::sstatus::set_sie() };
 
             // 先检查一下 sleeping 的线程
@@ -86,7 +108,10 @@ pub extern "C" fn trap_handler(frame: &mut Frame) {
             // Handle the interrupt.
             match id as _ {
                 0 => panic!("There should be an interrupt"),
-                plic::VIRTIO0_ID => virtio::handle_interrupt(),
+                plic::VIRTIO0_ID => {
+                    COUNTERS.virtio.fetch_add(1, Relaxed);
+                    virtio::handle_interrupt()
+                }
                 _ => panic!("Unknown Interrupt ID: {}", id),
             }
 
@@ -102,6 +127,7 @@ pub extern "C" fn trap_handler(frame: &mut Frame) {
         Exception(f @ LoadPageFault)
         | Exception(f @ StorePageFault)
         | Exception(f @ InstructionPageFault) => {
+            COUNTERS.pagefault.fetch_add(1, Relaxed);
             pagefault::handler(frame, f, stval);
         }
 
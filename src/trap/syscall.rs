@@ -3,6 +3,18 @@
 
 #![allow(dead_code)]
 
+use alloc::vec;
+use core::cmp;
+
+use crate::fs::lock::LockKind;
+use crate::fs::vfs::VFS;
+use crate::fs::{OpenFlags, Stat};
+use crate::io::{Read, Write};
+use crate::mem::userbuf::{read_user_buf, read_user_str, write_user_buf};
+use crate::thread;
+use crate::userproc::UserProc;
+use crate::{OsError, Result};
+
 /* -------------------------------------------------------------------------- */
 /*                               SYSCALL NUMBER                               */
 /* -------------------------------------------------------------------------- */
@@ -19,8 +31,262 @@ const SYS_SEEK: usize = 9;
 const SYS_TELL: usize = 10;
 const SYS_CLOSE: usize = 11;
 const SYS_FSTAT: usize = 12;
+const SYS_MMAP: usize = 13;
+const SYS_MUNMAP: usize = 14;
+const SYS_CHDIR: usize = 15;
+const SYS_MKDIR: usize = 16;
+const SYS_READDIR: usize = 17;
+const SYS_FLOCK: usize = 18;
+const SYS_STATFS: usize = 19;
+const SYS_SYNC: usize = 20;
+const SYS_OPENDIR: usize = 21;
+
+/// Operations of `flock`, see `user/lib/fcntl.h`.
+const LOCK_SH: usize = 1;
+const LOCK_EX: usize = 2;
+const LOCK_NB: usize = 4;
+const LOCK_UN: usize = 8;
+
+/// Longest path a user program may pass.
+const PATH_LEN_MAX: usize = 1024;
+
+/// Most bytes `read` and `write` copy through the kernel at a time.
+const IO_CHUNK: usize = 4096;
+
+/// Longest name in a [`UserDirent`].
+const NAME_MAX: usize = 255;
+
+pub fn syscall_handler(id: usize, args: [usize; 3]) -> isize {
+    let ret = match id {
+        SYS_OPEN => sys_open(args[0] as *const u8, args[1]),
+        SYS_READ => sys_read(args[0] as isize, args[1] as *mut u8, args[2]),
+        SYS_WRITE => sys_write(args[0] as isize, args[1] as *const u8, args[2]),
+        SYS_CLOSE => sys_close(args[0] as isize),
+        SYS_FSTAT => sys_fstat(args[0] as isize, args[1] as *mut u8),
+        SYS_MKDIR => sys_mkdir(args[0] as *const u8),
+        SYS_OPENDIR => sys_opendir(args[0] as *const u8),
+        SYS_READDIR => sys_readdir(args[0] as isize, args[1] as *mut u8),
+        SYS_FLOCK => sys_flock(args[0] as isize, args[1]),
+        SYS_STATFS => sys_statfs(args[0] as *const u8, args[1] as *mut u8),
+        SYS_SYNC => sys_sync(),
+        // TODO: LAB2 impl
+        _ => Err(OsError::UserError),
+    };
+    ret.unwrap_or(-1)
+}
+
+/// Run `f` on the user process of the current thread.
+fn with_userproc<R>(f: impl FnOnce(&UserProc) -> Result<R>) -> Result<R> {
+    let current = thread::current();
+    let userproc = current.userproc.as_ref().ok_or(OsError::UserError)?;
+    f(userproc)
+}
+
+fn sys_open(path: *const u8, flags: usize) -> Result<isize> {
+    let path = read_user_str(path, PATH_LEN_MAX)?;
+    let flags = OpenFlags::from_bits(flags as u32).ok_or(OsError::InvalidArgument)?;
+    let file = VFS.open_with(&path, flags)?;
+    with_userproc(|proc| Ok(proc.add_file(file)))
+}
+
+/// Read at most `size` bytes of the file of `fd` into `buf`, returning how
+/// many are read. Fewer are read at the end of the file, or once a read of
+/// the console reaches the end of a line.
+fn sys_read(fd: isize, buf: *mut u8, size: usize) -> Result<isize> {
+    let mut chunk = vec![0; cmp::min(size, IO_CHUNK)];
+    let mut done = 0;
+    while done < size {
+        let len = cmp::min(size - done, IO_CHUNK);
+        let cnt = with_userproc(|proc| {
+            proc.with_file(fd, |file| file.read(&mut chunk[..len]))
+                .ok_or(OsError::FileNotOpened)?
+        })?;
+        write_user_buf(buf.wrapping_add(done), &chunk[..cnt])?;
+        done += cnt;
+        if cnt < len {
+            break;
+        }
+    }
+    Ok(done as isize)
+}
+
+/// Write `size` bytes from `buf` to the file of `fd`, returning how many are
+/// written.
+fn sys_write(fd: isize, buf: *const u8, size: usize) -> Result<isize> {
+    let mut chunk = vec![0; cmp::min(size, IO_CHUNK)];
+    let mut done = 0;
+    while done < size {
+        let len = cmp::min(size - done, IO_CHUNK);
+        read_user_buf(buf.wrapping_add(done), &mut chunk[..len])?;
+        let cnt = with_userproc(|proc| {
+            proc.with_file(fd, |file| file.write(&chunk[..len]))
+                .ok_or(OsError::FileNotOpened)?
+        })?;
+        done += cnt;
+        if cnt < len {
+            break;
+        }
+    }
+    Ok(done as isize)
+}
+
+fn sys_close(fd: isize) -> Result<isize> {
+    with_userproc(|proc| {
+        if proc.remove_file(fd).is_none() && proc.remove_dir(fd).is_none() {
+            return Err(OsError::FileNotOpened);
+        }
+        Ok(0)
+    })
+}
+
+/// `stat` in `user/lib/fstat.h`.
+#[repr(C)]
+struct UserStat {
+    ino: u32,
+    _pad: u32,
+    size: u64,
+    kind: u16,
+    mode: u16,
+    nlink: u32,
+    atime: u64,
+    mtime: u64,
+    ctime: u64,
+    blocks: u64,
+}
+
+fn sys_fstat(fd: isize, buf: *mut u8) -> Result<isize> {
+    let stat: Stat = with_userproc(|proc| {
+        proc.with_file(fd, |file| file.stat())
+            .ok_or(OsError::FileNotOpened)
+    })?;
+    let user_stat = UserStat {
+        ino: stat.inum as u32,
+        _pad: 0,
+        size: stat.size as u64,
+        kind: stat.kind as u16,
+        mode: stat.mode,
+        nlink: stat.nlink,
+        atime: stat.atime,
+        mtime: stat.mtime,
+        ctime: stat.ctime,
+        blocks: stat.blocks as u64,
+    };
+    write_user_buf(buf, as_bytes(&user_stat))?;
+    Ok(0)
+}
+
+/// Create an empty directory at `path`.
+fn sys_mkdir(path: *const u8) -> Result<isize> {
+    let path = read_user_str(path, PATH_LEN_MAX)?;
+    VFS.mkdir(&path)?;
+    Ok(0)
+}
+
+/// `dirent` in `user/lib/dirent.h`.
+#[repr(C)]
+struct UserDirent {
+    ino: u32,
+    kind: u16,
+    name: [u8; NAME_MAX + 1],
+    _pad: [u8; 2],
+}
+
+/// Open the directory at `path` for reading its entries with `readdir`,
+/// returning a file descriptor closed with `close`.
+///
+/// The entries are those when it's opened.
+fn sys_opendir(path: *const u8) -> Result<isize> {
+    let path = read_user_str(path, PATH_LEN_MAX)?;
+    let entries = VFS.readdir(&path)?;
+    with_userproc(|proc| Ok(proc.add_dir(entries)))
+}
+
+/// Read the next entry of the directory opened as `fd` into `buf`.
+///
+/// ## Return
+/// `1` if there is such an entry, `0` if all of them are read.
+///
+/// ## Errors
+/// [`OsError::FileNameTooLong`] if the name doesn't fit in [`UserDirent`].
+/// The entry is skipped.
+fn sys_readdir(fd: isize, buf: *mut u8) -> Result<isize> {
+    let entry = with_userproc(|proc| {
+        proc.with_dir(fd, |dir| dir.next())
+            .ok_or(OsError::FileNotOpened)
+    })?;
+    let entry = match entry {
+        Some(entry) => entry,
+        None => return Ok(0),
+    };
+    if entry.name.len() > NAME_MAX {
+        return Err(OsError::FileNameTooLong);
+    }
+
+    let mut dirent = UserDirent {
+        ino: entry.inum as u32,
+        kind: entry.kind as u16,
+        name: [0; NAME_MAX + 1],
+        _pad: [0; 2],
+    };
+    dirent.name[..entry.name.len()].copy_from_slice(entry.name.as_bytes());
+    write_user_buf(buf, as_bytes(&dirent))?;
+    Ok(1)
+}
+
+/// Lock or unlock the whole file of `fd`.
+fn sys_flock(fd: isize, op: usize) -> Result<isize> {
+    // A clone shares the locks, and blocking on it leaves the fd table free.
+    let file = with_userproc(|proc| {
+        proc.with_file(fd, |file| file.clone())
+            .ok_or(OsError::FileNotOpened)
+    })?;
+    let wait = op & LOCK_NB == 0;
+    match op & !LOCK_NB {
+        LOCK_SH => file.lock(LockKind::Shared, 0..usize::MAX, wait)?,
+        LOCK_EX => file.lock(LockKind::Exclusive, 0..usize::MAX, wait)?,
+        LOCK_UN => file.unlock(0..usize::MAX)?,
+        _ => return Err(OsError::InvalidArgument),
+    }
+    Ok(0)
+}
+
+/// `fsstat` in `user/lib/statfs.h`.
+#[repr(C)]
+struct UserStatFs {
+    blocks: u64,
+    free_blocks: u64,
+    largest_free: u64,
+    inodes: u64,
+    free_inodes: u64,
+    swap_blocks: u64,
+}
+
+/// Report the capacity and usage of the file system `path` lies in.
+fn sys_statfs(path: *const u8, buf: *mut u8) -> Result<isize> {
+    let path = read_user_str(path, PATH_LEN_MAX)?;
+    let stat = VFS.statfs(&path)?;
+    let user_stat = UserStatFs {
+        blocks: stat.blocks as u64,
+        free_blocks: stat.free_blocks as u64,
+        largest_free: stat.largest_free as u64,
+        inodes: stat.inodes as u64,
+        free_inodes: stat.free_inodes as u64,
+        swap_blocks: stat.swap_blocks as u64,
+    };
+    write_user_buf(buf, as_bytes(&user_stat))?;
+    Ok(0)
+}
+
+/// Write everything mounted to the disk.
+fn sys_sync() -> Result<isize> {
+    VFS.sync()?;
+    Ok(0)
+}
 
-pub fn syscall_handler(_id: usize, _args: [usize; 3]) -> isize {
-    // TODO: LAB2 impl
-    -1
+/// The bytes of `value`, which must have no padding, or it leaks whatever
+/// the kernel left there to user space.
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe {
+        core::slice::from_raw_parts(value as *const T as *const u8, core::mem::size_of::<T>())
+    }
 }
@@ -3,25 +3,93 @@
 
 mod load;
 
+use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::vec::Vec;
 use core::arch::asm;
 use core::mem::MaybeUninit;
 use riscv::register::sstatus;
 
-use crate::fs::File;
+use crate::fs::vfs::VFS;
+use crate::fs::{File, OpenFlags, ReadDir};
 use crate::mem::pagetable::KernelPgTable;
+use crate::sync::Mutex;
 use crate::thread;
 use crate::trap::{trap_exit_u, Frame};
 
+/// Standard input, output and error, opened on `/dev/console`, read-only for
+/// the first and write-only for the others.
+const STDIO_FDS: [(isize, OpenFlags); 3] = [
+    (0, OpenFlags::RDONLY),
+    (1, OpenFlags::WRONLY),
+    (2, OpenFlags::WRONLY),
+];
+
 pub struct UserProc {
     #[allow(dead_code)]
     bin: File,
+    /// Opened files, indexed by file descriptor.
+    files: Mutex<BTreeMap<isize, File>>,
+    /// Directories opened for reading their entries, sharing file descriptors
+    /// with files. Each is read from where the last read stopped.
+    dirs: Mutex<BTreeMap<isize, ReadDir>>,
 }
 
 impl UserProc {
     pub fn new(file: File) -> Self {
-        Self { bin: file }
+        let mut files = BTreeMap::new();
+        if let Ok(console) = VFS.open("/dev/console") {
+            for (fd, flags) in STDIO_FDS {
+                files.insert(fd, console.clone().with_flags(flags));
+            }
+        }
+        Self {
+            bin: file,
+            files: Mutex::new(files),
+            dirs: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Add an opened file, returning the lowest unused file descriptor for it.
+    pub fn add_file(&self, file: File) -> isize {
+        let mut files = self.files.lock();
+        let dirs = self.dirs.lock();
+        let fd = (0..)
+            .find(|fd| !files.contains_key(fd) && !dirs.contains_key(fd))
+            .unwrap();
+        files.insert(fd, file);
+        fd
+    }
+
+    /// Add an opened directory, returning the lowest unused file descriptor for it.
+    pub fn add_dir(&self, dir: ReadDir) -> isize {
+        let files = self.files.lock();
+        let mut dirs = self.dirs.lock();
+        let fd = (0..)
+            .find(|fd| !files.contains_key(fd) && !dirs.contains_key(fd))
+            .unwrap();
+        dirs.insert(fd, dir);
+        fd
+    }
+
+    /// Remove the file of `fd`, returning it.
+    pub fn remove_file(&self, fd: isize) -> Option<File> {
+        self.files.lock().remove(&fd)
+    }
+
+    /// Remove the directory of `fd`, returning it.
+    pub fn remove_dir(&self, fd: isize) -> Option<ReadDir> {
+        self.dirs.lock().remove(&fd)
+    }
+
+    /// Run `f` on the file of `fd`.
+    pub fn with_file<R>(&self, fd: isize, f: impl FnOnce(&mut File) -> R) -> Option<R> {
+        self.files.lock().get_mut(&fd).map(f)
+    }
+
+    /// Run `f` on the directory of `fd`.
+    pub fn with_dir<R>(&self, fd: isize, f: impl FnOnce(&mut ReadDir) -> R) -> Option<R> {
+        self.dirs.lock().get_mut(&fd).map(f)
     }
 }
 
@@ -32,6 +32,18 @@ pub fn main() {
     #[cfg(any(feature = "test-virtio", feature = "test-virtio-repeat"))]
     virtio::repeat::main();
 
+    #[cfg(any(feature = "test-virtio", feature = "test-virtio-vectored"))]
+    virtio::vectored::main();
+
+    #[cfg(any(feature = "test-virtio", feature = "test-virtio-concurrent"))]
+    virtio::concurrent::main();
+
+    #[cfg(any(feature = "test-virtio", feature = "test-virtio-limits"))]
+    virtio::limits::main();
+
+    #[cfg(any(feature = "test-virtio", feature = "test-virtio-errors"))]
+    virtio::errors::main();
+
     #[cfg(feature = "test-mem-malloc")]
     malloc::main();
 
@@ -40,4 +52,19 @@ pub fn main() {
 
     #[cfg(feature = "test-fs-disk")]
     fs::disk::main();
+
+    #[cfg(feature = "test-fs-disk-format")]
+    fs::disk::format::main();
+
+    #[cfg(feature = "test-fs-ext2")]
+    fs::ext2::main();
+
+    #[cfg(feature = "test-fs-fat")]
+    fs::fat::main();
+
+    #[cfg(feature = "test-fs-devfs")]
+    fs::devfs::main();
+
+    #[cfg(feature = "test-fs-procfs")]
+    fs::procfs::main();
 }
@@ -1,2 +1,6 @@
+pub mod devfs;
 pub mod disk;
+pub mod ext2;
+pub mod fat;
 pub mod inmem;
+pub mod procfs;
@@ -0,0 +1,45 @@
+use crate::device::virtio::{Virtio, SECTOR_SIZE};
+use crate::fs::vfs::VFS;
+use crate::fs::FileType;
+use crate::io::prelude::*;
+use crate::OsError;
+
+pub fn main() {
+    let names: alloc::vec::Vec<_> = VFS.readdir("/dev").unwrap().map(|e| e.name).collect();
+    assert_eq!(names, ["console", "null", "zero", "random", "vda"]);
+    assert_eq!(VFS.open("/dev/nope").err(), Some(OsError::NoSuchFile));
+    assert_eq!(VFS.remove("/dev/null"), Err(OsError::InvalidArgument));
+
+    let mut buf = [1u8; 64];
+    {
+        let mut null = VFS.open("/dev/null").unwrap();
+        assert_eq!(null.stat().kind, FileType::Device);
+        assert_eq!(null.write(&buf).unwrap(), buf.len());
+        assert_eq!(null.read(&mut buf).unwrap(), 0);
+    }
+    {
+        let mut zero = VFS.open("/dev/zero").unwrap();
+        zero.read_exact(&mut buf).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+    }
+    {
+        let mut random = VFS.open("/dev/random").unwrap();
+        let mut other = [0u8; 64];
+        random.read_exact(&mut buf).unwrap();
+        random.read_exact(&mut other).unwrap();
+        assert_ne!(buf, other);
+    }
+    {
+        let mut vda = VFS.open("/dev/vda").unwrap();
+        assert_eq!(vda.len().unwrap() % SECTOR_SIZE, 0);
+        let mut sector = [0; SECTOR_SIZE];
+        Virtio::read_sector(1, &mut sector).unwrap();
+        vda.seek(SeekFrom::Start(SECTOR_SIZE + 7)).unwrap();
+        vda.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, sector[7..7 + buf.len()]);
+    }
+    {
+        let mut console = VFS.open("/dev/console").unwrap();
+        console.write_all(b"devfs: console ok\n").unwrap();
+    }
+}
@@ -1,13 +1,30 @@
+mod abort;
+mod checksum;
 mod chlen;
+pub mod format;
+mod link;
 mod readimg;
+mod rename;
 mod simple;
+mod sparse;
+mod statfs;
+mod swap;
 mod sync;
+mod vfs;
 
 pub fn main() {
     #[cfg(feature = "test-fs-disk-simple")]
     {
         simple::main();
+        link::main();
+        rename::main();
+        sparse::main();
+        checksum::main();
+        statfs::main();
+        abort::main();
+        vfs::main();
         readimg::main().unwrap();
+        swap::main();
     }
     #[cfg(not(feature = "test-fs-disk-simple"))]
     {
@@ -0,0 +1,26 @@
+use alloc::string::String;
+
+use crate::device::virtio::SECTOR_SIZE;
+use crate::fs::disk::{Path, DISKFS};
+use crate::fs::FileSys;
+use crate::OsError;
+
+pub fn main() {
+    let before = DISKFS.statfs().unwrap();
+    // The target of a symlink is metadata, and this one doesn't fit in the
+    // journal. Nothing of the failed symlink is left behind.
+    let target: String = "a".repeat(200 * SECTOR_SIZE);
+    assert_eq!(
+        DISKFS.symlink(&target, "/disk-abort".into()).err(),
+        Some(OsError::JournalFull)
+    );
+    assert!(!Path::exists("/disk-abort".into()));
+    let after = DISKFS.statfs().unwrap();
+    assert_eq!(after.inodes, before.inodes);
+    assert_eq!(after.free_blocks, before.free_blocks);
+
+    // Later transactions go through.
+    DISKFS.symlink("/", "/disk-abort".into()).unwrap();
+    DISKFS.remove("/disk-abort".into()).unwrap();
+    kprintln!("[DISKFS.ABORT] Done.")
+}
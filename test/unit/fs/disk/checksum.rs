@@ -0,0 +1,33 @@
+use crate::device::virtio::{Virtio, SECTOR_SIZE};
+use crate::fs::disk::DISKFS;
+use crate::fs::FileSys;
+use crate::io::prelude::*;
+use crate::OsError;
+
+pub fn main() {
+    let sector = {
+        let mut file = DISKFS.create("/disk-checksum".into()).unwrap();
+        file.write_all(b"checked").unwrap();
+        file.inum() as u64
+    };
+    {
+        // A corrupted inode isn't trusted once it's read back.
+        let mut good = [0; SECTOR_SIZE];
+        Virtio::read_sector(sector, &mut good).unwrap();
+        let mut bad = good;
+        bad[0] ^= 0xff;
+        Virtio::write_sector(sector, &bad).unwrap();
+        assert_eq!(
+            DISKFS.open("/disk-checksum".into()).err(),
+            Some(OsError::ChecksumMismatch)
+        );
+
+        Virtio::write_sector(sector, &good).unwrap();
+        let mut buf = [0; 7];
+        let mut file = DISKFS.open("/disk-checksum".into()).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"checked");
+    }
+    DISKFS.remove("/disk-checksum".into()).unwrap();
+    kprintln!("[DISKFS.CHECKSUM] Done.")
+}
@@ -0,0 +1,17 @@
+use crate::fs::disk::DISKFS;
+use crate::fs::FileSys;
+
+/// The disk is formatted when [`DISKFS`] is mounted, see `fs-disk-format`.
+pub fn main() {
+    // Nothing but the root dir and the free map.
+    let stat = DISKFS.statfs().unwrap();
+    assert_eq!(stat.inodes, 2);
+    assert_eq!(stat.swap_blocks, 0);
+    assert!(stat.free_blocks > 0);
+
+    // Only a clean unmount marks the file system clean.
+    assert!(!DISKFS.is_clean());
+    DISKFS.unmount().unwrap();
+    assert!(DISKFS.is_clean());
+    kprintln!("[DISKFS.FORMAT] Done.")
+}
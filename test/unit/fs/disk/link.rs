@@ -0,0 +1,74 @@
+use crate::fs::disk::{Path, DISKFS};
+use crate::fs::{FileSys, FileType};
+use crate::io::prelude::*;
+use crate::OsError;
+
+pub fn main() {
+    {
+        let mut file = DISKFS.create("/disk-link".into()).unwrap();
+        file.write_all(b"linked").unwrap();
+    }
+    {
+        // A hard link keeps the file after the first name is gone.
+        DISKFS
+            .link("/disk-link".into(), "/disk-link-hard".into())
+            .unwrap();
+        assert_eq!(DISKFS.open("/disk-link".into()).unwrap().stat().nlink, 2);
+        DISKFS.remove("/disk-link".into()).unwrap();
+
+        let mut file = DISKFS.open("/disk-link-hard".into()).unwrap();
+        assert_eq!(file.stat().nlink, 1);
+        let mut buf = [0; 6];
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"linked");
+    }
+    {
+        // A symlink is followed on open, but removed by itself.
+        DISKFS
+            .symlink("/disk-link-hard", "/disk-link-soft".into())
+            .unwrap();
+        let file = DISKFS.open("/disk-link-soft".into()).unwrap();
+        assert_eq!(file.stat().kind, FileType::File);
+        assert_eq!(
+            DISKFS.readlink("/disk-link-soft".into()).unwrap(),
+            "/disk-link-hard"
+        );
+        DISKFS.remove("/disk-link-soft".into()).unwrap();
+        assert!(Path::exists("/disk-link-hard".into()));
+    }
+    {
+        // A relative target is looked up from the dir of the symlink.
+        DISKFS.mkdir("/disk-link-dir".into()).unwrap();
+        {
+            let mut file = DISKFS.create("/disk-link-dir/f".into()).unwrap();
+            file.write_all(b"nested").unwrap();
+        }
+        DISKFS.symlink("f", "/disk-link-dir/rel".into()).unwrap();
+        let mut file = DISKFS.open("/disk-link-dir/rel".into()).unwrap();
+        let mut buf = [0; 6];
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"nested");
+        assert!(!Path::exists("/f".into()));
+
+        DISKFS.remove("/disk-link-dir/rel".into()).unwrap();
+        DISKFS.remove("/disk-link-dir/f".into()).unwrap();
+        DISKFS.remove("/disk-link-dir".into()).unwrap();
+    }
+    {
+        // Symlinks naming each other.
+        DISKFS
+            .symlink("/disk-link-b", "/disk-link-a".into())
+            .unwrap();
+        DISKFS
+            .symlink("/disk-link-a", "/disk-link-b".into())
+            .unwrap();
+        assert_eq!(
+            DISKFS.open("/disk-link-a".into()).err(),
+            Some(OsError::SymlinkLoop)
+        );
+        DISKFS.remove("/disk-link-a".into()).unwrap();
+        DISKFS.remove("/disk-link-b".into()).unwrap();
+    }
+    DISKFS.remove("/disk-link-hard".into()).unwrap();
+    kprintln!("[DISKFS.LINK] Done.")
+}
@@ -1,7 +1,8 @@
 use crate::fs::disk::{Swap, DISKFS};
 use crate::fs::FileSys;
 use crate::io::prelude::*;
-use crate::Result;
+use crate::mem::PG_SIZE;
+use crate::{OsError, Result};
 
 pub fn main() -> Result<()> {
     let file = DISKFS.open("exit".into())?;
@@ -12,10 +13,20 @@ pub fn main() -> Result<()> {
         Swap::len(),
         Swap::page_num()
     );
-    let mut swap = Swap::lock();
-    swap.write_from(0xfabcdeusize)?;
-    swap.rewind()?;
-    assert_eq!(swap.read_into::<usize>()?, 0xfabcde);
+    let slot = Swap::alloc_slot()?;
+    let mut page = [0; PG_SIZE];
+    page[..8].copy_from_slice(&0xfabcdeusize.to_le_bytes());
+    Swap::write_page(slot, &page)?;
+    page.fill(0);
+    Swap::read_page(slot, &mut page)?;
+    assert_eq!(page[..8], 0xfabcdeusize.to_le_bytes());
+    assert_eq!(Swap::stats().used, 1);
+    Swap::free_slot(slot)?;
+    assert_eq!(Swap::free_slot(slot), Err(OsError::DoubleFree));
+    assert_eq!(
+        Swap::read_page(slot, &mut page),
+        Err(OsError::InvalidArgument)
+    );
     kprintln!("[DISKFS.READIMG] Swap read/write works.");
 
     Ok(())
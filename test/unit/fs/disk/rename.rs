@@ -0,0 +1,81 @@
+use crate::fs::disk::{Path, DISKFS};
+use crate::fs::{FileSys, FileType};
+use crate::io::prelude::*;
+use crate::OsError;
+
+pub fn main() {
+    DISKFS.mkdir("/disk-rename".into()).unwrap();
+    DISKFS.mkdir("/disk-rename/sub".into()).unwrap();
+    {
+        let mut entries = DISKFS.readdir("/disk-rename".into()).unwrap();
+        let entry = entries.next().unwrap();
+        assert_eq!(entry.name, "sub");
+        assert_eq!(entry.kind, FileType::Dir);
+        assert!(entries.next().is_none());
+    }
+    {
+        let mut file = DISKFS.create("/disk-rename/a".into()).unwrap();
+        file.write_all(b"moved").unwrap();
+        DISKFS.create("/disk-rename/sub/b".into()).unwrap();
+
+        // Move across directories, replacing the target.
+        DISKFS
+            .rename("/disk-rename/a".into(), "/disk-rename/sub/b".into())
+            .unwrap();
+        assert!(!Path::exists("/disk-rename/a".into()));
+
+        // The opened file is not affected.
+        let mut buf = [0; 5];
+        file.rewind().unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"moved");
+        let moved = DISKFS.open("/disk-rename/sub/b".into()).unwrap();
+        assert_eq!(moved.inum(), file.inum());
+    }
+    {
+        // A failed move changes nothing, whether it replaces a file or not.
+        DISKFS.create("/disk-rename/c".into()).unwrap();
+        let too_long = alloc::format!("/disk-rename/sub/{}", "l".repeat(256));
+        assert_eq!(
+            DISKFS.rename("/disk-rename/c".into(), too_long.as_str().into()),
+            Err(OsError::FileNameTooLong)
+        );
+        assert!(Path::exists("/disk-rename/c".into()));
+        assert_eq!(
+            DISKFS.rename("/disk-rename/c".into(), "/disk-rename/sub".into()),
+            Err(OsError::IsDir)
+        );
+        assert!(Path::exists("/disk-rename/c".into()));
+        assert!(Path::exists("/disk-rename/sub/b".into()));
+
+        // Replacing in the same directory.
+        DISKFS
+            .rename("/disk-rename/sub/b".into(), "/disk-rename/c".into())
+            .unwrap();
+        assert!(!Path::exists("/disk-rename/sub/b".into()));
+        let mut buf = [0; 5];
+        let mut file = DISKFS.open("/disk-rename/c".into()).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"moved");
+        DISKFS
+            .rename("/disk-rename/c".into(), "/disk-rename/sub/b".into())
+            .unwrap();
+    }
+    {
+        // A directory can't be moved into itself.
+        assert_eq!(
+            DISKFS.rename("/disk-rename".into(), "/disk-rename/sub/dir".into()),
+            Err(OsError::InvalidArgument)
+        );
+        // Nor can a non-empty one be removed.
+        assert_eq!(
+            DISKFS.remove("/disk-rename/sub".into()),
+            Err(OsError::DirNotEmpty)
+        );
+    }
+    DISKFS.remove("/disk-rename/sub/b".into()).unwrap();
+    DISKFS.remove("/disk-rename/sub".into()).unwrap();
+    DISKFS.remove("/disk-rename".into()).unwrap();
+    assert!(!Path::exists("/disk-rename".into()));
+    kprintln!("[DISKFS.RENAME] Done.")
+}
@@ -1,7 +1,8 @@
 use crate::device::virtio::SECTOR_SIZE;
 use crate::fs::disk::{Path, DISKFS};
-use crate::fs::FileSys;
+use crate::fs::{FileSys, FileType};
 use crate::io::prelude::*;
+use crate::OsError;
 
 pub fn main() {
     let buf = [1; 3 * SECTOR_SIZE];
@@ -23,6 +24,11 @@ pub fn main() {
         for i in 0..SECTOR_SIZE * 3 {
             assert_eq!(buf2[random_off + i], 1);
         }
+        let stat = file.stat();
+        assert_eq!(stat.kind, FileType::File);
+        assert_eq!(stat.size, 4 * SECTOR_SIZE);
+        assert_eq!(stat.nlink, 1);
+        assert!(stat.ctime <= stat.mtime && stat.mtime <= stat.atime);
         // Drop file and inode.
     }
     {
@@ -35,5 +41,40 @@ pub fn main() {
         // There isn't such file.
         assert_eq!(Path::exists("/disk-simple".into()), false);
     }
+    {
+        // Long names are kept whole, too long ones are rejected.
+        let long = alloc::format!("/{}", "l".repeat(255));
+        DISKFS.create(long.as_str().into()).unwrap();
+        assert!(Path::exists(long.as_str().into()));
+        let too_long = alloc::format!("{}l", long);
+        assert_eq!(
+            DISKFS.create(too_long.as_str().into()).err(),
+            Some(OsError::FileNameTooLong)
+        );
+        DISKFS.remove(long.as_str().into()).unwrap();
+
+        // The limit is in bytes, whatever the characters.
+        let wide = alloc::format!("/{}", "é".repeat(127));
+        DISKFS.create(wide.as_str().into()).unwrap();
+        assert!(Path::exists(wide.as_str().into()));
+        let too_wide = alloc::format!("{}é", wide);
+        assert_eq!(
+            DISKFS.create(too_wide.as_str().into()).err(),
+            Some(OsError::FileNameTooLong)
+        );
+        DISKFS.remove(wide.as_str().into()).unwrap();
+    }
+    {
+        // The root dir grows past its initial size, and shrinks back.
+        let name = |i| alloc::format!("/disk-simple-grow-{:03}", i);
+        for i in 0..64 {
+            DISKFS.create(name(i).as_str().into()).unwrap();
+        }
+        for i in 0..64 {
+            assert!(Path::exists(name(i).as_str().into()));
+            DISKFS.remove(name(i).as_str().into()).unwrap();
+        }
+        assert!(!Path::exists(name(0).as_str().into()));
+    }
     kprintln!("[DISKFS.SIMPLE] Done.")
 }
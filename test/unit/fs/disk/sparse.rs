@@ -0,0 +1,72 @@
+use alloc::vec;
+
+use crate::device::virtio::SECTOR_SIZE;
+use crate::fs::disk::DISKFS;
+use crate::fs::FileSys;
+use crate::io::prelude::*;
+
+/// Far enough to need the double indirect sector.
+const FAR: usize = 2 << 20;
+
+pub fn main() {
+    let mut file = DISKFS.create("/disk-sparse".into()).unwrap();
+    {
+        // Writing far past the end leaves a hole, taking no space.
+        file.seek(SeekFrom::Start(FAR)).unwrap();
+        file.write_all(b"tail").unwrap();
+        let stat = file.stat();
+        assert_eq!(stat.size, FAR + 4);
+        assert!(stat.blocks <= 4, "{} blocks taken", stat.blocks);
+
+        let mut buf = vec![0xff; 3 * SECTOR_SIZE];
+        file.seek(SeekFrom::Start(FAR - SECTOR_SIZE)).unwrap();
+        file.read_exact(&mut buf[..SECTOR_SIZE + 4]).unwrap();
+        assert!(buf[..SECTOR_SIZE].iter().all(|b| *b == 0));
+        assert_eq!(&buf[SECTOR_SIZE..SECTOR_SIZE + 4], b"tail");
+    }
+    {
+        // Growing by resizing leaves a hole as well.
+        file.set_len(FAR + FAR / 2).unwrap();
+        assert!(file.stat().blocks <= 4);
+        let mut buf = [0xff; 8];
+        file.seek(SeekFrom::Start(FAR + 4)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0; 8]);
+    }
+    {
+        // Punching frees whole sectors and zeros partial ones.
+        file.rewind().unwrap();
+        file.write_all(&vec![7; 4 * SECTOR_SIZE]).unwrap();
+        let blocks = file.stat().blocks;
+        file.punch_hole(100..3 * SECTOR_SIZE + 100).unwrap();
+        assert_eq!(file.stat().blocks, blocks - 2);
+        assert_eq!(file.stat().size, FAR + FAR / 2);
+
+        let mut buf = vec![0xff; 4 * SECTOR_SIZE];
+        file.rewind().unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert!(buf[..100].iter().all(|b| *b == 7));
+        assert!(buf[100..3 * SECTOR_SIZE + 100].iter().all(|b| *b == 0));
+        assert!(buf[3 * SECTOR_SIZE + 100..].iter().all(|b| *b == 7));
+
+        // Writing to the hole fills it again.
+        file.seek(SeekFrom::Start(SECTOR_SIZE)).unwrap();
+        file.write_all(b"back").unwrap();
+        assert_eq!(file.stat().blocks, blocks - 1);
+    }
+    {
+        // Shrinking frees the sectors past the end, and what is left of the
+        // last one reads as zeros once the file grows again.
+        file.set_len(10).unwrap();
+        assert_eq!(file.stat().blocks, 1);
+        file.set_len(SECTOR_SIZE).unwrap();
+        let mut buf = [0xff; SECTOR_SIZE];
+        file.rewind().unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf[..10], [7; 10]);
+        assert!(buf[10..].iter().all(|b| *b == 0));
+    }
+    drop(file);
+    DISKFS.remove("/disk-sparse".into()).unwrap();
+    kprintln!("[DISKFS.SPARSE] Done.")
+}
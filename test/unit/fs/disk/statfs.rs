@@ -0,0 +1,28 @@
+use alloc::vec;
+
+use crate::device::virtio::SECTOR_SIZE;
+use crate::fs::disk::DISKFS;
+use crate::fs::FileSys;
+use crate::io::prelude::*;
+
+pub fn main() {
+    let before = DISKFS.statfs().unwrap();
+    assert!(before.free_blocks < before.blocks);
+    assert!(before.largest_free <= before.free_blocks);
+    assert!(before.swap_blocks > 0);
+    {
+        // A new file takes its inode and content sectors.
+        let mut file = DISKFS.create("/disk-statfs".into()).unwrap();
+        file.write_all(&vec![1; 8 * SECTOR_SIZE]).unwrap();
+        let after = DISKFS.statfs().unwrap();
+        assert_eq!(after.inodes, before.inodes + 1);
+        assert_eq!(after.free_blocks, before.free_blocks - 9);
+        assert_eq!(after.free_inodes, after.free_blocks);
+    }
+    // All of it is free again once removed.
+    DISKFS.remove("/disk-statfs".into()).unwrap();
+    let after = DISKFS.statfs().unwrap();
+    assert_eq!(after.inodes, before.inodes);
+    assert_eq!(after.free_blocks, before.free_blocks);
+    kprintln!("[DISKFS.STATFS] Done.")
+}
@@ -0,0 +1,39 @@
+use alloc::vec::Vec;
+
+use crate::fs::disk::Swap;
+use crate::mem::PG_SIZE;
+use crate::OsError;
+
+pub fn main() {
+    let before = Swap::stats();
+    let slots = Swap::page_num();
+
+    // Every slot can be allocated once, and then the swap is full.
+    let mut taken = Vec::new();
+    while let Ok(slot) = Swap::alloc_slot() {
+        taken.push(slot);
+    }
+    assert_eq!(taken.len(), slots - before.used);
+    assert_eq!(Swap::alloc_slot(), Err(OsError::SwapFull));
+    assert_eq!(Swap::stats().used, slots);
+
+    // Pages are kept apart, and freed ones are reused.
+    let (a, b) = (taken[0], taken[taken.len() - 1]);
+    Swap::write_page(a, &[0xaa; PG_SIZE]).unwrap();
+    Swap::write_page(b, &[0xbb; PG_SIZE]).unwrap();
+    let mut page = [0; PG_SIZE];
+    Swap::read_page(a, &mut page).unwrap();
+    assert!(page.iter().all(|byte| *byte == 0xaa));
+    Swap::free_slot(a).unwrap();
+    assert_eq!(Swap::alloc_slot(), Ok(a));
+    Swap::read_page(a, &mut page).unwrap();
+    assert!(page.iter().all(|byte| *byte == 0));
+
+    for slot in taken {
+        Swap::free_slot(slot).unwrap();
+    }
+    let after = Swap::stats();
+    assert_eq!(after.used, before.used);
+    assert_eq!(after.writes, before.writes + 2);
+    kprintln!("[DISKFS.SWAP] Done.")
+}
@@ -0,0 +1,141 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+
+use crate::fs::disk::DISKFS;
+use crate::fs::vfs::{DynFileSys, VFS};
+use crate::fs::{File, FileSys, FileType, OpenFlags, ReadDir, StatFs};
+use crate::io::prelude::*;
+use crate::{OsError, Result};
+
+/// Shows a directory of the disk fs at its mount point.
+struct Bind(&'static str);
+
+impl Bind {
+    fn path(&self, path: &str) -> String {
+        format!("{}{}", self.0, path)
+    }
+}
+
+impl DynFileSys for Bind {
+    fn unmount(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn open(&self, path: &str) -> Result<File> {
+        DISKFS.open(self.path(path).as_str().into())
+    }
+
+    fn create(&self, path: &str) -> Result<File> {
+        DISKFS.create(self.path(path).as_str().into())
+    }
+
+    fn remove(&self, path: &str) -> Result<()> {
+        DISKFS.remove(self.path(path).as_str().into())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        DISKFS.rename(
+            self.path(from).as_str().into(),
+            self.path(to).as_str().into(),
+        )
+    }
+
+    fn mkdir(&self, path: &str) -> Result<()> {
+        DISKFS.mkdir(self.path(path).as_str().into())
+    }
+
+    fn readdir(&self, path: &str) -> Result<ReadDir> {
+        DISKFS.readdir(self.path(path).as_str().into())
+    }
+
+    fn statfs(&self) -> Result<StatFs> {
+        DISKFS.statfs()
+    }
+
+    fn sync(&self) -> Result<()> {
+        DISKFS.sync()
+    }
+
+    fn escape(&self, path: &str, follow: bool) -> Option<String> {
+        DISKFS.escape(self.path(path).as_str().into(), follow)
+    }
+}
+
+pub fn main() {
+    DISKFS.mkdir("/vfs-src".into()).unwrap();
+    let file = VFS.create("/vfs-src/f").unwrap();
+
+    VFS.mount("/vfs-mnt", Arc::new(Bind("/vfs-src"))).unwrap();
+    assert_eq!(
+        VFS.mount("/vfs-mnt/", Arc::new(Bind("/vfs-src"))),
+        Err(OsError::CreateExistInode)
+    );
+
+    // Lookups cross the mount point.
+    assert_eq!(VFS.open("/vfs-mnt/f").unwrap().inum(), file.inum());
+    let mut entries = VFS.readdir("/vfs-mnt").unwrap();
+    assert_eq!(entries.next().unwrap().name, "f");
+    assert!(entries.next().is_none());
+    VFS.create("vfs-mnt/./g").unwrap();
+    VFS.rename("/vfs-mnt/g", "/vfs-mnt/h").unwrap();
+    assert!(VFS.open("/vfs-src/h").is_ok());
+    // Every file system, the bound one included.
+    VFS.sync().unwrap();
+
+    // Not across file systems though.
+    assert_eq!(
+        VFS.rename("/vfs-mnt/f", "/vfs-f"),
+        Err(OsError::CrossDevice)
+    );
+
+    VFS.unmount("/vfs-mnt").unwrap();
+    assert_eq!(VFS.open("/vfs-mnt/f").err(), Some(OsError::NoSuchFile));
+    assert_eq!(VFS.unmount("/vfs-mnt"), Err(OsError::NoSuchFile));
+
+    // Open flags.
+    let (creat, excl) = (OpenFlags::CREAT, OpenFlags::EXCL);
+    assert_eq!(
+        VFS.open_with("/vfs-src/f", OpenFlags::RDWR | creat | excl)
+            .err(),
+        Some(OsError::CreateExistInode)
+    );
+    let mut f = VFS.open_with("/vfs-src/f", OpenFlags::WRONLY).unwrap();
+    f.write_all(b"data").unwrap();
+    VFS.open_with("/vfs-src/f", OpenFlags::RDONLY | OpenFlags::TRUNC)
+        .unwrap();
+    assert_eq!(f.len(), Ok(4));
+    VFS.open_with("/vfs-src/f", OpenFlags::WRONLY | OpenFlags::TRUNC)
+        .unwrap();
+    assert_eq!(f.len(), Ok(0));
+    assert_eq!(
+        VFS.open_with("/vfs-src", OpenFlags::RDWR).err(),
+        Some(OsError::IsDir)
+    );
+    VFS.open_with("/vfs-src/i", OpenFlags::RDWR | creat | excl)
+        .unwrap();
+
+    // Symlinks to absolute paths are followed through the mount table.
+    DISKFS.symlink("/tmp", "/vfs-src/tmp".into()).unwrap();
+    let tmp = VFS.create("/vfs-src/tmp/vfs-link").unwrap();
+    assert_eq!(VFS.open("/tmp/vfs-link").unwrap().inum(), tmp.inum());
+    assert_eq!(
+        DISKFS.open("/vfs-src/tmp/vfs-link".into()).err(),
+        Some(OsError::NoSuchFile)
+    );
+    VFS.remove("/vfs-src/tmp/vfs-link").unwrap();
+    DISKFS.symlink("/dev/null", "/vfs-src/null".into()).unwrap();
+    assert_eq!(
+        VFS.open("/vfs-src/null").unwrap().stat().kind,
+        FileType::Device
+    );
+    // Only the link itself is removed.
+    VFS.remove("/vfs-src/null").unwrap();
+    VFS.remove("/vfs-src/tmp").unwrap();
+    assert!(VFS.open("/dev/null").is_ok());
+
+    VFS.remove("/vfs-src/i").unwrap();
+    VFS.remove("/vfs-src/f").unwrap();
+    VFS.remove("/vfs-src/h").unwrap();
+    VFS.remove("/vfs-src").unwrap();
+}
@@ -0,0 +1,62 @@
+//! Needs an ext2 disk image with the user programs, made by
+//! `make build/ext2.img` and passed to `tacos` as `DISK_IMG`.
+use alloc::string::ToString;
+use alloc::vec;
+
+use crate::device::virtio::Virtio;
+use crate::fs::ext2::Ext2Fs;
+use crate::fs::lock::LockKind;
+use crate::fs::vfs::VFS;
+use crate::fs::{FileSys, FileType};
+use crate::io::prelude::*;
+use crate::userproc;
+use crate::OsError;
+
+pub fn main() {
+    let fs = Ext2Fs::mount(Virtio::get()).expect("Not an ext2 disk");
+
+    for entry in fs.readdir("/".into()).unwrap() {
+        if entry.kind != FileType::File {
+            continue;
+        }
+        let mut file = fs.open(entry.name.clone()).unwrap();
+        let mut buf = vec![0; file.len().unwrap()];
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(file.read(&mut [0; 1]), Ok(0));
+        assert_eq!(file.write(b"x"), Err(OsError::ReadOnlyFs));
+    }
+
+    // User programs can be loaded from it.
+    let mut exit = fs.open("/exit".into()).unwrap();
+    let mut magic = [0; 4];
+    exit.read_exact(&mut magic).unwrap();
+    assert_eq!(&magic, b"\x7fELF");
+
+    // And run, with the disk mounted at `/`.
+    let exit = VFS.open("/exit").unwrap();
+    let tid = userproc::execute(exit, vec!["exit".to_string()]);
+    assert_eq!(userproc::wait(tid), Some(0));
+
+    // Opens of the same file share its locks.
+    let sample = fs.open("/sample.txt".into()).unwrap();
+    sample
+        .lock(LockKind::Exclusive, 0..usize::MAX, false)
+        .unwrap();
+    let other = fs.open("/sample.txt".into()).unwrap();
+    assert_eq!(
+        other.lock(LockKind::Shared, 0..usize::MAX, false),
+        Err(OsError::WouldBlock)
+    );
+    drop(sample);
+    other.lock(LockKind::Shared, 0..usize::MAX, false).unwrap();
+
+    let zeros = fs.open("/zeros".into()).unwrap();
+    assert_eq!(zeros.stat().size, 8192);
+    assert_eq!(fs.remove("/zeros".into()), Err(OsError::ReadOnlyFs));
+    assert_eq!(fs.open("/zeros/x".into()).err(), Some(OsError::NotDir));
+    assert_eq!(
+        fs.open("/no-such-file".into()).err(),
+        Some(OsError::NoSuchFile)
+    );
+    kprintln!("[EXT2] Done.")
+}
@@ -0,0 +1,58 @@
+//! Needs a FAT32 disk image with the user programs, made by
+//! `make build/fat.img` and passed to `tacos` as `DISK_IMG`.
+use alloc::string::ToString;
+use alloc::vec;
+
+use crate::device::virtio::Virtio;
+use crate::fs::fat::FatFs;
+use crate::fs::lock::LockKind;
+use crate::fs::vfs::VFS;
+use crate::fs::{FileSys, FileType};
+use crate::io::prelude::*;
+use crate::userproc;
+use crate::OsError;
+
+pub fn main() {
+    let fs = FatFs::mount(Virtio::get()).expect("Not a FAT32 disk");
+
+    for entry in fs.readdir("/".into()).unwrap() {
+        if entry.kind != FileType::File {
+            continue;
+        }
+        let mut file = fs.open(entry.name.clone()).unwrap();
+        let len = file.len().unwrap();
+        let mut buf = vec![0; len];
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(file.read(&mut [0; 1]), Ok(0));
+        assert_eq!(file.write(b"x"), Err(OsError::ReadOnlyFs));
+
+        // FAT names ignore case.
+        let upper = fs.open(entry.name.to_ascii_uppercase()).unwrap();
+        assert_eq!(upper.stat().inum, file.stat().inum);
+
+        // Opens of the same file share its locks.
+        file.lock(LockKind::Exclusive, 0..usize::MAX, false)
+            .unwrap();
+        assert_eq!(
+            upper.lock(LockKind::Shared, 0..usize::MAX, false),
+            Err(OsError::WouldBlock)
+        );
+        file.unlock(0..usize::MAX).unwrap();
+        assert_eq!(fs.remove(entry.name.clone()), Err(OsError::ReadOnlyFs));
+        kprintln!("[FAT] Read {} ({} bytes)", entry.name, len);
+    }
+
+    // User programs run from it, with the disk mounted at `/`.
+    let exit = VFS.open("/exit").unwrap();
+    let tid = userproc::execute(exit, vec!["exit".to_string()]);
+    assert_eq!(userproc::wait(tid), Some(0));
+
+    assert_eq!(fs.create("/new".into()).err(), Some(OsError::ReadOnlyFs));
+    assert_eq!(
+        fs.open("/no-such-file".into()).err(),
+        Some(OsError::NoSuchFile)
+    );
+    let stat = fs.statfs().unwrap();
+    assert!(stat.free_blocks <= stat.blocks);
+    kprintln!("[FAT] Done.")
+}
@@ -1,14 +1,16 @@
-use alloc::boxed::Box;
-
+use crate::fs::lock::LockKind;
 use crate::fs::File;
-use crate::fs::{inmem::MemFs, FileSys};
+use crate::fs::{inmem::MemFs, FileSys, FileType, OpenFlags};
 use crate::io::prelude::*;
 use crate::thread;
-use crate::Result;
+use crate::{OsError, Result};
 
 pub fn main() {
     let fs = &MemFs::mount(()).unwrap();
     base::test(fs);
+    tree::test(fs);
+    flags::test(fs);
+    locks::test(fs);
     // TODO: actually should use wait().
     sync::test(fs);
 }
@@ -18,7 +20,8 @@ mod sync {
     pub(super) fn test(fs: &MemFs) {
         const NUM: usize = 10;
         let sync = [0u8; NUM * core::mem::size_of::<usize>()];
-        let f = fs.open(Box::from(sync)).unwrap();
+        let mut f = fs.create("/sync".into()).unwrap();
+        f.write_all(&sync).unwrap();
         let fw = f.clone();
 
         thread::spawn("writer", || writer(fw, NUM));
@@ -68,7 +71,9 @@ mod base {
     pub(super) fn test(fs: &MemFs) {
         let raw: [u8; 8] = [0x1a, 0x2b, 0x3c, 0x4d, 0x5e, 0x6f, 0x70, 0x89];
 
-        let mut f = fs.open(Box::from(raw)).unwrap();
+        let mut f = fs.create("/base".into()).unwrap();
+        f.write_all(&raw).unwrap();
+        f.rewind().unwrap();
 
         let a: usize = f.read_into().expect("fail to call read_into()");
         assert_eq!(a, 0x_89_70_6f_5e_4d_3c_2b_1a_usize);
@@ -85,3 +90,137 @@ mod base {
         assert_eq!(a, 0x_89_70_12_34_4d_3c_2b_1a_usize);
     }
 }
+
+mod tree {
+    use super::*;
+    pub(super) fn test(fs: &MemFs) {
+        fs.mkdir("/dir".into()).unwrap();
+        assert_eq!(fs.mkdir("/dir".into()), Err(OsError::CreateExistInode));
+        let mut f = fs.create("/dir/a".into()).unwrap();
+
+        // Files grow on write, and shrink on resize.
+        f.seek(SeekFrom::Start(4)).unwrap();
+        f.write_all(b"tmp").unwrap();
+        assert_eq!(f.len(), Ok(7));
+        f.set_len(5).unwrap();
+        let mut buf = [1u8; 5];
+        f.rewind().unwrap();
+        f.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"\0\0\0\0t");
+
+        // Files don't grow past the largest, nor wrap around.
+        assert_eq!(f.set_len(usize::MAX), Err(OsError::FileTooLarge));
+        f.seek(SeekFrom::Start(usize::MAX)).unwrap();
+        assert_eq!(f.write(b"x"), Err(OsError::FileTooLarge));
+        f.seek(SeekFrom::Start(1 << 40)).unwrap();
+        assert_eq!(f.write(b"x"), Err(OsError::FileTooLarge));
+        assert_eq!(f.len(), Ok(5));
+        f.seek(SeekFrom::Start(5)).unwrap();
+
+        // Writes are denied while any file denies them.
+        let mut exe = fs.open("/dir/a".into()).unwrap();
+        exe.deny_write();
+        assert_eq!(f.write(b"x"), Err(OsError::InvalidFileMode));
+        drop(exe);
+        assert_eq!(f.write(b"x"), Ok(1));
+
+        fs.rename("/dir/a".into(), "/b".into()).unwrap();
+        assert_eq!(fs.open("/dir/a".into()).err(), Some(OsError::NoSuchFile));
+        assert_eq!(fs.open("/b".into()).unwrap().inum(), f.inum());
+        assert_eq!(
+            fs.rename("/dir".into(), "/dir/sub".into()),
+            Err(OsError::InvalidArgument)
+        );
+
+        let mut entries = fs.readdir("/".into()).unwrap();
+        let b = entries.find(|e| e.name == "b").unwrap();
+        assert_eq!(b.kind, FileType::File);
+        assert_eq!(fs.create("/dir".into()).err(), Some(OsError::IsDir));
+        assert_eq!(fs.readdir("/b".into()).err(), Some(OsError::NotDir));
+
+        fs.create("/dir/c".into()).unwrap();
+        assert_eq!(fs.remove("/dir".into()), Err(OsError::DirNotEmpty));
+        fs.remove("/dir/c".into()).unwrap();
+        fs.remove("/dir".into()).unwrap();
+        fs.remove("/b".into()).unwrap();
+
+        // A removed file stays usable while opened.
+        assert_eq!(f.stat().nlink, 0);
+        f.rewind().unwrap();
+        f.read_exact(&mut buf).unwrap();
+    }
+}
+
+mod flags {
+    use super::*;
+    pub(super) fn test(fs: &MemFs) {
+        let file = fs.create("/flags".into()).unwrap();
+
+        let mut rdonly = file.clone().with_flags(OpenFlags::RDONLY);
+        assert_eq!(rdonly.write(b"x"), Err(OsError::InvalidFileMode));
+        assert_eq!(rdonly.set_len(0), Err(OsError::InvalidFileMode));
+        let mut wronly = file.clone().with_flags(OpenFlags::WRONLY);
+        assert_eq!(wronly.read(&mut [0; 1]), Err(OsError::InvalidFileMode));
+
+        // Appends go to the end, wherever the other writers are.
+        wronly.write_all(b"0123").unwrap();
+        let mut append = file.with_flags(OpenFlags::WRONLY | OpenFlags::APPEND);
+        append.write_all(b"ab").unwrap();
+        wronly.write_all(b"4").unwrap();
+        append.write_all(b"c").unwrap();
+        assert_eq!(append.stream_position(), Ok(7));
+
+        let mut buf = [0; 7];
+        rdonly.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"01234bc");
+        fs.remove("/flags".into()).unwrap();
+    }
+}
+
+mod locks {
+    use super::*;
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicBool, Ordering::SeqCst};
+
+    pub(super) fn test(fs: &MemFs) {
+        let a = fs.create("/locks".into()).unwrap();
+        let b = fs.open("/locks".into()).unwrap();
+        let (shared, exclusive) = (LockKind::Shared, LockKind::Exclusive);
+
+        a.lock(shared, 0..usize::MAX, false).unwrap();
+        b.lock(shared, 0..usize::MAX, false).unwrap();
+        assert_eq!(b.lock(exclusive, 0..10, false), Err(OsError::WouldBlock));
+        a.unlock(0..usize::MAX).unwrap();
+        b.lock(exclusive, 0..10, false).unwrap();
+
+        // Ranges apart don't conflict, and a clone shares the locks.
+        a.lock(exclusive, 10..20, false).unwrap();
+        assert_eq!(b.lock(exclusive, 15..16, false), Err(OsError::WouldBlock));
+        let c = a.clone();
+        c.lock(exclusive, 5..usize::MAX, false)
+            .expect_err("conflicts with b");
+        c.unlock(10..20).unwrap();
+        b.lock(exclusive, 15..16, false).unwrap();
+
+        // A blocking lock waits until the holder is dropped.
+        let locked = Arc::new(AtomicBool::new(false));
+        let waiter = {
+            let (file, locked) = (c, locked.clone());
+            move || {
+                file.lock(shared, 0..1, true).unwrap();
+                locked.store(true, SeqCst);
+            }
+        };
+        thread::spawn("waiter", waiter);
+        for _ in 0..5 {
+            thread::schedule();
+        }
+        assert!(!locked.load(SeqCst));
+        drop(b);
+        for _ in 0..5 {
+            thread::schedule();
+        }
+        assert!(locked.load(SeqCst));
+        fs.remove("/locks".into()).unwrap();
+    }
+}
@@ -0,0 +1,33 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::fs::vfs::VFS;
+use crate::io::prelude::*;
+use crate::OsError;
+
+fn read(path: &str) -> String {
+    let mut file = VFS.open(path).unwrap();
+    let mut buf = vec![0; file.len().unwrap()];
+    file.read_exact(&mut buf).unwrap();
+    String::from_utf8(buf).unwrap()
+}
+
+pub fn main() {
+    let names: Vec<_> = VFS.readdir("/proc").unwrap().map(|e| e.name).collect();
+    assert_eq!(names[..4], ["threads", "meminfo", "uptime", "interrupts"]);
+
+    let threads = read("/proc/threads");
+    assert!(threads.lines().any(|line| line.ends_with("Idle")));
+    let meminfo = read("/proc/meminfo");
+    assert!(meminfo.starts_with("heap_total\t"));
+    assert!(read("/proc/uptime").starts_with("ticks\t"));
+    assert!(read("/proc/interrupts").contains("timer\t"));
+
+    let mut file = VFS.open("/proc/uptime").unwrap();
+    assert_eq!(file.write(b"0"), Err(OsError::InvalidFileMode));
+    assert_eq!(VFS.remove("/proc/uptime"), Err(OsError::InvalidArgument));
+    assert_eq!(VFS.open("/proc").err(), Some(OsError::IsDir));
+    // Kernel threads have no maps.
+    assert_eq!(VFS.open("/proc/0/maps").err(), Some(OsError::NoSuchFile));
+}
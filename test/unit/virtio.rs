@@ -1,2 +1,6 @@
+pub mod concurrent;
+pub mod errors;
+pub mod limits;
 pub mod repeat;
 pub mod simple;
+pub mod vectored;
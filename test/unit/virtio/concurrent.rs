@@ -0,0 +1,46 @@
+use alloc::sync::Arc;
+use alloc::vec;
+
+use crate::device::virtio::{Virtio, SECTOR_SIZE};
+use crate::sync::Semaphore;
+use crate::thread;
+
+const START: u64 = 32;
+const THREADS: usize = 4;
+/// Sectors each thread writes and reads back, in requests of 8 sectors.
+const CNT: usize = 64;
+
+pub fn main() {
+    let mut saved = vec![0; THREADS * CNT * SECTOR_SIZE];
+    for (i, chunk) in saved.chunks_mut(8 * SECTOR_SIZE).enumerate() {
+        Virtio::read_sectors(START + i as u64 * 8, &mut [chunk]).unwrap();
+    }
+
+    // Requests of all threads are in flight together, and each of them must
+    // be woken by its own.
+    let finish = Arc::new(Semaphore::new(0));
+    for t in 0..THREADS {
+        let finish = finish.clone();
+        thread::spawn("virtio-concurrent", move || {
+            let start = START + (t * CNT) as u64;
+            let data = vec![t as u8 + 1; 8 * SECTOR_SIZE];
+            let mut back = vec![0; 8 * SECTOR_SIZE];
+            for i in (0..CNT as u64).step_by(8) {
+                Virtio::write_sectors(start + i, &[&data]).unwrap();
+            }
+            for i in (0..CNT as u64).step_by(8) {
+                Virtio::read_sectors(start + i, &mut [&mut back]).unwrap();
+                assert_eq!(back, data);
+            }
+            finish.up();
+        });
+    }
+    for _ in 0..THREADS {
+        finish.down();
+    }
+
+    for (i, chunk) in saved.chunks(8 * SECTOR_SIZE).enumerate() {
+        Virtio::write_sectors(START + i as u64 * 8, &[chunk]).unwrap();
+    }
+    kprintln!("Virtio concurrent test done.");
+}
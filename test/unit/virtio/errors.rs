@@ -0,0 +1,23 @@
+use crate::device::virtio::{Virtio, SECTOR_SIZE};
+use crate::OsError;
+
+pub fn main() {
+    let capacity = Virtio::get().lock().capacity();
+    let mut buf = [0; SECTOR_SIZE];
+
+    // Sectors past the end of the disk fail, rather than panic.
+    assert_eq!(
+        Virtio::read_sector(capacity, &mut buf),
+        Err(OsError::IoError)
+    );
+    assert_eq!(Virtio::write_sector(capacity, &buf), Err(OsError::IoError));
+    let mut two = [0; 2 * SECTOR_SIZE];
+    assert_eq!(
+        Virtio::read_sectors(capacity - 1, &mut [&mut two]),
+        Err(OsError::IoError)
+    );
+
+    // And the device keeps working.
+    Virtio::read_sector(capacity - 1, &mut buf).unwrap();
+    kprintln!("Virtio errors test done.");
+}
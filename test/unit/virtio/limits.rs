@@ -0,0 +1,33 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::device::virtio::{Virtio, SECTOR_SIZE, SEGMENTS_MAX};
+
+const START: u64 = 64;
+const CNT: usize = 48;
+
+pub fn main() {
+    let mut saved = vec![0; CNT * SECTOR_SIZE];
+    Virtio::read_sectors(START, &mut [&mut saved]).unwrap();
+
+    // More segments than a request takes are split into several requests,
+    // none of them ending inside a sector.
+    let data: Vec<u8> = (0..CNT * SECTOR_SIZE).map(|i| (i % 241) as u8).collect();
+    let segments: Vec<&[u8]> = data.chunks(SECTOR_SIZE / 2 + 3).collect();
+    assert!(segments.len() > 2 * SEGMENTS_MAX);
+    Virtio::write_sectors(START, &segments).unwrap();
+
+    let mut back = vec![0; CNT * SECTOR_SIZE];
+    {
+        let mut segments: Vec<&mut [u8]> = back.chunks_mut(100).collect();
+        Virtio::read_sectors(START, &mut segments).unwrap();
+    }
+    assert_eq!(back, data);
+
+    // Whether or not the device caches writes.
+    Virtio::flush().unwrap();
+    assert!(!Virtio::get().lock().read_only());
+
+    Virtio::write_sectors(START, &[&saved]).unwrap();
+    kprintln!("Virtio limits test done.");
+}
@@ -6,14 +6,14 @@ pub fn main() {
     let mut buf3 = [0; virtio::SECTOR_SIZE];
 
     for s in 0..10 {
-        Virtio::write_sector(s, &buf1);
-        Virtio::read_sector(s, &mut buf3);
+        Virtio::write_sector(s, &buf1).unwrap();
+        Virtio::read_sector(s, &mut buf3).unwrap();
         for i in buf3 {
             assert_eq!(i, 1);
         }
 
-        Virtio::write_sector(s, &buf2);
-        Virtio::read_sector(s, &mut buf3);
+        Virtio::write_sector(s, &buf2).unwrap();
+        Virtio::read_sector(s, &mut buf3).unwrap();
         for i in buf3 {
             assert_eq!(i, 0);
         }
@@ -3,7 +3,7 @@ use crate::device::virtio::{self, Virtio};
 pub fn main() {
     let mut buf3 = [0; virtio::SECTOR_SIZE];
 
-    Virtio::read_sector(1, &mut buf3);
+    Virtio::read_sector(1, &mut buf3).unwrap();
     for i in buf3 {
         kprint!("{:#x} ", i);
     }
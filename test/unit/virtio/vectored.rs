@@ -0,0 +1,50 @@
+use alloc::vec;
+
+use crate::device::virtio::{Virtio, SECTOR_SIZE};
+
+const START: u64 = 16;
+const CNT: usize = 10;
+
+pub fn main() {
+    let mut saved = vec![0; CNT * SECTOR_SIZE];
+    Virtio::read_sectors(START, &mut [&mut saved]).unwrap();
+
+    // Segments needn't be whole sectors, only all of them together.
+    let data: vec::Vec<u8> = (0..CNT * SECTOR_SIZE).map(|i| (i % 251) as u8).collect();
+    let (a, rest) = data.split_at(100);
+    let (b, c) = rest.split_at(3 * SECTOR_SIZE);
+    Virtio::write_sectors(START, &[a, b, c]).unwrap();
+
+    let mut sector = [0; SECTOR_SIZE];
+    for i in 0..CNT {
+        Virtio::read_sector(START + i as u64, &mut sector).unwrap();
+        assert_eq!(sector[..], data[i * SECTOR_SIZE..(i + 1) * SECTOR_SIZE]);
+    }
+
+    let mut back = vec![0; CNT * SECTOR_SIZE];
+    {
+        let (a, rest) = back.split_at_mut(SECTOR_SIZE + 7);
+        let (b, c) = rest.split_at_mut(1);
+        Virtio::read_sectors(START, &mut [a, b, c]).unwrap();
+    }
+    assert_eq!(back, data);
+
+    // Many segments too short to make up a sector within the device's limit.
+    let data: vec::Vec<u8> = data.iter().map(|b| b ^ 0x5a).collect();
+    let tiny: vec::Vec<&[u8]> = data.chunks(3).collect();
+    Virtio::write_sectors(START, &tiny).unwrap();
+    for i in 0..CNT {
+        Virtio::read_sector(START + i as u64, &mut sector).unwrap();
+        assert_eq!(sector[..], data[i * SECTOR_SIZE..(i + 1) * SECTOR_SIZE]);
+    }
+
+    let mut back = vec![0; CNT * SECTOR_SIZE];
+    {
+        let mut tiny: vec::Vec<&mut [u8]> = back.chunks_mut(5).collect();
+        Virtio::read_sectors(START, &mut tiny).unwrap();
+    }
+    assert_eq!(back, data);
+
+    Virtio::write_sectors(START, &[&saved]).unwrap();
+    kprintln!("Virtio vectored test done.");
+}
@@ -1,8 +1,7 @@
 use alloc::string::ToString;
 use alloc::vec::Vec;
 
-use crate::fs::disk::DISKFS;
-use crate::fs::FileSys;
+use crate::fs::vfs::VFS;
 use crate::thread;
 use crate::userproc;
 
@@ -30,7 +29,7 @@ pub fn main(cmd: &str) {
 
     let argv: Vec<_> = cmd.split(" ").map(|s| s.to_string()).collect();
     let name = argv[0].clone();
-    let file = DISKFS.open(name.as_str().into()).unwrap();
+    let file = VFS.open(&name).unwrap();
 
     let r = userproc::wait(userproc::execute(file, argv)).unwrap();
     if KILLED_USERPROC.iter().find(|n| name.eq(**n)).is_some() {
@@ -0,0 +1,23 @@
+/** Reports capacity and usage of file systems, the root one by default. */
+
+#include "user.h"
+
+static void df(const char* path) {
+    fsstat st;
+    if (statfs(path, &st) < 0) {
+        fprintf(2, "df: cannot stat %s\n", path);
+        return;
+    }
+    printf("%s\t%d\t%d\t%d\t%d\t%d\t%d\n", path, (int)st.blocks,
+           (int)(st.blocks - st.free_blocks), (int)st.free_blocks, (int)st.largest_free,
+           (int)st.inodes, (int)st.swap_blocks);
+}
+
+void main(int argc, char* argv[]) {
+    printf("path\tblocks\tused\tfree\tlargest\tinodes\tswap\n");
+    if (argc < 2) {
+        df("/");
+        return;
+    }
+    for (int i = 1; i < argc; i++) df(argv[i]);
+}
@@ -0,0 +1,38 @@
+/** Lists the entries of directories, the root dir by default. */
+
+#include "user.h"
+
+static const char* type_name(ushort type) {
+    switch (type) {
+        case T_DIR: return "dir";
+        case T_FILE: return "file";
+        case T_DEVICE: return "dev";
+        case T_SYMLINK: return "link";
+        default: return "?";
+    }
+}
+
+static void ls(const char* dir) {
+    int fd = opendir(dir);
+    if (fd < 0) {
+        fprintf(2, "ls: cannot open %s\n", dir);
+        return;
+    }
+    dirent entry;
+    int ret;
+    while ((ret = readdir(fd, &entry)) == 1)
+        printf("%d\t%s\t%s\n", entry.ino, type_name(entry.type), entry.name);
+    if (ret < 0) fprintf(2, "ls: cannot read %s\n", dir);
+    close(fd);
+}
+
+void main(int argc, char* argv[]) {
+    if (argc < 2) {
+        ls("/");
+        return;
+    }
+    for (int i = 1; i < argc; i++) {
+        if (argc > 2) printf("%s:\n", argv[i]);
+        ls(argv[i]);
+    }
+}
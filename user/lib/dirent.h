@@ -0,0 +1,15 @@
+#ifndef __LIB_DIRENT_H
+#define __LIB_DIRENT_H
+
+#include "types.h"
+
+#define NAME_MAX 255  // Longest file name
+
+typedef struct {
+    uint ino;                // Inode number
+    ushort type;             // T_DIR, T_FILE, T_DEVICE or T_SYMLINK
+    char name[NAME_MAX + 1]; // NUL-terminated name
+    char _pad[2];            // Always 0
+} dirent;
+
+#endif
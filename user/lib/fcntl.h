@@ -1,5 +1,14 @@
 #define O_RDONLY 0x000
 #define O_WRONLY 0x001
 #define O_RDWR 0x002
+#define O_APPEND 0x008
 #define O_CREATE 0x200
+#define O_CREAT O_CREATE
 #define O_TRUNC 0x400
+#define O_EXCL 0x800
+
+/* Operations of flock(). */
+#define LOCK_SH 1
+#define LOCK_EX 2
+#define LOCK_NB 4
+#define LOCK_UN 8
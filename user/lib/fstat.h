@@ -6,10 +6,19 @@
 #define T_DIR 1     // Directory
 #define T_FILE 2    // File
 #define T_DEVICE 3  // Device
+#define T_SYMLINK 4 // Symbolic link
 
 typedef struct {
-    uint ino;     // Inode number
-    uint64 size;  // Size of file in bytes
+    uint ino;      // Inode number
+    uint _pad;     // Always 0
+    uint64 size;   // Size of file in bytes
+    ushort type;   // T_DIR, T_FILE, T_DEVICE or T_SYMLINK
+    ushort mode;   // Permission bits
+    uint nlink;    // Number of links to the file
+    uint64 atime;  // Last access time, in ms since boot
+    uint64 mtime;  // Last modification time, in ms since boot
+    uint64 ctime;  // Creation time, in ms since boot
+    uint64 blocks; // Space taken in 512-byte units
 } stat;
 
 #endif
@@ -0,0 +1,15 @@
+#ifndef __LIB_STATFS_H
+#define __LIB_STATFS_H
+
+#include "types.h"
+
+typedef struct {
+    uint64 blocks;       // Space of the file system in 512-byte units
+    uint64 free_blocks;  // Space that can still be allocated
+    uint64 largest_free; // Largest contiguous free space
+    uint64 inodes;       // Files and directories
+    uint64 free_inodes;  // Files and directories that can still be created
+    uint64 swap_blocks;  // Space set aside for swapping
+} fsstat;
+
+#endif
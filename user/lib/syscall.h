@@ -18,3 +18,9 @@
 /* Project 4 only. */
 #define SYS_CHDIR 15 /**< Change the current directory. */
 #define SYS_MKDIR 16 /**< Create a directory. */
+
+#define SYS_READDIR 17 /**< Read an entry of a directory. */
+#define SYS_FLOCK 18   /**< Lock or unlock a file. */
+#define SYS_STATFS 19  /**< Get capacity and usage of a file system. */
+#define SYS_SYNC 20    /**< Write file systems to the disk. */
+#define SYS_OPENDIR 21 /**< Open a directory for reading its entries. */
@@ -3,8 +3,10 @@
 
 #include <stdarg.h>
 
+#include "dirent.h"
 #include "fcntl.h"
 #include "fstat.h"
+#include "statfs.h"
 #include "types.h"
 
 #define NULL ((void*)0)
@@ -42,6 +44,11 @@ int mmap(int fd, void* addr);
 void munmap(int mapid);
 int chdir(const char* dir);
 int mkdir(const char* dir);
+int opendir(const char* dir);
+int readdir(int fd, dirent* entry);
+int flock(int fd, int op);
+int statfs(const char* path, fsstat* buf);
+int sync(void);
 
 // ulib.c
 void fprintf(int fd, const char* fmt, ...);
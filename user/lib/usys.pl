@@ -30,3 +30,8 @@ entry("mmap");
 entry("munmap");
 entry("chdir");
 entry("mkdir");
+entry("readdir");
+entry("flock");
+entry("statfs");
+entry("sync");
+entry("opendir");
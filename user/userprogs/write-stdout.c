@@ -0,0 +1,9 @@
+/** Try writing to fd 1 (stdout), which should write all of it to the
+   console. */
+
+#include "user.h"
+
+void main() {
+    const char msg[] = "write-stdout: hello\n";
+    assert(write(1, msg, sizeof msg - 1) == sizeof msg - 1);
+}